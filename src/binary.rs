@@ -0,0 +1,800 @@
+//! The binary protocol. Decodes memcached's binary framing into the same
+//! ServerCommands that the ASCII parser produces, and encodes the store's
+//! Responses back into binary frames
+
+use std::io;
+use std::io::Write;
+
+use nom::{be_u8, be_u16, be_u32, be_u64, Err, ErrorKind};
+
+pub use nom::{IResult, Needed};
+
+use store::Store;
+use shards::ShardedStore;
+use store::ServerCommand;
+use store::Response;
+use store::SetterType;
+use store::GetterType;
+use store::IncrementerType;
+use store::StatsType;
+use store::{Key, Flags, Ttl, CasUnique, IncrValue};
+use server::VERSION;
+use parser::map_stats_name;
+
+pub const REQUEST_MAGIC: u8 = 0x80;
+const RESPONSE_MAGIC: u8 = 0x81;
+
+const HEADER_SIZE: usize = 24;
+
+const GET: u8 = 0x00;
+const SET: u8 = 0x01;
+const ADD: u8 = 0x02;
+const REPLACE: u8 = 0x03;
+const DELETE: u8 = 0x04;
+const INCREMENT: u8 = 0x05;
+const DECREMENT: u8 = 0x06;
+const QUIT: u8 = 0x07;
+const FLUSH: u8 = 0x08;
+const GETQ: u8 = 0x09;
+const NOOP: u8 = 0x0a;
+const VERSION_OP: u8 = 0x0b;
+const GETK: u8 = 0x0c;
+const GETKQ: u8 = 0x0d;
+const APPEND: u8 = 0x0e;
+const PREPEND: u8 = 0x0f;
+const STAT: u8 = 0x10;
+const SETQ: u8 = 0x11;
+const ADDQ: u8 = 0x12;
+const REPLACEQ: u8 = 0x13;
+const DELETEQ: u8 = 0x14;
+const INCREMENTQ: u8 = 0x15;
+const DECREMENTQ: u8 = 0x16;
+const QUITQ: u8 = 0x17;
+const FLUSHQ: u8 = 0x18;
+const APPENDQ: u8 = 0x19;
+const PREPENDQ: u8 = 0x1a;
+
+const STATUS_OK: u16 = 0x0000;
+const STATUS_KEY_NOT_FOUND: u16 = 0x0001;
+const STATUS_KEY_EXISTS: u16 = 0x0002;
+const STATUS_TOO_LARGE: u16 = 0x0003;
+const STATUS_INVALID_ARGUMENTS: u16 = 0x0004;
+const STATUS_NOT_STORED: u16 = 0x0005;
+const STATUS_NON_NUMERIC: u16 = 0x0006;
+const STATUS_UNKNOWN_COMMAND: u16 = 0x0081;
+const STATUS_INTERNAL_ERROR: u16 = 0x0084;
+
+// incr/decr requests with this expiration fail on a missing key instead of
+// creating it
+const NO_AUTOVIVIFY: Ttl = 0xffffffff;
+
+#[derive(Debug,PartialEq,Eq)]
+pub struct RequestHeader {
+    pub opcode: u8,
+    key_length: u16,
+    extras_length: u8,
+    body_length: u32,
+    pub opaque: u32,
+    pub cas: CasUnique,
+}
+
+#[derive(Debug,PartialEq,Eq)]
+pub struct BinaryCommand<'a> {
+    pub header: RequestHeader,
+    pub key: Key<'a>,
+    pub command: ServerCommand<'a>,
+    // incr and decr can create a missing key with this value and ttl
+    pub initial: Option<(IncrValue, Ttl)>,
+}
+
+#[derive(Debug,PartialEq,Eq)]
+pub struct BinaryResponse<'a> {
+    header: RequestHeader,
+    key: Key<'a>,
+    response: Response<'a>,
+    cas: CasUnique,
+}
+
+named!(request_header<&[u8], RequestHeader>,
+    chain!(
+        tag!(b"\x80") ~
+        opcode: be_u8 ~
+        key_length: be_u16 ~
+        extras_length: be_u8 ~
+        be_u8 ~ // data type, which is reserved
+        be_u16 ~ // vbucket id, which we don't use
+        body_length: be_u32 ~
+        opaque: be_u32 ~
+        cas: be_u64,
+        || {
+            RequestHeader {
+                opcode,
+                key_length,
+                extras_length,
+                body_length,
+                opaque,
+                cas,
+            }
+        }
+    )
+);
+
+// set, add and replace: <flags> <expiration>
+named!(setter_extras<&[u8], (Flags, Ttl)>,
+    chain!(
+        flags: be_u32 ~
+        ttl: be_u32,
+        || { (flags, ttl) }
+    )
+);
+
+// incr and decr: <delta> <initial value> <expiration>
+named!(incr_extras<&[u8], (IncrValue, IncrValue, Ttl)>,
+    chain!(
+        delta: be_u64 ~
+        initial: be_u64 ~
+        ttl: be_u32,
+        || { (delta, initial, ttl) }
+    )
+);
+
+// flush: <expiration>
+named!(flush_extras<&[u8], Ttl>, call!(be_u32));
+
+pub fn oversized(input: &[u8],
+                 item_size_max: usize)
+                 -> Option<(BinaryResponse<'static>, usize)> {
+    // like parser::oversized, a request with a value bigger than we'll store
+    // is turned down as soon as we have its header, rather than once we've
+    // buffered the lot. Gives the response to send and how much of the
+    // stream the request takes up
+    let header = match request_header(input) {
+        IResult::Done(_, header) => header,
+        _ => return None,
+    };
+    let body_length = header.body_length as usize;
+    let value_room = item_size_max + header.extras_length as usize +
+                     header.key_length as usize;
+    if body_length <= value_room {
+        return None;
+    }
+    let response = BinaryResponse {
+        header,
+        key: b"",
+        response: Response::TooBig,
+        cas: 0,
+    };
+    Some((response, HEADER_SIZE + body_length))
+}
+
+pub fn parse_command(input: &[u8]) -> IResult<&[u8], BinaryCommand<'_>> {
+    if input.len() < HEADER_SIZE {
+        return IResult::Incomplete(Needed::Size(HEADER_SIZE));
+    }
+
+    let (rest, header) = match request_header(input) {
+        IResult::Done(rest, header) => (rest, header),
+        IResult::Error(err) => return IResult::Error(err),
+        IResult::Incomplete(needed) => return IResult::Incomplete(needed),
+    };
+
+    let body_length = header.body_length as usize;
+    let key_length = header.key_length as usize;
+    let extras_length = header.extras_length as usize;
+
+    if key_length + extras_length > body_length {
+        // the framing is nonsense so there's no way to find the next packet
+        return IResult::Error(Err::Position(ErrorKind::Custom(0), input));
+    }
+
+    if rest.len() < body_length {
+        return IResult::Incomplete(Needed::Size(HEADER_SIZE + body_length));
+    }
+
+    let (body, remaining) = rest.split_at(body_length);
+    let (extras, body) = body.split_at(extras_length);
+    let (key, value) = body.split_at(key_length);
+
+    let (command, initial) = decode(&header, extras, key, value);
+
+    IResult::Done(remaining,
+                  BinaryCommand {
+                      header,
+                      key,
+                      command,
+                      initial,
+                  })
+}
+
+fn decode<'a>(header: &RequestHeader,
+              extras: &'a [u8],
+              key: Key<'a>,
+              value: &'a [u8])
+              -> (ServerCommand<'a>, Option<(IncrValue, Ttl)>) {
+    let invalid = (ServerCommand::Bad(b"Invalid arguments"), None);
+
+    match header.opcode {
+        GET | GETQ | GETK | GETKQ => {
+            if !extras.is_empty() || key.is_empty() || !value.is_empty() {
+                return invalid;
+            }
+            (ServerCommand::Getter {
+                getter: GetterType::Get,
+                keys: vec![key],
+            },
+             None)
+        }
+        SET | SETQ | ADD | ADDQ | REPLACE | REPLACEQ => {
+            let (flags, ttl) = match setter_extras(extras) {
+                IResult::Done(b"", parsed) if !key.is_empty() => parsed,
+                _ => return invalid,
+            };
+            let setter = match header.opcode {
+                ADD | ADDQ => SetterType::Add,
+                _ if header.cas != 0 => SetterType::Cas(header.cas),
+                SET | SETQ => SetterType::Set,
+                _ => SetterType::Replace,
+            };
+            (ServerCommand::Setter {
+                setter,
+                key,
+                data: value,
+                ttl,
+                flags,
+            },
+             None)
+        }
+        APPEND | APPENDQ | PREPEND | PREPENDQ => {
+            if !extras.is_empty() || key.is_empty() {
+                return invalid;
+            }
+            let setter = match header.opcode {
+                APPEND | APPENDQ => SetterType::Append,
+                _ => SetterType::Prepend,
+            };
+            // the flags and ttl are ignored for appends and prepends
+            (ServerCommand::Setter {
+                setter,
+                key,
+                data: value,
+                ttl: 0,
+                flags: 0,
+            },
+             None)
+        }
+        DELETE | DELETEQ => {
+            if !extras.is_empty() || key.is_empty() || !value.is_empty() {
+                return invalid;
+            }
+            (ServerCommand::Delete { key }, None)
+        }
+        INCREMENT | INCREMENTQ | DECREMENT | DECREMENTQ => {
+            let (delta, initial, ttl) = match incr_extras(extras) {
+                IResult::Done(b"", parsed) if !key.is_empty() &&
+                                              value.is_empty() => parsed,
+                _ => return invalid,
+            };
+            let incrementer = match header.opcode {
+                INCREMENT | INCREMENTQ => IncrementerType::Incr,
+                _ => IncrementerType::Decr,
+            };
+            let initial = if ttl == NO_AUTOVIVIFY {
+                None
+            } else {
+                Some((initial, ttl))
+            };
+            (ServerCommand::Incrementer {
+                incrementer,
+                key,
+                value: delta,
+            },
+             initial)
+        }
+        FLUSH | FLUSHQ => {
+            // the expiration in the extras is optional
+            let delay = match flush_extras(extras) {
+                _ if extras.is_empty() => 0,
+                IResult::Done(b"", delay) => delay,
+                _ => return invalid,
+            };
+            if !key.is_empty() || !value.is_empty() {
+                return invalid;
+            }
+            (ServerCommand::FlushAll { delay }, None)
+        }
+        QUIT | QUITQ => (ServerCommand::Quit, None),
+        STAT => {
+            if !extras.is_empty() || !value.is_empty() {
+                return invalid;
+            }
+            // the key optionally names a group like in `stats items`
+            let stats_type = if key.is_empty() {
+                StatsType::General
+            } else {
+                match map_stats_name(key) {
+                    Some(stats_type) => stats_type,
+                    None => return invalid,
+                }
+            };
+            (ServerCommand::Stats(stats_type), None)
+        }
+        NOOP => (ServerCommand::Noop, None),
+        VERSION_OP => (ServerCommand::Version, None),
+        _ => (ServerCommand::Bad(b"Unknown command"), None),
+    }
+}
+
+fn known_opcode(opcode: u8) -> bool {
+    matches!(opcode,
+             GET | SET | ADD | REPLACE | DELETE | INCREMENT | DECREMENT |
+             QUIT | FLUSH | GETQ | NOOP | VERSION_OP | GETK | GETKQ |
+             APPEND | PREPEND | STAT | SETQ | ADDQ | REPLACEQ | DELETEQ |
+             INCREMENTQ | DECREMENTQ | QUITQ | FLUSHQ | APPENDQ | PREPENDQ)
+}
+
+fn is_quiet(opcode: u8) -> bool {
+    matches!(opcode,
+             GETQ | GETKQ | SETQ | ADDQ | REPLACEQ | DELETEQ | INCREMENTQ |
+             DECREMENTQ | QUITQ | FLUSHQ | APPENDQ | PREPENDQ)
+}
+
+impl<'a> BinaryCommand<'a> {
+    pub fn is_quit(&self) -> bool {
+        self.command == ServerCommand::Quit
+    }
+}
+
+pub fn apply<'a>(store: &ShardedStore,
+                 request: BinaryCommand<'a>)
+                 -> BinaryResponse<'a> {
+    let BinaryCommand { header, key, command, initial } = request;
+
+    let (response, cas) = match command {
+        // the server loop hangs up on them after we've replied
+        ServerCommand::Quit => (Response::Ok, 0),
+        command @ ServerCommand::Getter { .. } |
+        command @ ServerCommand::Setter { .. } |
+        command @ ServerCommand::Delete { .. } |
+        command @ ServerCommand::Incrementer { .. } => {
+            apply_keyed(&mut store.lock(key), key, command, initial)
+        }
+        // the rest aren't about any one key
+        command => (store.apply(command), 0),
+    };
+
+    BinaryResponse {
+        header,
+        key,
+        response,
+        cas,
+    }
+}
+
+fn apply_keyed<'a>(store: &mut Store,
+                   key: Key<'a>,
+                   command: ServerCommand<'a>,
+                   initial: Option<(IncrValue, Ttl)>)
+                   -> (Response<'a>, CasUnique) {
+    // runs a command against the shard that holds its key, along with
+    // anything else we have to do before we let go of it
+    let response = store.apply(command);
+
+    let response = match (response, initial) {
+        (Response::NotFound, Some((value, ttl))) => {
+            // binary incr/decr create the key if it's missing
+            let data = value.to_string();
+            match store.apply(ServerCommand::Setter {
+                setter: SetterType::Add,
+                key,
+                data: data.as_bytes(),
+                ttl,
+                flags: 0,
+            }) {
+                Response::Stored => Response::Incr { value },
+                _ => Response::NotStored,
+            }
+        }
+        (response, _) => response,
+    };
+
+    // the store doesn't tell us the CAS of items that it mutates, but it
+    // can't have been changed by anyone else while we hold the lock
+    let cas = match response {
+        Response::Data { ref responses } |
+        Response::Gets { ref responses } => {
+            responses.first().map_or(0, |found| found.unique)
+        }
+        Response::Stored | Response::Incr { .. } => store.last_cas_id(),
+        _ => 0,
+    };
+
+    (response, cas)
+}
+
+impl<'a> BinaryResponse<'a> {
+    fn status(&self) -> u16 {
+        let opcode = self.header.opcode;
+        match self.response {
+            Response::Data { ref responses } |
+            Response::Gets { ref responses } if responses.is_empty() => {
+                STATUS_KEY_NOT_FOUND
+            }
+            Response::NotStored => {
+                match opcode {
+                    ADD | ADDQ => STATUS_KEY_EXISTS,
+                    REPLACE | REPLACEQ => STATUS_KEY_NOT_FOUND,
+                    _ => STATUS_NOT_STORED,
+                }
+            }
+            Response::Exists => STATUS_KEY_EXISTS,
+            Response::NotFound => STATUS_KEY_NOT_FOUND,
+            Response::TooBig => STATUS_TOO_LARGE,
+            Response::ClientError { .. } => {
+                match opcode {
+                    INCREMENT | INCREMENTQ | DECREMENT | DECREMENTQ => {
+                        STATUS_NON_NUMERIC
+                    }
+                    _ => STATUS_INVALID_ARGUMENTS,
+                }
+            }
+            Response::ServerError { .. } => STATUS_INTERNAL_ERROR,
+            Response::Error if known_opcode(opcode) => STATUS_INVALID_ARGUMENTS,
+            Response::Error => STATUS_UNKNOWN_COMMAND,
+            _ => STATUS_OK,
+        }
+    }
+
+    pub fn should_reply(&self) -> bool {
+        // quiet commands only speak up when something went wrong, except
+        // quiet gets which stay quiet on misses too
+        if !is_quiet(self.header.opcode) {
+            return true;
+        }
+        let quiet_get = matches!(self.header.opcode, GETQ | GETKQ);
+        match self.status() {
+            STATUS_OK => quiet_get,
+            STATUS_KEY_NOT_FOUND => !quiet_get,
+            _ => true,
+        }
+    }
+}
+
+fn put_u16(buff: &mut Vec<u8>, value: u16) {
+    buff.push((value >> 8) as u8);
+    buff.push(value as u8);
+}
+
+fn put_u32(buff: &mut Vec<u8>, value: u32) {
+    put_u16(buff, (value >> 16) as u16);
+    put_u16(buff, value as u16);
+}
+
+fn put_u64(buff: &mut Vec<u8>, value: u64) {
+    put_u32(buff, (value >> 32) as u32);
+    put_u32(buff, value as u32);
+}
+
+// one response packet
+struct Frame<'a> {
+    opcode: u8,
+    status: u16,
+    opaque: u32,
+    cas: CasUnique,
+    extras: &'a [u8],
+    key: &'a [u8],
+    value: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    fn put(&self, frame: &mut Vec<u8>) {
+        let body_length = self.extras.len() + self.key.len() +
+                          self.value.len();
+
+        frame.push(RESPONSE_MAGIC);
+        frame.push(self.opcode);
+        put_u16(frame, self.key.len() as u16);
+        frame.push(self.extras.len() as u8);
+        frame.push(0); // data type
+        put_u16(frame, self.status);
+        put_u32(frame, body_length as u32);
+        put_u32(frame, self.opaque);
+        put_u64(frame, self.cas);
+        frame.extend_from_slice(self.extras);
+        frame.extend_from_slice(self.key);
+        frame.extend_from_slice(self.value);
+    }
+}
+
+fn error_message(status: u16) -> &'static [u8] {
+    match status {
+        STATUS_KEY_NOT_FOUND => b"Not found",
+        STATUS_KEY_EXISTS => b"Data exists for key.",
+        STATUS_TOO_LARGE => b"Too large.",
+        STATUS_INVALID_ARGUMENTS => b"Invalid arguments",
+        STATUS_NOT_STORED => b"Not stored.",
+        STATUS_NON_NUMERIC => {
+            b"Non-numeric server-side value for incr or decr"
+        }
+        STATUS_UNKNOWN_COMMAND => b"Unknown command",
+        _ => b"Internal error",
+    }
+}
+
+pub fn format_response(response: BinaryResponse,
+                       socket: &mut dyn Write)
+                       -> io::Result<()> {
+    let status = response.status();
+    let opcode = response.header.opcode;
+    let returns_key = opcode == GETK || opcode == GETKQ;
+
+    let opaque = response.header.opaque;
+
+    let mut extras: Vec<u8> = Vec::new();
+    let mut key: &[u8] = b"";
+    let mut value: Vec<u8> = Vec::new();
+    let mut frame: Vec<u8> = Vec::new();
+
+    match response.response {
+        Response::Data { ref responses } |
+        Response::Gets { ref responses } if status == STATUS_OK => {
+            let found = &responses[0];
+            put_u32(&mut extras, found.flags);
+            if returns_key {
+                key = found.key;
+            }
+            value.extend_from_slice(&found.data);
+        }
+        Response::Incr { value: new_value } => {
+            put_u64(&mut value, new_value);
+        }
+        Response::Version => {
+            value.extend_from_slice(VERSION);
+        }
+        Response::Stats { ref stats } => {
+            // one packet per stat, and the empty one below terminates them
+            for (name, stat) in stats {
+                let packet = Frame {
+                    opcode,
+                    status,
+                    opaque,
+                    cas: 0,
+                    extras: b"",
+                    key: name.as_bytes(),
+                    value: stat.as_bytes(),
+                };
+                packet.put(&mut frame);
+            }
+        }
+        _ if status != STATUS_OK => {
+            if returns_key {
+                key = response.key;
+            }
+            value.extend_from_slice(error_message(status));
+        }
+        _ => {}
+    }
+
+    let packet = Frame {
+        opcode,
+        status,
+        opaque,
+        cas: response.cas,
+        extras: &extras,
+        key,
+        value: &value,
+    };
+    packet.put(&mut frame);
+
+    socket.write_all(&frame)?;
+    socket.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shards::ShardedStore;
+    use settings::Settings;
+    use store::ServerCommand;
+    use store::SetterType;
+    use store::GetterType;
+    use store::IncrementerType;
+
+    fn request(opcode: u8,
+               opaque: u32,
+               cas: u64,
+               extras: &[u8],
+               key: &[u8],
+               value: &[u8])
+               -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.push(REQUEST_MAGIC);
+        frame.push(opcode);
+        put_u16(&mut frame, key.len() as u16);
+        frame.push(extras.len() as u8);
+        frame.push(0);
+        put_u16(&mut frame, 0);
+        put_u32(&mut frame,
+                (extras.len() + key.len() + value.len()) as u32);
+        put_u32(&mut frame, opaque);
+        put_u64(&mut frame, cas);
+        frame.extend_from_slice(extras);
+        frame.extend_from_slice(key);
+        frame.extend_from_slice(value);
+        frame
+    }
+
+    fn make_store() -> ShardedStore {
+        ShardedStore::new(Settings {
+            maxbytes: 4000,
+            shards: 4,
+            ..Settings::default()
+        })
+    }
+
+    fn run(store: &ShardedStore, frame: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        match parse_command(frame) {
+            IResult::Done(b"", command) => {
+                let response = apply(store, command);
+                if response.should_reply() {
+                    format_response(response, &mut out).unwrap();
+                }
+            }
+            other => panic!("couldn't parse {:?}", other),
+        }
+        out
+    }
+
+    #[test]
+    pub fn commands() {
+        let set = request(SET, 7, 0, b"\x00\x00\x00\x0c\x00\x00\x00\x22",
+                          b"foo", b"data!");
+        let cas = request(SETQ, 7, 89, b"\x00\x00\x00\x0c\x00\x00\x00\x22",
+                          b"foo", b"data!");
+        let get = request(GETKQ, 8, 0, b"", b"foo", b"");
+        let incr = request(INCREMENT, 9, 0,
+                           b"\x00\x00\x00\x00\x00\x00\x00\x05\
+                             \x00\x00\x00\x00\x00\x00\x00\x01\
+                             \xff\xff\xff\xff",
+                           b"foo", b"");
+        let delete = request(DELETE, 0, 0, b"", b"foo", b"");
+        let flush = request(FLUSH, 0, 0, b"", b"", b"");
+        let delayed_flush = request(FLUSHQ, 0, 0, b"\x00\x00\x00\x1e", b"",
+                                    b"");
+        let bad_set = request(SET, 0, 0, b"", b"foo", b"data!");
+        let unknown = request(0x50, 0, 0, b"", b"", b"");
+
+        // the request, what it parses to, and the initial value and ttl that
+        // incr and decr bring along
+        type Case<'a> = (&'a [u8], ServerCommand<'a>, Option<(u64, u32)>);
+        let tests: Vec<Case> = vec![
+            (&set, ServerCommand::Setter { setter: SetterType::Set, key: b"foo", data: b"data!", ttl: 34, flags: 12 }, None),
+            (&cas, ServerCommand::Setter { setter: SetterType::Cas(89), key: b"foo", data: b"data!", ttl: 34, flags: 12 }, None),
+            (&get, ServerCommand::Getter { getter: GetterType::Get, keys: vec![b"foo"] }, None),
+            (&incr, ServerCommand::Incrementer { incrementer: IncrementerType::Incr, key: b"foo", value: 5 }, None),
+            (&delete, ServerCommand::Delete { key: b"foo" }, None),
+            (&flush, ServerCommand::FlushAll { delay: 0 }, None),
+            (&delayed_flush, ServerCommand::FlushAll { delay: 30 }, None),
+            (&bad_set, ServerCommand::Bad(b"Invalid arguments"), None),
+            (&unknown, ServerCommand::Bad(b"Unknown command"), None),
+        ];
+
+        for &(frame, ref expected_command, ref expected_initial) in &tests {
+            match parse_command(frame) {
+                IResult::Done(remaining, parsed) => {
+                    assert_eq!(remaining, b"");
+                    assert_eq!(parsed.command, *expected_command);
+                    assert_eq!(parsed.initial, *expected_initial);
+                }
+                other => panic!("couldn't parse {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    pub fn incomplete() {
+        let set = request(SET, 7, 0, b"\x00\x00\x00\x0c\x00\x00\x00\x22",
+                          b"foo", b"data!");
+        assert_eq!(parse_command(&set[..10]),
+                   IResult::Incomplete(Needed::Size(24)));
+        assert_eq!(parse_command(&set[..30]),
+                   IResult::Incomplete(Needed::Size(24 + 16)));
+
+        // pipelined requests leave the next one for the next call
+        let mut two = set.clone();
+        two.extend_from_slice(&set);
+        match parse_command(&two) {
+            IResult::Done(remaining, _) => assert_eq!(remaining, &set[..]),
+            other => panic!("couldn't parse {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn oversized_values() {
+        let extras = b"\x00\x00\x00\x0c\x00\x00\x00\x22";
+        let set = request(SETQ, 7, 0, extras, b"foo", &vec![b'x'; 2000]);
+        // we only need the header to know
+        assert_eq!(oversized(&set[..10], 1024), None);
+        assert_eq!(oversized(&set[..24], 2000), None);
+        let (response, skip) = oversized(&set[..24], 1024).unwrap();
+        assert_eq!(skip, set.len());
+
+        // even quiet commands say when they've been turned down
+        assert!(response.should_reply());
+        let mut out = Vec::new();
+        format_response(response, &mut out).unwrap();
+        assert_eq!(out,
+                   [b"\x81\x11\x00\x00\x00\x00\x00\x03\x00\x00\x00\x0a\
+                      \x00\x00\x00\x07\x00\x00\x00\x00\x00\x00\x00\x00"
+                        as &[u8],
+                    b"Too large."]
+                       .concat());
+    }
+
+    #[test]
+    pub fn bad_framing() {
+        let mut set = request(SET, 7, 0, b"\x00\x00\x00\x0c\x00\x00\x00\x22",
+                              b"foo", b"data!");
+        set[0] = b's';
+        assert!(parse_command(&set).is_err());
+    }
+
+    #[test]
+    pub fn round_trip() {
+        let store = make_store();
+
+        let out = run(&store,
+                      &request(SET, 0xdeadbeef, 0,
+                               b"\x00\x00\x00\x0c\x00\x00\x00\x00",
+                               b"foo", b"bar"));
+        assert_eq!(out[0], RESPONSE_MAGIC);
+        assert_eq!(out[1], SET);
+        assert_eq!(&out[6..8], b"\x00\x00"); // status
+        assert_eq!(&out[12..16], b"\xde\xad\xbe\xef"); // opaque
+        let cas = out[16..24].to_vec();
+        assert!(cas != b"\x00\x00\x00\x00\x00\x00\x00\x00");
+
+        let out = run(&store, &request(GETK, 5, 0, b"", b"foo", b""));
+        assert_eq!(&out[2..4], b"\x00\x03"); // key length
+        assert_eq!(out[4], 4); // extras length
+        assert_eq!(&out[8..12], b"\x00\x00\x00\x0a"); // body length
+        assert_eq!(&out[16..24], &cas[..]);
+        assert_eq!(&out[24..], b"\x00\x00\x00\x0cfoobar");
+
+        // a cas with the wrong token is rejected
+        let out = run(&store,
+                      &request(SET, 0, 1234,
+                               b"\x00\x00\x00\x00\x00\x00\x00\x00",
+                               b"foo", b"baz"));
+        assert_eq!(&out[6..8], b"\x00\x02");
+
+        // quiet misses and quiet successes say nothing
+        assert!(run(&store, &request(GETQ, 0, 0, b"", b"nope", b""))
+            .is_empty());
+        assert!(run(&store, &request(DELETEQ, 0, 0, b"", b"foo", b""))
+            .is_empty());
+        let out = run(&store, &request(DELETEQ, 0, 0, b"", b"foo", b""));
+        assert_eq!(&out[6..8], b"\x00\x01");
+    }
+
+    #[test]
+    pub fn incr_autovivify() {
+        let store = make_store();
+        let incr = request(INCREMENT, 0, 0,
+                           b"\x00\x00\x00\x00\x00\x00\x00\x05\
+                             \x00\x00\x00\x00\x00\x00\x00\x0a\
+                             \x00\x00\x00\x00",
+                           b"foo", b"");
+
+        let out = run(&store, &incr);
+        assert_eq!(&out[6..8], b"\x00\x00");
+        assert_eq!(&out[24..], b"\x00\x00\x00\x00\x00\x00\x00\x0a");
+
+        let out = run(&store, &incr);
+        assert_eq!(&out[24..], b"\x00\x00\x00\x00\x00\x00\x00\x0f");
+        assert_eq!(Some("15".to_string()),
+                   store.lock(b"foo").simple_get("foo"));
+    }
+}
@@ -1,3 +1,4 @@
+use std::cmp;
 use std::env;
 use std::process;
 use std::str::FromStr;
@@ -6,7 +7,11 @@ use std::io::Write;
 use getopts::Options;
 
 use server;
+use store;
+use settings::Settings;
 use parser::parse_size;
+use policy::{PolicyType, SegmentLimits};
+use writelog::FsyncPolicy;
 
 macro_rules! println_stderr(
     ($($arg:tt)*) => (
@@ -18,16 +23,85 @@ macro_rules! println_stderr(
 );
 
 pub fn main() {
-    let mut port = 11211;
-    let mut capacity = 64 * 1024 * 1024;
-    let mut verbose = false;
+    let mut settings = Settings::default();
 
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
     let mut opts = Options::new();
     opts.optopt("p", "port", "port to listen on (default: 11211)", "PORT");
+    opts.optopt("U",
+                "udp-port",
+                "UDP port to listen on (default: 0, off)",
+                "PORT");
+    opts.optopt("s",
+                "socket",
+                "unix socket to listen on, as well as TCP unless -p is 0",
+                "PATH");
+    opts.optopt("a",
+                "socket-mask",
+                "permissions for the unix socket, in octal (default: 700)",
+                "MASK");
+    opts.optopt("t",
+                "threads",
+                "number of threads handling connections (default: 4)",
+                "THREADS");
+    opts.optopt("c",
+                "max-conns",
+                "most clients to have connected at once (default: 1024)",
+                "CONNS");
+    opts.optopt("",
+                "shards",
+                "number of independently locked pieces to split the cache \
+                 into, each with an even share of the memory, or fewer if \
+                 the shares would be too small for the biggest item \
+                 (default: 16)",
+                "SHARDS");
     opts.optopt("m", "memory", "port to listen on (default: 64mb)", "MEMORY");
+    opts.optopt("I",
+                "max-item-size",
+                "biggest value we'll store, from 1k to 128m (default: 1m)",
+                "SIZE");
+    opts.optopt("f",
+                "factor",
+                "growth factor between slab size classes (default: 1.25)",
+                "FACTOR");
+    opts.optopt("n",
+                "slab-min-size",
+                "smallest chunk size for storing values (default: 96)",
+                "BYTES");
+    opts.optopt("",
+                "eviction",
+                "how to choose what to throw away when memory is full: lru, \
+                 arc, tinylfu or segmented (default: lru)",
+                "POLICY");
+    opts.optmulti("o",
+                  "extended",
+                  "comma separated extended options: lru_segmented, \
+                   hot_lru_pct=PCT, warm_lru_pct=PCT, temporary_ttl=SECS, \
+                   lru_crawler, no_lru_crawler, lru_crawler_sleep=USECS, \
+                   lru_crawler_tocrawl=ITEMS, idle_timeout=SECS, \
+                   read_buf_max=SIZE, write_buf_max=SIZE",
+                  "OPTIONS");
+    opts.optopt("",
+                "snapshot",
+                "file to save the cache to on SIGTERM or a snapshot command",
+                "FILE");
+    opts.optopt("",
+                "restore",
+                "file to load the cache from at startup, as saved by \
+                 --snapshot",
+                "FILE");
+    opts.optopt("",
+                "write-log",
+                "file to write every change to as it happens, and replay at \
+                 startup",
+                "FILE");
+    opts.optopt("",
+                "log-fsync",
+                "how often the write log is fsynced: always, everysec or no \
+                 (default: everysec)",
+                "POLICY");
     opts.optflag("v", "verbose", "be really verbose");
     opts.optflag("h", "help", "print help and exit");
 
@@ -51,25 +125,252 @@ pub fn main() {
 
     if let Some(digits) = matches.opt_str("p") {
         if let Result::Ok(port_num) = FromStr::from_str(&digits) {
-            port = port_num;
+            settings.port = port_num;
         } else {
             println_stderr!("couldn't parse port num {}", digits);
             return print_usage_and_die(1);
         }
     }
 
+    if let Some(digits) = matches.opt_str("U") {
+        if let Result::Ok(port_num) = FromStr::from_str(&digits) {
+            settings.udp_port = port_num;
+        } else {
+            println_stderr!("couldn't parse udp port num {}", digits);
+            print_usage_and_die(1);
+        }
+    }
+
+    settings.socket_path = matches.opt_str("s");
+
+    if let Some(digits) = matches.opt_str("a") {
+        if let Result::Ok(mask) = u32::from_str_radix(&digits, 8) {
+            settings.socket_mask = mask;
+        } else {
+            println_stderr!("couldn't parse socket mask {}", digits);
+            print_usage_and_die(1);
+        }
+    }
+
+    if let Some(digits) = matches.opt_str("t") {
+        match FromStr::from_str(&digits) {
+            Result::Ok(threads) if threads > 0 => settings.threads = threads,
+            _ => {
+                println_stderr!("couldn't parse thread count {}", digits);
+                print_usage_and_die(1);
+            }
+        }
+    }
+
+    if let Some(digits) = matches.opt_str("c") {
+        match FromStr::from_str(&digits) {
+            Result::Ok(conns) if conns > 0 => settings.max_conns = conns,
+            _ => {
+                println_stderr!("couldn't parse connection limit {}", digits);
+                print_usage_and_die(1);
+            }
+        }
+    }
+
+    if let Some(digits) = matches.opt_str("shards") {
+        match FromStr::from_str(&digits) {
+            Result::Ok(shards) if shards > 0 => settings.shards = shards,
+            _ => {
+                println_stderr!("couldn't parse shard count {}", digits);
+                print_usage_and_die(1);
+            }
+        }
+    }
+
+    if settings.port == 0 && settings.udp_port == 0 &&
+       settings.socket_path.is_none() {
+        println_stderr!("nothing to listen on");
+        print_usage_and_die(1);
+    }
+
     if let Some(size_spec) = matches.opt_str("m") {
         if let Some(size) = parse_size(&size_spec) {
-            capacity = size;
+            settings.maxbytes = size;
         } else {
             println_stderr!("couldn't parse size {}", size_spec);
             return print_usage_and_die(1);
         }
     }
 
+    if let Some(size_spec) = matches.opt_str("I") {
+        match parse_size(&size_spec) {
+            Some(size) if (store::MIN_DATA_LIMIT..=store::MAX_DATA_LIMIT)
+                .contains(&size) => {
+                settings.item_size_max = size
+            }
+            _ => {
+                println_stderr!("couldn't parse item size {}", size_spec);
+                print_usage_and_die(1);
+            }
+        }
+        if settings.item_size_max > settings.maxbytes {
+            println_stderr!("the item size can't be more than the memory");
+            print_usage_and_die(1);
+        }
+    } else {
+        // the default can't be bigger than the memory either, but a small -m
+        // is no reason not to start
+        settings.item_size_max = cmp::min(settings.item_size_max,
+                                          settings.maxbytes);
+    }
+
+    if let Some(digits) = matches.opt_str("f") {
+        match FromStr::from_str(&digits) {
+            Result::Ok(factor) if factor > 1.0 => {
+                settings.growth_factor = factor
+            }
+            _ => {
+                println_stderr!("couldn't parse growth factor {}", digits);
+                print_usage_and_die(1);
+            }
+        }
+    }
+
+    if let Some(digits) = matches.opt_str("n") {
+        match FromStr::from_str(&digits) {
+            Result::Ok(size) if size > 0 => settings.chunk_size = size,
+            _ => {
+                println_stderr!("couldn't parse chunk size {}", digits);
+                print_usage_and_die(1);
+            }
+        }
+    }
+
+    if let Some(name) = matches.opt_str("eviction") {
+        if let Some(policy) = PolicyType::from_name(&name) {
+            settings.eviction = policy;
+        } else {
+            println_stderr!("unknown eviction policy {}", name);
+            print_usage_and_die(1);
+        }
+    }
+
+    for spec in matches.opt_strs("o") {
+        for option in spec.split(',').filter(|option| !option.is_empty()) {
+            if let Err(message) = extended_option(&mut settings, option) {
+                println_stderr!("{}", message);
+                print_usage_and_die(1);
+            }
+        }
+    }
+
+    settings.snapshot_file = matches.opt_str("snapshot");
+    settings.restore_file = matches.opt_str("restore");
+    settings.write_log = matches.opt_str("write-log");
+
+    if let Some(name) = matches.opt_str("log-fsync") {
+        if let Some(fsync) = FsyncPolicy::from_name(&name) {
+            settings.log_fsync = fsync;
+        } else {
+            println_stderr!("unknown fsync policy {}", name);
+            print_usage_and_die(1);
+        }
+    }
+
     if matches.opt_present("v") {
-        verbose = true;
+        settings.verbose = true;
     }
 
-    server::start(port, capacity, verbose);
+    server::start(settings);
+}
+
+fn extended_option(settings: &mut Settings,
+                   option: &str)
+                   -> Result<(), String> {
+    // one of memcached's -o options, like `hot_lru_pct=20`
+    let mut parts = option.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let value = parts.next();
+
+    match name {
+        "lru_segmented" => {
+            // keeping any limits that were already given
+            match settings.eviction {
+                PolicyType::Segmented(_) => (),
+                _ => {
+                    settings.eviction =
+                        PolicyType::Segmented(SegmentLimits::default())
+                }
+            }
+            Ok(())
+        }
+        "hot_lru_pct" | "warm_lru_pct" | "temporary_ttl" => {
+            let number = option_number(option, value)?;
+            let limits = match settings.eviction {
+                PolicyType::Segmented(ref mut limits) => limits,
+                _ => {
+                    return Err(format!("{} needs the segmented eviction \
+                                        policy",
+                                       name))
+                }
+            };
+            match name {
+                "hot_lru_pct" => limits.hot_pct = number,
+                "warm_lru_pct" => limits.warm_pct = number,
+                _ => limits.temporary_ttl = number as u32,
+            }
+            if limits.hot_pct + limits.warm_pct > 80 {
+                // like memcached, COLD always gets at least a fifth
+                return Err("hot_lru_pct + warm_lru_pct can't be more than 80"
+                    .to_string());
+            }
+            Ok(())
+        }
+        "lru_crawler" => {
+            settings.lru_crawler = true;
+            Ok(())
+        }
+        "no_lru_crawler" => {
+            settings.lru_crawler = false;
+            Ok(())
+        }
+        "lru_crawler_sleep" => {
+            match option_number(option, value)? {
+                sleep if sleep <= 1000000 => {
+                    settings.lru_crawler_sleep = sleep as u32;
+                    Ok(())
+                }
+                _ => Err("lru_crawler_sleep can't be more than a second"
+                    .to_string()),
+            }
+        }
+        "lru_crawler_tocrawl" => {
+            let tocrawl = option_number(option, value)?;
+            settings.lru_crawler_tocrawl = tocrawl as u32;
+            Ok(())
+        }
+        "idle_timeout" => {
+            let timeout = option_number(option, value)?;
+            settings.idle_timeout = timeout as u32;
+            Ok(())
+        }
+        "read_buf_max" | "write_buf_max" => {
+            let size = match value.and_then(parse_size) {
+                Some(size) => size,
+                None => {
+                    return Err(format!("couldn't parse extended option {}",
+                                       option))
+                }
+            };
+            if name == "read_buf_max" {
+                settings.read_buf_max = size;
+            } else {
+                settings.write_buf_max = size;
+            }
+            Ok(())
+        }
+        _ => Err(format!("unknown extended option {}", name)),
+    }
+}
+
+fn option_number(option: &str, value: Option<&str>) -> Result<usize, String> {
+    match value.map(FromStr::from_str) {
+        Some(Ok(number)) => Ok(number),
+        _ => Err(format!("couldn't parse extended option {}", option)),
+    }
 }
@@ -0,0 +1,186 @@
+//! A thin wrapper around Linux's epoll, just enough for the event loops in
+//! server.rs, plus an eventfd-based `Waker` for poking a loop from another
+//! thread
+
+use std::io;
+use std::io::{Read, Write};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use libc;
+
+// what we tell epoll to hand back to us when something's ready
+pub type Token = u64;
+
+pub const READABLE: u32 = libc::EPOLLIN as u32;
+pub const WRITABLE: u32 = libc::EPOLLOUT as u32;
+// we always get these, whether we asked for them or not
+pub const HANGUP: u32 = (libc::EPOLLHUP | libc::EPOLLERR) as u32;
+
+// how many events we pick up from a single wait
+const MAX_EVENTS: usize = 256;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Event {
+    pub token: Token,
+    pub events: u32,
+}
+
+pub struct Epoll {
+    fd: RawFd,
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+impl Epoll {
+    pub fn new() -> io::Result<Epoll> {
+        let fd = cvt(unsafe {
+            libc::epoll_create1(libc::EPOLL_CLOEXEC)
+        })?;
+        Ok(Epoll { fd })
+    }
+
+    fn ctl(&self,
+           op: libc::c_int,
+           fd: RawFd,
+           token: Token,
+           interest: u32)
+           -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: interest,
+            u64: token,
+        };
+        cvt(unsafe { libc::epoll_ctl(self.fd, op, fd, &mut event) })?;
+        Ok(())
+    }
+
+    pub fn add(&self,
+               fd: RawFd,
+               token: Token,
+               interest: u32)
+               -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    pub fn modify(&self,
+                  fd: RawFd,
+                  token: Token,
+                  interest: u32)
+                  -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    pub fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    pub fn wait(&self,
+                events: &mut Vec<Event>,
+                timeout_ms: i32)
+                -> io::Result<()> {
+        // blocks until something is ready or the timeout (-1 for none) passes,
+        // and replaces the contents of `events` with whatever's ready
+        let mut raw = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        events.clear();
+        let count = unsafe {
+            libc::epoll_wait(self.fd,
+                             raw.as_mut_ptr(),
+                             MAX_EVENTS as libc::c_int,
+                             timeout_ms)
+        };
+        if count < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                // as far as the caller cares, that's just nothing happening
+                return Ok(());
+            }
+            return Err(err);
+        }
+        for event in &raw[..count as usize] {
+            events.push(Event {
+                token: event.u64,
+                events: event.events,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+pub struct Waker {
+    file: File,
+}
+
+impl Waker {
+    pub fn new() -> io::Result<Waker> {
+        let fd = cvt(unsafe {
+            libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC)
+        })?;
+        Ok(Waker { file: unsafe { File::from_raw_fd(fd) } })
+    }
+
+    pub fn wake(&self) -> io::Result<()> {
+        match (&self.file).write(&1u64.to_ne_bytes()) {
+            Ok(_) => Ok(()),
+            // the counter is full, so whoever's listening is definitely going
+            // to wake up anyway
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn reset(&self) {
+        // called by the woken thread so that it doesn't keep being woken up
+        let mut buff = [0; 8];
+        let _ = (&self.file).read(&mut buff);
+    }
+}
+
+impl AsRawFd for Waker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn waking() {
+        let epoll = Epoll::new().unwrap();
+        let waker = Waker::new().unwrap();
+        epoll.add(waker.as_raw_fd(), 7, READABLE).unwrap();
+
+        let mut events = Vec::new();
+        epoll.wait(&mut events, 0).unwrap();
+        assert!(events.is_empty());
+
+        waker.wake().unwrap();
+        waker.wake().unwrap();
+        epoll.wait(&mut events, 1000).unwrap();
+        assert_eq!(events,
+                   vec![Event {
+                            token: 7,
+                            events: READABLE,
+                        }]);
+
+        // once it's reset it stops being ready
+        waker.reset();
+        epoll.wait(&mut events, 0).unwrap();
+        assert!(events.is_empty());
+    }
+}
@@ -1,56 +1,228 @@
-/// The LRU storage engine
+/// The storage engine. What gets thrown away to make room is up to an eviction
+/// policy from policy.rs, which is LRU unless told otherwise
 
 use std::cmp::Ord;
 use std::collections::HashMap;
+use std::collections::hash_map;
 use std::collections::BTreeSet;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
 use std::mem;
 use std::hash::Hash;
 use std::sync::Arc;
+use std::time::Instant;
+
+use policy::{EvictionPolicy, PolicyType};
 
 pub type Weight = usize;
 pub type Timestamp = u32;
 
-type LruEntryUsed<K> = (Timestamp, Arc<K>);
+// the granularity of the item size histograms we keep
+pub const SIZE_BUCKET: Weight = 32;
+
 type LruEntryExpires<K> = (Timestamp, Arc<K>);
 
 #[derive(Debug)]
-pub struct LruCache<K: HasWeight + Ord + Hash + Clone, V: HasWeight> {
+pub struct LruCache<K: HasWeight + Ord + Hash + Clone, V: HasWeight + Pooled> {
     map: HashMap<Arc<K>, LruEntry<K, V>>,
-    lru: BTreeSet<LruEntryUsed<K>>,
-    expires: BTreeSet<LruEntryExpires<K>>,
+    // every value lives in one of these, each with its own queues, so that
+    // the owner can make room in one without touching the others
+    pools: Vec<Pool<K>>,
     capacity: Weight,
     weight: Weight, // TODO store this?
+    stats: LruStats,
+    // flush_all with a delay. Rather than sweeping everything when it's
+    // scheduled, we remember the deadline and treat anything stored before it
+    // as gone once it passes. flushed_before is the last deadline that passed
+    flushed_before: Timestamp,
+    flush_deadline: Option<Timestamp>,
+}
+
+#[derive(Debug)]
+struct Pool<K> {
+    policy: Box<dyn EvictionPolicy<K>>,
+    expires: BTreeSet<LruEntryExpires<K>>,
+}
+
+#[derive(Debug,Default,Clone,PartialEq,Eq)]
+pub struct LruStats {
+    // live items that we had to throw away to make room
+    pub evictions: u64,
+    // ...of which nobody had ever fetched
+    pub evicted_unfetched: u64,
+    // expired items that we threw away to make room
+    pub reclaimed: u64,
+    // ...of which nobody had ever fetched
+    pub expired_unfetched: u64,
+    // evictions by the pool they were evicted from
+    pub evicted_by_pool: BTreeMap<usize, u64>,
+    // new items that the eviction policy wouldn't let in
+    pub rejections: u64,
+    // expired items that the crawler threw away before anybody needed the
+    // room
+    pub crawler_reclaimed: u64,
+}
+
+impl LruStats {
+    pub fn add(&mut self, other: &LruStats) {
+        // for adding up the stats of several caches
+        self.evictions += other.evictions;
+        self.evicted_unfetched += other.evicted_unfetched;
+        self.reclaimed += other.reclaimed;
+        self.expired_unfetched += other.expired_unfetched;
+        self.rejections += other.rejections;
+        self.crawler_reclaimed += other.crawler_reclaimed;
+        for (&pool, &count) in &other.evicted_by_pool {
+            *self.evicted_by_pool.entry(pool).or_insert(0) += count;
+        }
+    }
+}
+
+// how an entry had been used before a lookup
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Access {
+    pub fetched: bool,
+    pub used: Timestamp,
 }
 
 pub trait HasWeight {
     fn weight(&self) -> Weight;
 }
 
+pub trait Pooled {
+    // which of the cache's pools this goes in
+    fn pool(&self) -> usize;
+}
+
 #[derive(Debug)]
 pub struct LruEntry<K, V> {
     pub data: V,
     pub key: Arc<K>,
+    pool: usize,
     used: Timestamp,
+    stored: Timestamp,
     pub expires: Option<Timestamp>,
     weight: Weight,
+    // whether anybody has read this since it was set
+    pub fetched: bool,
+    // lease state for the meta commands: whether it's been invalidated but
+    // kept around to serve while somebody recaches it, and whether somebody
+    // has already been told that they're the one to do that
+    pub stale: bool,
+    pub win_token_sent: bool,
 }
 
-impl<K: HasWeight + Ord + Hash + Clone, V: HasWeight> LruCache<K, V> {
-    pub fn new(capacity: Weight) -> LruCache<K, V> {
+impl<K, V> LruCache<K, V>
+    where K: HasWeight + Ord + Hash + Clone + Debug + Send + Sync + 'static,
+          V: HasWeight + Pooled
+{
+    pub fn with_pools(capacity: Weight,
+                      pools: usize,
+                      policy: PolicyType)
+                      -> LruCache<K, V> {
+        // when we're over capacity we throw away whatever the pools' policies
+        // pick, least recently used first. To keep the pools apart, the owner
+        // should give us plenty of capacity and make room with evict_from
+        // instead
         LruCache {
             map: HashMap::new(),
-            lru: BTreeSet::new(),
-            expires: BTreeSet::new(),
+            pools: (0..pools)
+                .map(|_| {
+                    Pool {
+                        policy: policy.make(),
+                        expires: BTreeSet::new(),
+                    }
+                })
+                .collect(),
             capacity: capacity,
             weight: 0,
+            stats: LruStats::default(),
+            flushed_before: 0,
+            flush_deadline: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        // includes expired items that we haven't cleaned up yet
+        self.map.len()
+    }
+
+    pub fn weight(&self) -> Weight {
+        self.weight
+    }
+
+    pub fn stats(&self) -> &LruStats {
+        &self.stats
+    }
+
+    pub fn segments(&self, pool: usize) -> Vec<(&'static str, usize)> {
+        // how the pool's policy has split up its keys, if it does that
+        self.pools[pool].policy.segments()
+    }
+
+    pub fn maintain(&mut self, now: Timestamp) {
+        // give the policies a chance to do their housekeeping
+        for pool in &mut self.pools {
+            pool.policy.maintain(now);
         }
     }
 
+    pub fn entries(&self) -> hash_map::Values<'_, Arc<K>, LruEntry<K, V>> {
+        // every entry we hold, in no particular order and including expired
+        // ones that we haven't cleaned up yet
+        self.map.values()
+    }
+
+    pub fn by_recency(&self, now: Timestamp) -> Vec<&LruEntry<K, V>> {
+        // every live entry, least recently used first, for putting them back
+        // in the same order with restore
+        let cutoff = self.flush_cutoff(now);
+        let mut entries: Vec<&LruEntry<K, V>> = self.map
+            .values()
+            .filter(|entry| !dead(entry, cutoff, now))
+            .collect();
+        entries.sort_by(|a, b| (a.used, &a.key).cmp(&(b.used, &b.key)));
+        entries
+    }
+
     pub fn clear(&mut self) {
         self.map.clear();
-        self.lru.clear();
-        self.expires.clear();
+        for pool in &mut self.pools {
+            pool.policy.clear();
+            pool.expires.clear();
+        }
         self.weight = 0;
+        // there's nothing left for a pending flush to apply to
+        self.flushed_before = 0;
+        self.flush_deadline = None;
+    }
+
+    pub fn flush_at(&mut self, deadline: Timestamp, now: Timestamp) {
+        // schedule everything stored before `deadline` to go away when it
+        // passes. Like memcached this replaces any flush that's still pending,
+        // but one that's already happened stays happened
+        if let Some(old_deadline) = self.flush_deadline {
+            if old_deadline <= now {
+                self.flushed_before = old_deadline;
+            }
+        }
+        self.flush_deadline = Some(deadline);
+    }
+
+    pub fn pending_flush(&self, now: Timestamp) -> Option<Timestamp> {
+        // the deadline of a flush_all that's still to come, if there is one
+        match self.flush_deadline {
+            Some(deadline) if deadline > now => Some(deadline),
+            _ => None,
+        }
+    }
+
+    fn flush_cutoff(&self, now: Timestamp) -> Timestamp {
+        // entries stored before this have been flushed
+        match self.flush_deadline {
+            Some(deadline) if deadline <= now => deadline,
+            _ => self.flushed_before,
+        }
     }
 
     pub fn get_full_entry(&mut self,
@@ -65,11 +237,12 @@ impl<K: HasWeight + Ord + Hash + Clone, V: HasWeight> LruCache<K, V> {
                        key: &K,
                        now: Timestamp) 
                        -> Option<&mut LruEntry<K, V>> {
+        let cutoff = self.flush_cutoff(now);
         match self.map.get_mut(key) {
             None => Option::None,
 
-            Some(ref entry) if expired((*entry).expires, now) => {
-                // we found it, but it's expired. we could theoretically
+            Some(ref entry) if dead(entry, cutoff, now) => {
+                // we found it, but it's expired (or flushed). we could theoretically
                 // pre-emptively remove it on discovering this, but for the
                 // moment we'll leave it there and clean it up during the normal
                 // cleaup process (thereby keeping our reads fast and paying the
@@ -79,7 +252,7 @@ impl<K: HasWeight + Ord + Hash + Clone, V: HasWeight> LruCache<K, V> {
 
             Some(entry) => {
                 // we found it and it hasn't expired. Since it's being used now
-                // we need to update its position in the LRU
+                // we need to tell the eviction policy
 
                 // use the key we found inside of the structure instead of the
                 // one that was passed in, so we can save memory by just
@@ -87,22 +260,43 @@ impl<K: HasWeight + Ord + Hash + Clone, V: HasWeight> LruCache<K, V> {
                 // copying it
                 let inner_key = (*entry).key.clone();
 
-                if ((*entry).used) != now {
-                    // only update it if the value would change
-                    let old_lru_key = ((*entry).used, inner_key.clone());
-                    self.lru.remove(&old_lru_key);
+                let policy = &mut self.pools[entry.pool].policy;
+                policy.access(&inner_key, entry.used, now);
+                entry.used = now;
 
-                    entry.used = now;
-
-                    let new_lru_key = (now, inner_key.clone());
-                    self.lru.insert(new_lru_key);
-                }
+                entry.fetched = true;
 
                 Some(entry)
             }
         }
     }
 
+    pub fn lookup(&mut self,
+                  key: &K,
+                  now: Timestamp,
+                  bump: bool)
+                  -> Option<(Access, &mut LruEntry<K, V>)> {
+        // like get_full_entry, but also tells you how the entry had been used
+        // before this lookup, and can leave the LRU alone. Callers may update
+        // the entry in place, but mustn't change anything about its weight
+        let cutoff = self.flush_cutoff(now);
+        let access = match self.map.get(key) {
+            Some(entry) if !dead(entry, cutoff, now) => {
+                Access {
+                    fetched: entry.fetched,
+                    used: entry.used,
+                }
+            }
+            _ => return None,
+        };
+        let entry = if bump {
+            self._get_full_entry(key, now)
+        } else {
+            self.map.get_mut(key)
+        };
+        entry.map(|entry| (access, entry))
+    }
+
     pub fn get(&mut self, key: &K, now: Timestamp) -> Option<&V> {
         self.get_full_entry(key, now).map(|entry| &entry.data)
     }
@@ -119,7 +313,7 @@ impl<K: HasWeight + Ord + Hash + Clone, V: HasWeight> LruCache<K, V> {
         }
 
         // if it's already in here, we need to get rid of it
-        self.delete(&key);
+        let replacing = self.delete(&key);
 
         let weight = compute_weight(&key, &value);
 
@@ -128,45 +322,94 @@ impl<K: HasWeight + Ord + Hash + Clone, V: HasWeight> LruCache<K, V> {
             return false;
         }
 
+        if !replacing && self.weight + weight > self.capacity {
+            // something has to go to make room for this, so the policy gets
+            // a say in whether it's worth it. Things that are already here
+            // have been let in once
+            let pool = value.pool();
+            if let Some(victim) = self.next_victim(now) {
+                if !self.admits_over(&key, pool, &victim, now) {
+                    return false;
+                }
+            }
+        }
+
         // free up any space that we need to in order to fit this
         let capacity = self.capacity;
         self.deweight(capacity - weight, now);
 
         let k2 = Arc::new(key.clone());
+        let pool = value.pool();
 
         let entry = LruEntry {
             key: k2.clone(),
+            pool,
             data: value,
             expires: expires,
             weight: weight,
             used: now,
+            stored: now,
+            fetched: false,
+            stale: false,
+            win_token_sent: false,
         };
 
         self.map.insert(k2.clone(), entry);
         self.weight += weight;
 
-        let lru_key = (now, k2.clone());
-        self.lru.insert(lru_key);
+        self.pools[pool].policy.insert(&k2, expires, now);
 
         if let Some(expires_ts) = expires {
             // if it expires, add it to the expiration queue
             let expires_key = (expires_ts, k2.clone());
-            self.expires.insert(expires_key);
+            self.pools[pool].expires.insert(expires_key);
         }
 
         // TODO Store always ignores this return value. Do we care?
         true
     }
 
-    pub fn fast_get(&self, key: &K, now: Timestamp) -> Option<&V> {
-        // fetch the value of a key without updating the LRU
+    pub fn restore(&mut self,
+                   key: K,
+                   value: V,
+                   expires: Option<Timestamp>,
+                   used: Timestamp,
+                   now: Timestamp)
+                   -> bool {
+        // like set, but for something we had before a restart. It keeps the
+        // time it was last used, so the caller should hand them to us least
+        // recently used first. It can't have been stored any later than
+        // that either, which is what a flush_all that's still to come needs
+        let k2 = key.clone();
+        if !self.set(key, value, expires, now) {
+            return false;
+        }
+        if let Some(entry) = self.map.get_mut(&k2) {
+            entry.used = used;
+            entry.stored = used;
+        }
+        true
+    }
+
+    pub fn flushed(&self, stored: Timestamp, now: Timestamp) -> bool {
+        // whether something stored then would have been flushed by now
+        stored < self.flush_cutoff(now)
+    }
+
+    pub fn peek(&self, key: &K, now: Timestamp) -> Option<&LruEntry<K, V>> {
+        // fetch the entry for a key without updating the LRU
         match self.map.get(key) {
-            Some(entry) if expired((*entry).expires, now) => None,
-            Some(entry) => Some(&(*entry).data),
+            Some(entry) if dead(entry, self.flush_cutoff(now), now) => None,
+            Some(entry) => Some(entry),
             None => None,
         }
     }
 
+    pub fn fast_get(&self, key: &K, now: Timestamp) -> Option<&V> {
+        // fetch the value of a key without updating the LRU
+        self.peek(key, now).map(|entry| &entry.data)
+    }
+
     pub fn contains(&self, key: &K, now: Timestamp) -> bool {
         // checks for the presence of a key (without updating the LRU)
         self.fast_get(key, now).is_some()
@@ -178,63 +421,63 @@ impl<K: HasWeight + Ord + Hash + Clone, V: HasWeight> LruCache<K, V> {
                  now: Timestamp)
                  -> bool {
         // update the timestamp and last-used field of a row without copying the
-        // whole contents
-        let (old_key, old_expires, old_used) = match self._get_full_entry(key, now) {
-            None => {
-                // just bail, it was never in here anyway
-                return false;
-            }
-            Some(full_entry) => {
-                // change it in-place
-                let old_key = (*full_entry).key.clone();
-                let old_expires = (*full_entry).expires;
-                let old_used = (*full_entry).used;
-                (*full_entry).expires = expires;
-                (*full_entry).used = now;
-                (old_key, old_expires, old_used)
-            }
-        };
+        // whole contents. Finding it counts as using it, so that's the
+        // eviction policy told
+        let (old_key, pool, old_expires) =
+            match self._get_full_entry(key, now) {
+                None => {
+                    // just bail, it was never in here anyway
+                    return false;
+                }
+                Some(full_entry) => {
+                    // change it in-place
+                    let old_key = full_entry.key.clone();
+                    let old_expires = full_entry.expires;
+                    full_entry.expires = expires;
+                    (old_key, full_entry.pool, old_expires)
+                }
+            };
 
         // update our data structures
-        self._touch(old_key, old_expires, expires, old_used, now);
+        self._touch(old_key, pool, old_expires, expires);
         true
     }
 
     fn _touch(&mut self,
               key: Arc<K>,
+              pool: usize,
               old_expires: Option<Timestamp>,
-              new_expires: Option<Timestamp>,
-              old_used: Timestamp,
-              now: Timestamp) {
+              new_expires: Option<Timestamp>) {
+        let pool = &mut self.pools[pool];
 
         if old_expires != new_expires {
             if let Some(old_expires_ts) = old_expires {
                 // if it expired before, we have to remove it
                 let old_expires_key = (old_expires_ts, key.clone());
-                self.expires.remove(&old_expires_key);
+                pool.expires.remove(&old_expires_key);
             }
 
             if let Some(expires_ts) = new_expires {
                 // if it expires now, we have to add it
                 let expires_key = (expires_ts, key.clone());
-                self.expires.insert(expires_key);
+                pool.expires.insert(expires_key);
             }
         }
-
-        if old_used != now {
-            let old_lru_key = (old_used, key.clone());
-            self.lru.remove(&old_lru_key);
-            let new_lru_key = (now, key.clone());
-            self.lru.insert(new_lru_key);
-        }
     }
 
     pub fn delete(&mut self, key: &K) -> bool {
+        self.remove(key, false)
+    }
+
+    fn remove(&mut self, key: &K, evicted: bool) -> bool {
+        // `evicted` tells the eviction policy that we chose to throw it away,
+        // rather than somebody deleting it or replacing it
         let found = {
             match self.map.get(key) {
                 None => None,
                 Some(entry) => {
                     Some(((*entry).key.clone(),
+                          (*entry).pool,
                           (*entry).expires,
                           (*entry).used,
                           (*entry).weight))
@@ -242,13 +485,13 @@ impl<K: HasWeight + Ord + Hash + Clone, V: HasWeight> LruCache<K, V> {
             }
         };
 
-        if let Some((old_key, expires, used, weight)) = found {
+        if let Some((old_key, pool, expires, used, weight)) = found {
             self.map.remove(&old_key);
-            let lru_key = (used, old_key.clone());
-            self.lru.remove(&lru_key);
+            let pool = &mut self.pools[pool];
+            pool.policy.remove(&old_key, used, evicted);
             if let Some(expires_ts) = expires {
                 let expires_key = (expires_ts, old_key.clone());
-                self.lru.remove(&expires_key);
+                pool.expires.remove(&expires_key);
             }
             self.weight -= weight;
             true
@@ -274,50 +517,143 @@ impl<K: HasWeight + Ord + Hash + Clone, V: HasWeight> LruCache<K, V> {
             return;
         }
 
-        let expired_key = {
-            // check the expiration queue for stuff that's already expired that
-            // we can just delete
-            let maybe_expirable = &self.expires;
-            let mut maybe_expirable = maybe_expirable.into_iter();
-            let maybe_expirable = maybe_expirable.next();
-            match maybe_expirable {
-                None => None,
-                Some(found_tuple) => {
-                    let (ref expired_ts, ref expired_key) = *found_tuple;
-                    if _expired(*expired_ts, now) {
-                        Some(expired_key.clone())
-                    } else {
-                        None
-                    }
-                }
-            }
-        };
+        match self.next_victim(now) {
+            Some(key_ref) => self.throw_away(&key_ref, now),
+            None => unreachable!("there's nothing to evict?"),
+        }
+    }
 
-        if let Some(key_ref) = expired_key {
-            self.delete(&*key_ref);
-            return;
+    fn next_victim(&self, now: Timestamp) -> Option<Arc<K>> {
+        // check the expiration queues for stuff that's already expired that
+        // we can just delete
+        let expired_key = self.pools
+            .iter()
+            .filter_map(|pool| first_expired(pool, now))
+            .next();
+
+        if expired_key.is_some() {
+            return expired_key;
         }
 
-        // otherwise we have to use the LRU
-        let lru_key = {
-            let lru = &self.lru;
-            let mut lru = lru.into_iter();
-            let lru = lru.next();
-            match lru {
-                None => None,
-                Some(found_tuple) => {
-                    let (_, ref lru_key) = *found_tuple;
-                    Some(lru_key.clone())
-                }
+        // otherwise each pool's policy offers something up, and the least
+        // recently used of those goes
+        self.pools
+            .iter()
+            .filter_map(|pool| pool.policy.victim())
+            .min_by_key(|key| (self.map[key].used, key.clone()))
+    }
+
+    fn pool_victim(&self, pool: usize, now: Timestamp) -> Option<Arc<K>> {
+        // what evict_from would throw away
+        let pool = &self.pools[pool];
+        first_expired(pool, now).or_else(|| pool.policy.victim())
+    }
+
+    pub fn admits(&mut self, key: &K, pool: usize, now: Timestamp) -> bool {
+        // for an owner that's about to evict_from `pool` to make room for
+        // `key`: whether it should bother, or just not store it
+        if self.map.contains_key(key) {
+            return true;
+        }
+        match self.pool_victim(pool, now) {
+            Some(victim) => self.admits_over(key, pool, &victim, now),
+            None => true,
+        }
+    }
+
+    fn admits_over(&mut self,
+                   key: &K,
+                   pool: usize,
+                   victim: &Arc<K>,
+                   now: Timestamp)
+                   -> bool {
+        // whether `key`, on its way into `pool`, gets to push out `victim`.
+        // Dead things are free to throw away
+        if dead(&self.map[victim], self.flush_cutoff(now), now) {
+            return true;
+        }
+        let admitted = self.pools[pool].policy.admit(key, victim);
+        if !admitted {
+            self.stats.rejections += 1;
+        }
+        admitted
+    }
+
+    pub fn evict_from(&mut self, pool: usize, now: Timestamp) -> bool {
+        // make room in just the one pool, preferring something that's expired
+        // anyway to whatever its policy picks. False if it's empty
+        match self.pool_victim(pool, now) {
+            Some(key_ref) => {
+                self.throw_away(&key_ref, now);
+                true
             }
-        };
+            None => false,
+        }
+    }
 
-        if let Some(key_ref) = lru_key {
-            self.delete(&*key_ref);
-            return;
+    pub fn remove_matching<F>(&mut self, pool: usize, matches: F) -> usize
+        where F: Fn(&V) -> bool
+    {
+        // delete everything in a pool that `matches` likes, for when that
+        // pool's memory is wanted elsewhere. Returns how many there were
+        let found: Vec<Arc<K>> = self.map
+            .values()
+            .filter(|entry| entry.pool == pool && matches(&entry.data))
+            .map(|entry| entry.key.clone())
+            .collect();
+        for key_ref in &found {
+            self.delete(&**key_ref);
         }
+        found.len()
+    }
 
-        unreachable!("there's nothing on the LRU?");
+    pub fn crawl(&mut self,
+                 pool: usize,
+                 now: Timestamp,
+                 limit: usize,
+                 deadline: Instant)
+                 -> (usize, bool) {
+        // throw away up to `limit` of a pool's expired entries, soonest
+        // expired first, until `deadline`. They're at the front of the
+        // expiration queue, so we never have to look at anything else.
+        // Returns how many we threw away, and whether there may be more
+        let mut reclaimed = 0;
+        while let Some(key_ref) = first_expired(&self.pools[pool], now) {
+            if reclaimed >= limit || Instant::now() >= deadline {
+                return (reclaimed, true);
+            }
+            if !self.map[&key_ref].fetched {
+                self.stats.expired_unfetched += 1;
+            }
+            self.stats.crawler_reclaimed += 1;
+            self.remove(&*key_ref, false);
+            reclaimed += 1;
+        }
+        (reclaimed, false)
+    }
+
+    fn throw_away(&mut self, key_ref: &Arc<K>, now: Timestamp) {
+        // delete something to make room. It only counts as an eviction if it
+        // was still alive
+        let (fetched, pool, was_dead) = {
+            let entry = &self.map[key_ref];
+            (entry.fetched,
+             entry.pool,
+             dead(entry, self.flush_cutoff(now), now))
+        };
+        if was_dead {
+            self.stats.reclaimed += 1;
+            if !fetched {
+                self.stats.expired_unfetched += 1;
+            }
+        } else {
+            self.stats.evictions += 1;
+            if !fetched {
+                self.stats.evicted_unfetched += 1;
+            }
+            *self.stats.evicted_by_pool.entry(pool).or_insert(0) += 1;
+        }
+        self.remove(&**key_ref, !was_dead);
     }
 
     #[cfg(test)]
@@ -325,8 +661,9 @@ impl<K: HasWeight + Ord + Hash + Clone, V: HasWeight> LruCache<K, V> {
         // very expensive operation that fetches a full list of all of the keys
         // that we know about that aren't expired
         let mut ret = Vec::new();
+        let cutoff = self.flush_cutoff(now);
         for (ref key, ref value) in &self.map {
-            if !expired((*value).expires, now) {
+            if !dead(value, cutoff, now) {
                 // more stars is better, right?
                 let copied = (*(**key)).clone();
                 ret.push(copied);
@@ -337,6 +674,39 @@ impl<K: HasWeight + Ord + Hash + Clone, V: HasWeight> LruCache<K, V> {
     }
 }
 
+impl<K, V> LruEntry<K, V> {
+    pub fn weight(&self) -> Weight {
+        self.weight
+    }
+
+    pub fn used(&self) -> Timestamp {
+        self.used
+    }
+}
+
+pub fn size_bucket(weight: Weight) -> Weight {
+    // rounds up to the nearest SIZE_BUCKET
+    weight.div_ceil(SIZE_BUCKET) * SIZE_BUCKET
+}
+
+fn first_expired<K>(pool: &Pool<K>, now: Timestamp) -> Option<Arc<K>> {
+    // the entry in the pool that expired first, if it's expired yet
+    match pool.expires.iter().next() {
+        Some(&(expires, ref key)) if _expired(expires, now) => {
+            Some(key.clone())
+        }
+        _ => None,
+    }
+}
+
+fn dead<K, V>(entry: &LruEntry<K, V>,
+              flush_cutoff: Timestamp,
+              now: Timestamp)
+              -> bool {
+    // whether an entry is still hanging around but shouldn't be visible
+    expired(entry.expires, now) || entry.stored < flush_cutoff
+}
+
 fn expired(timestamp: Option<Timestamp>, now: Timestamp) -> bool {
     match timestamp {
         Some(ts) if _expired(ts, now) => true,
@@ -358,7 +728,7 @@ pub fn compute_weight<K: HasWeight, V: HasWeight>(key: &K,
     sum += 3 * mem::size_of::<Arc<K>>();
     sum += value.weight();
     sum += mem::size_of::<Weight>();
-    sum += 2 * mem::size_of::<Timestamp>();
+    sum += 3 * mem::size_of::<Timestamp>();
     sum += 2 * mem::size_of::<Option<Timestamp>>();
     sum
 }
@@ -372,98 +742,365 @@ impl HasWeight for Vec<u8> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant};
+    use policy::SegmentLimits;
 
     const NOW: Timestamp = 100;
     const FUTURE: Timestamp = NOW + 1;
     const FUTURE2: Timestamp = NOW + 2;
     const PAST: Timestamp = NOW - 1;
     const CAPACITY: Weight = 300;
+    // everything here should hold for all of them
+    const POLICIES: [PolicyType; 4] = [PolicyType::Lru,
+                                       PolicyType::Arc,
+                                       PolicyType::TinyLfu,
+                                       PolicyType::Segmented(SEGMENTED)];
+    const SEGMENTED: SegmentLimits = SegmentLimits {
+        hot_pct: 20,
+        warm_pct: 40,
+        temporary_ttl: 61,
+    };
+
+    impl Pooled for Vec<u8> {
+        fn pool(&self) -> usize {
+            // so there's something to tell the pools apart by
+            if self.len() > 50 { 1 } else { 0 }
+        }
+    }
 
     #[test]
     fn basic_set() {
-        let mut store = make_store();
+        for &policy in POLICIES.iter() {
+            let mut store = make_store(policy);
 
-        store.set(b("foo"), b("data"), None, NOW);
-        assert!(store.contains(&b("foo"), NOW));
-        assert_eq!(store.all_keys(NOW), vec![b("foo")]);
+            store.set(b("foo"), b("data"), None, NOW);
+            assert!(store.contains(&b("foo"), NOW));
+            assert_eq!(store.all_keys(NOW), vec![b("foo")]);
+        }
     }
 
     #[test]
     fn set_already_expired() {
-        let mut store = make_store();
+        for &policy in POLICIES.iter() {
+            let mut store = make_store(policy);
 
-        store.set(b("foo"), b("data"), Some(PAST), NOW);
-        assert!(store.all_keys(NOW).is_empty());
+            store.set(b("foo"), b("data"), Some(PAST), NOW);
+            assert!(store.all_keys(NOW).is_empty());
+        }
     }
 
     #[test]
     fn set_expires() {
-        let mut store = make_store();
+        for &policy in POLICIES.iter() {
+            let mut store = make_store(policy);
 
-        store.set(b("foo"), b("data"), Some(PAST), NOW);
-        assert!(store.all_keys(FUTURE).is_empty());
+            store.set(b("foo"), b("data"), Some(PAST), NOW);
+            assert!(store.all_keys(FUTURE).is_empty());
+        }
     }
 
     #[test]
     fn too_big() {
-        let mut store = make_store();
+        for &policy in POLICIES.iter() {
+            let mut store = make_store(policy);
 
-        store.set(b("foo1"), b("data"), None, PAST);
+            store.set(b("foo1"), b("data"), None, PAST);
 
-        let big = make_big(CAPACITY * 2);
+            let big = make_big(CAPACITY * 2);
 
-        store.set(b("foo2"), big, None, PAST);
+            store.set(b("foo2"), big, None, PAST);
 
-        // setting that big item should have been rejected, so the old data
-        // should still be there
-        assert!(store.contains(&b("foo1"), NOW));
-        assert_eq!(store.all_keys(NOW), vec![b("foo1")]);
+            // setting that big item should have been rejected, so the old data
+            // should still be there
+            assert!(store.contains(&b("foo1"), NOW));
+            assert_eq!(store.all_keys(NOW), vec![b("foo1")]);
+        }
     }
 
     #[test]
     fn outgrow() {
-        let mut store = make_store();
+        for &policy in POLICIES.iter() {
+            let mut store = make_store(policy);
 
-        store.set(b("foo1"), b("data"), None, PAST);
+            store.set(b("foo1"), b("data"), None, PAST);
 
-        // these should push that guy out
-        store.set(b("foo2"), make_big(30), None, NOW);
-        store.set(b("foo3"), make_big(30), None, FUTURE);
+            // these should push that guy out
+            store.set(b("foo2"), make_big(30), None, NOW);
+            store.set(b("foo3"), make_big(30), None, FUTURE);
 
-        assert!(!store.contains(&b("foo1"), FUTURE2));
-        assert!(store.contains(&b("foo2"), FUTURE2));
-        assert!(store.contains(&b("foo3"), FUTURE2));
+            assert!(!store.contains(&b("foo1"), FUTURE2));
+            assert!(store.contains(&b("foo2"), FUTURE2));
+            assert!(store.contains(&b("foo3"), FUTURE2));
+        }
     }
 
     #[test]
     fn prefer_expired() {
         // make sure we prefer to remove expired members over live members
-        let mut store = make_store();
+        for &policy in POLICIES.iter() {
+            let mut store = make_store(policy);
+
+            store.set(b("foo1"), make_big(30), None, PAST);
+            store.set(b("foo2"), make_big(30), Some(NOW), NOW);
+
+            // this has to push one of them out
+            store.set(b("foo3"), make_big(30), None, FUTURE);
 
-        store.set(b("foo1"), make_big(30), None, PAST);
-        store.set(b("foo2"), make_big(30), Some(NOW), NOW);
+            assert!(store.contains(&b("foo1"), FUTURE2));
+            assert!(!store.contains(&b("foo2"), FUTURE2));
+            assert!(store.contains(&b("foo3"), FUTURE2));
+        }
+    }
 
-        // this has to push one of them out
-        store.set(b("foo3"), make_big(30), None, FUTURE);
+    #[test]
+    fn eviction_stats() {
+        for &policy in POLICIES.iter() {
+            let mut store = make_store(policy);
+
+            store.set(b("foo1"), make_big(30), None, PAST);
+            store.set(b("foo2"), make_big(30), Some(NOW), PAST);
+            assert!(store.get(&b("foo1"), PAST).is_some());
+
+            // pushes out the expired one, then the fetched one. Unless it's
+            // ARC or the segmented LRU, which keep that one because it was
+            // fetched, or TinyLFU, which won't throw it away for something
+            // it's never seen
+            store.set(b("foo3"), make_big(30), None, FUTURE);
+            store.set(b("foo4"), make_big(30), None, FUTURE);
+
+            let (kept, evictions, unfetched, rejections) = match policy {
+                PolicyType::Lru => (vec![b("foo3"), b("foo4")], 1, 0, 0),
+                PolicyType::Arc |
+                PolicyType::Segmented(_) => {
+                    (vec![b("foo1"), b("foo4")], 1, 1, 0)
+                }
+                PolicyType::TinyLfu => (vec![b("foo1"), b("foo3")], 0, 0, 1),
+            };
+            assert_eq!(store.all_keys(FUTURE), kept);
 
-        assert!(store.contains(&b("foo1"), FUTURE2));
-        assert!(!store.contains(&b("foo2"), FUTURE2));
-        assert!(store.contains(&b("foo3"), FUTURE2));
+            let mut evicted_by_pool = BTreeMap::new();
+            if evictions > 0 {
+                evicted_by_pool.insert(1, evictions);
+            }
+            assert_eq!(store.stats(),
+                       &LruStats {
+                           evictions,
+                           evicted_unfetched: unfetched,
+                           reclaimed: 1,
+                           expired_unfetched: 1,
+                           evicted_by_pool,
+                           rejections,
+                           crawler_reclaimed: 0,
+                       });
+            assert_eq!(store.len(), 2);
+        }
+    }
+
+    #[test]
+    fn pools() {
+        for &policy in POLICIES.iter() {
+            let mut store = LruCache::with_pools(1000, 2, policy);
+
+            store.set(b("big1"), make_big(30), None, PAST);
+            store.set(b("small1"), b("data"), Some(NOW), PAST);
+            store.set(b("small2"), b("data"), None, PAST);
+            store.set(b("small3"), b("data"), None, NOW);
+            store.set(b("big2"), make_big(30), None, NOW);
+
+            // the expired one goes first, then the least recently used of the
+            // others in the same pool
+            assert!(store.evict_from(0, FUTURE));
+            assert!(store.evict_from(0, FUTURE));
+            assert_eq!(store.all_keys(FUTURE),
+                       vec![b("big1"), b("big2"), b("small3")]);
+            assert_eq!(store.stats().reclaimed, 1);
+            assert_eq!(store.stats().evicted_by_pool[&0], 1);
+
+            assert_eq!(store.remove_matching(1, |data| data[1] == 1), 2);
+            assert!(!store.evict_from(1, FUTURE));
+            assert_eq!(store.all_keys(FUTURE), vec![b("small3")]);
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        for &policy in POLICIES.iter() {
+            let mut store = make_store(policy);
+
+            store.set(b("foo"), b("data"), None, PAST);
+            let (access, _) = store.lookup(&b("foo"), NOW, false).unwrap();
+            assert_eq!(access, Access { fetched: false, used: PAST });
+
+            // that lookup didn't bump it, so this one sees the same thing
+            let (access, _) = store.lookup(&b("foo"), NOW, true).unwrap();
+            assert_eq!(access, Access { fetched: false, used: PAST });
+
+            let (access, entry) =
+                store.lookup(&b("foo"), FUTURE, false).unwrap();
+            assert_eq!(access, Access { fetched: true, used: NOW });
+            assert_eq!(entry.data, b("data"));
+
+            assert!(store.lookup(&b("bar"), NOW, true).is_none());
+
+            // setting it again starts its lease state over
+            store.lookup(&b("foo"), NOW, false).unwrap().1.stale = true;
+            store.set(b("foo"), b("data"), None, NOW);
+            assert!(!store.lookup(&b("foo"), NOW, false).unwrap().1.stale);
+        }
     }
 
     #[test]
     fn clear() {
-        let mut store = make_store();
+        for &policy in POLICIES.iter() {
+            let mut store = make_store(policy);
 
-        store.set(b("foo1"), b("data"), None, PAST);
-        store.clear();
+            store.set(b("foo1"), b("data"), None, PAST);
+            store.clear();
 
-        assert!(!store.contains(&b("foo1"), NOW));
+            assert!(!store.contains(&b("foo1"), NOW));
+        }
     }
 
 
-    fn make_store() -> LruCache<Vec<u8>, Vec<u8>> {
-        let store = LruCache::new(CAPACITY);
+    #[test]
+    fn delayed_flush() {
+        for &policy in POLICIES.iter() {
+            let mut store = make_store(policy);
+
+            store.set(b("foo1"), b("data"), None, PAST);
+            store.flush_at(FUTURE, NOW);
+            store.set(b("foo2"), b("data"), None, NOW);
+            store.set(b("foo3"), b("data"), None, FUTURE);
+
+            // nothing happens until the deadline...
+            assert_eq!(store.all_keys(NOW),
+                       vec![b("foo1"), b("foo2"), b("foo3")]);
+            // ...and then only things stored before it go away
+            assert_eq!(store.all_keys(FUTURE), vec![b("foo3")]);
+            assert!(store.get(&b("foo2"), FUTURE).is_none());
+            assert!(!store.touch(&b("foo2"), None, FUTURE));
+
+            // a later flush doesn't bring them back while it's pending
+            store.flush_at(FUTURE2 + 10, FUTURE2);
+            assert_eq!(store.all_keys(FUTURE2), vec![b("foo3")]);
+            store.set(b("foo1"), b("data"), None, FUTURE2);
+            assert!(store.all_keys(FUTURE2 + 10).is_empty());
+
+            // cleaning them up to make room counts as reclaiming, not evicting
+            store.set(b("foo4"), make_big(100), None, FUTURE2 + 10);
+            assert_eq!(store.stats().evictions, 0);
+            assert_eq!(store.stats().reclaimed, 3);
+        }
+    }
+
+    #[test]
+    fn trace_replay() {
+        // a few popular keys among a long tail of keys that are only used
+        // once. TinyLFU shouldn't let the tail flush out the popular ones
+        // the way LRU does. The trace is always the same, so the hit counts
+        // are too
+        let trace = make_trace(10000);
+        let lru = replay(PolicyType::Lru, &trace);
+        let arc = replay(PolicyType::Arc, &trace);
+        let tinylfu = replay(PolicyType::TinyLfu, &trace);
+        let segmented = replay(PolicyType::Segmented(SEGMENTED), &trace);
+        assert_eq!(lru, 1961);
+        assert_eq!(arc, 3490);
+        assert_eq!(tinylfu, 3409);
+        assert_eq!(segmented, 2551);
+        // and any of the others beats LRU on it
+        assert!(tinylfu > lru);
+        assert!(arc > lru);
+        assert!(segmented > lru);
+    }
+
+    fn make_trace(length: usize) -> Vec<Vec<u8>> {
+        // half of the requests are for one of 100 keys, the lower numbered
+        // ones more often, and the rest are for keys we never see again. A
+        // little LCG keeps it the same every time
+        let mut state: u64 = 1;
+        let mut random = || {
+            state = state.wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as usize
+        };
+        (0..length)
+            .map(|request| {
+                let id = if random() % 2 == 0 {
+                    let popular = random() % 100;
+                    popular * popular / 100
+                } else {
+                    1000 + request
+                };
+                format!("key{:05}", id).into_bytes()
+            })
+            .collect()
+    }
+
+    fn replay(policy: PolicyType, trace: &[Vec<u8>]) -> usize {
+        // with room for 50 entries, storing whatever missed, and the
+        // maintainer running every so often
+        let capacity = 50 * compute_weight(&trace[0], &b("data"));
+        let mut store = LruCache::with_pools(capacity, 2, policy);
+        let mut hits = 0;
+        for (now, key) in trace.iter().enumerate() {
+            let now = now as Timestamp;
+            if now.is_multiple_of(100) {
+                store.maintain(now);
+            }
+            if store.get(key, now).is_some() {
+                hits += 1;
+            } else {
+                store.set(key.clone(), b("data"), None, now);
+            }
+        }
+        hits
+    }
+
+    #[test]
+    fn crawl() {
+        for &policy in POLICIES.iter() {
+            let mut store = LruCache::with_pools(1000, 2, policy);
+            let later = Instant::now() + Duration::from_secs(60);
+
+            store.set(b("foo1"), b("data"), Some(NOW), PAST);
+            store.set(b("foo2"), b("data"), Some(FUTURE), PAST);
+            store.set(b("foo3"), b("data"), Some(NOW), PAST);
+            store.set(b("foo4"), b("data"), None, PAST);
+            store.set(b("big"), make_big(30), Some(NOW), PAST);
+            assert!(store.get(&b("foo3"), PAST).is_some());
+
+            // a stretch that runs out of time doesn't get anything done
+            assert_eq!(store.crawl(0, FUTURE, 10, Instant::now()), (0, true));
+
+            // only the expired ones go, only from the one pool, and no more
+            // than we asked for
+            assert_eq!(store.crawl(0, FUTURE, 1, later), (1, true));
+            assert_eq!(store.crawl(0, FUTURE, 10, later), (1, false));
+            assert_eq!(store.len(), 3);
+            assert_eq!(store.all_keys(NOW),
+                       vec![b("big"), b("foo2"), b("foo4")]);
+
+            let stats = store.stats();
+            assert_eq!(stats.crawler_reclaimed, 2);
+            assert_eq!(stats.expired_unfetched, 1);
+            assert_eq!(stats.reclaimed, 0);
+            assert_eq!(stats.evictions, 0);
+        }
+    }
+
+    #[test]
+    fn size_buckets() {
+        assert_eq!(size_bucket(0), 0);
+        assert_eq!(size_bucket(1), 32);
+        assert_eq!(size_bucket(32), 32);
+        assert_eq!(size_bucket(33), 64);
+    }
+
+    fn make_store(policy: PolicyType) -> LruCache<Vec<u8>, Vec<u8>> {
+        // with room for both pools, but they share the capacity
+        let store = LruCache::with_pools(CAPACITY, 2, policy);
         store
     }
 
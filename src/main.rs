@@ -3,12 +3,24 @@ extern crate nom;
 extern crate time;
 extern crate getopts;
 extern crate regex;
+extern crate libc;
 
 mod parser;
+mod binary;
+mod meta;
 mod store;
+mod shards;
 mod lru;
+mod policy;
+mod slabs;
+mod stats;
 mod server;
+mod epoll;
+mod udp;
 mod cmd;
+mod settings;
+mod snapshot;
+mod writelog;
 
 pub fn main() {
     cmd::main()
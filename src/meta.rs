@@ -0,0 +1,269 @@
+//! The meta commands (mg, ms, md, ma, mn, me). The parser turns the flag
+//! tokens after the key into `MetaFlags`, the store answers with a
+//! `MetaResponse`, and server.rs writes that back out
+
+use std::str::from_utf8;
+use std::str::FromStr;
+
+use store::{Ttl, Flags, CasUnique, IncrValue, StoredKey, ReturnedData};
+
+// like try!, but for Options
+macro_rules! try_opt {
+    ($e:expr) => (match $e { Some(x) => x, None => return None })
+}
+
+#[derive(Debug,Default,PartialEq,Eq)]
+pub struct MetaFlags<'a> {
+    // b: the key is base64 encoded
+    pub base64: bool,
+    // c, f, h, k, l, s, t, v: things the client wants back
+    pub return_cas: bool,
+    pub return_flags: bool,
+    pub return_hit: bool,
+    pub return_key: bool,
+    pub return_last_access: bool,
+    pub return_size: bool,
+    pub return_ttl: bool,
+    pub return_value: bool,
+    // O: an opaque token that we echo back
+    pub opaque: Option<&'a [u8]>,
+    // q: leave out the uninteresting responses
+    pub quiet: bool,
+    // u: don't bump the item in the LRU
+    pub no_bump: bool,
+    // C: only go ahead if the item has this cas unique
+    pub compare_cas: Option<CasUnique>,
+    // F: client flags to store
+    pub client_flags: Option<Flags>,
+    // T: ttl to store (or to update to, on a get)
+    pub ttl: Option<Ttl>,
+    // M: the mode for ms and ma
+    pub mode: Option<u8>,
+    // N: mg and ma create missing items, with this ttl
+    pub vivify: Option<Ttl>,
+    // R: mg hands out a win token if the item has less than this ttl left
+    pub recache: Option<Ttl>,
+    // I: md marks the item stale instead of deleting it
+    pub invalidate: bool,
+    // J: ...and this initial value
+    pub initial: Option<IncrValue>,
+    // D: how much ma changes the value by
+    pub delta: Option<IncrValue>,
+}
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum MetaCode {
+    Value, // VA
+    Stored, // HD
+    Miss, // EN
+    NotStored, // NS
+    Exists, // EX
+    NotFound, // NF
+}
+
+#[derive(Debug,PartialEq,Eq)]
+pub enum MetaReturn<'a> {
+    Base64,
+    Cas(CasUnique),
+    Flags(Flags),
+    Hit(bool),
+    Key(&'a [u8]),
+    LastAccess(Ttl),
+    Opaque(&'a [u8]),
+    Size(usize),
+    // -1 means it never expires
+    Ttl(i64),
+    // this client should recache the item
+    Win,
+    // the item has been invalidated
+    Stale,
+    // somebody else has already been told to recache it
+    AlreadyWon,
+}
+
+#[derive(Debug,PartialEq,Eq)]
+pub struct MetaResponse<'a> {
+    pub code: MetaCode,
+    pub returned: Vec<MetaReturn<'a>>,
+    pub data: Option<ReturnedData>,
+    // the client asked for quiet mode and this is a response it doesn't want
+    pub silent: bool,
+}
+
+impl MetaCode {
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match *self {
+            MetaCode::Value => b"VA",
+            MetaCode::Stored => b"HD",
+            MetaCode::Miss => b"EN",
+            MetaCode::NotStored => b"NS",
+            MetaCode::Exists => b"EX",
+            MetaCode::NotFound => b"NF",
+        }
+    }
+}
+
+impl<'a> MetaReturn<'a> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = Vec::new();
+        match *self {
+            MetaReturn::Base64 => ret.push(b'b'),
+            MetaReturn::Cas(unique) => {
+                ret.extend_from_slice(format!("c{}", unique).as_bytes())
+            }
+            MetaReturn::Flags(flags) => {
+                ret.extend_from_slice(format!("f{}", flags).as_bytes())
+            }
+            MetaReturn::Hit(hit) => {
+                ret.extend_from_slice(if hit { b"h1" } else { b"h0" })
+            }
+            MetaReturn::Key(key) => {
+                ret.push(b'k');
+                ret.extend_from_slice(key);
+            }
+            MetaReturn::LastAccess(secs) => {
+                ret.extend_from_slice(format!("l{}", secs).as_bytes())
+            }
+            MetaReturn::Opaque(opaque) => {
+                ret.push(b'O');
+                ret.extend_from_slice(opaque);
+            }
+            MetaReturn::Size(size) => {
+                ret.extend_from_slice(format!("s{}", size).as_bytes())
+            }
+            MetaReturn::Ttl(ttl) => {
+                ret.extend_from_slice(format!("t{}", ttl).as_bytes())
+            }
+            MetaReturn::Win => ret.push(b'W'),
+            MetaReturn::Stale => ret.push(b'X'),
+            MetaReturn::AlreadyWon => ret.push(b'Z'),
+        }
+        ret
+    }
+}
+
+fn number<T: FromStr>(token: &[u8]) -> Option<T> {
+    from_utf8(token).ok().and_then(|s| s.parse().ok())
+}
+
+pub fn parse_flags<'a>(tokens: Vec<&'a [u8]>) -> Option<MetaFlags<'a>> {
+    // each token is a single letter, optionally followed by an argument. We
+    // accept the union of every command's flags and let each command ignore
+    // the ones that don't mean anything to it
+    let mut flags = MetaFlags::default();
+    for token in tokens {
+        // the last token comes back empty if the buffer ends in a space
+        let (&flag, arg) = try_opt!(token.split_first());
+        match flag {
+            b'b' => flags.base64 = true,
+            b'c' => flags.return_cas = true,
+            b'f' => flags.return_flags = true,
+            b'h' => flags.return_hit = true,
+            b'k' => flags.return_key = true,
+            b'l' => flags.return_last_access = true,
+            b's' => flags.return_size = true,
+            b't' => flags.return_ttl = true,
+            b'v' => flags.return_value = true,
+            b'q' => flags.quiet = true,
+            b'u' => flags.no_bump = true,
+            b'I' => flags.invalidate = true,
+            b'O' => flags.opaque = Some(arg),
+            b'C' => flags.compare_cas = Some(try_opt!(number(arg))),
+            b'F' => flags.client_flags = Some(try_opt!(number(arg))),
+            b'T' => flags.ttl = Some(try_opt!(number(arg))),
+            b'N' => flags.vivify = Some(try_opt!(number(arg))),
+            b'R' => flags.recache = Some(try_opt!(number(arg))),
+            b'J' => flags.initial = Some(try_opt!(number(arg))),
+            b'D' => flags.delta = Some(try_opt!(number(arg))),
+            b'M' if arg.len() == 1 => flags.mode = Some(arg[0]),
+            _ => return None,
+        }
+    }
+    Some(flags)
+}
+
+fn base64_value(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+pub fn base64_decode(encoded: &[u8]) -> Option<Vec<u8>> {
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let unpadded = match encoded.iter().position(|&c| c == b'=') {
+        Some(pos) if encoded.len() - pos <= 2 &&
+                     encoded[pos..].iter().all(|&c| c == b'=') => pos,
+        Some(_) => return None,
+        None => encoded.len(),
+    };
+
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &c in &encoded[..unpadded] {
+        acc = (acc << 6) | try_opt!(base64_value(c)) as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+pub fn decode_key(key: &[u8], base64: bool) -> Option<StoredKey> {
+    if base64 {
+        base64_decode(key)
+    } else {
+        Some(key.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn flags() {
+        let tokens: Vec<&[u8]> = vec![b"v", b"k", b"T30", b"Oabc", b"MA",
+                                      b"C12"];
+        assert_eq!(parse_flags(tokens),
+                   Some(MetaFlags {
+                       return_value: true,
+                       return_key: true,
+                       ttl: Some(30),
+                       opaque: Some(b"abc"),
+                       mode: Some(b'A'),
+                       compare_cas: Some(12),
+                       ..MetaFlags::default()
+                   }));
+
+        let bad_number: Vec<&[u8]> = vec![b"Tsoon"];
+        assert_eq!(parse_flags(bad_number), None);
+        let unknown: Vec<&[u8]> = vec![b"v", b"z"];
+        assert_eq!(parse_flags(unknown), None);
+    }
+
+    #[test]
+    pub fn base64() {
+        let tests: Vec<(&[u8], Option<&[u8]>)> = vec![
+            (b"", Some(b"")),
+            (b"Zm9v", Some(b"foo")),
+            (b"Zm9vYg==", Some(b"foob")),
+            (b"Zm9vYmE=", Some(b"fooba")),
+            (b"Zm9vYmE", None),
+            (b"Zm=vYmE=", None),
+            (b"Zm9v!mE=", None),
+        ];
+        for (encoded, expected) in tests {
+            assert_eq!(base64_decode(encoded), expected.map(|e| e.to_vec()));
+        }
+    }
+}
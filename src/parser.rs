@@ -1,6 +1,7 @@
 /// The parser. Takes a stream of bytes and turns it into a series of parsed
 /// commands ready to be send to the store
 
+use std::cmp;
 use std::str::from_utf8;
 use std::str::FromStr;
 
@@ -13,6 +14,11 @@ use store::ServerCommand;
 use store::IncrementerType;
 use store::GetterType;
 use store::SetterType;
+use store::StatsType;
+use store::CrawlerCommand;
+use store::MAX_KEY;
+use meta;
+use meta::MetaFlags;
 
 #[derive(Debug,PartialEq,Eq)]
 pub struct CommandConfig<'a> {
@@ -23,11 +29,9 @@ pub struct CommandConfig<'a> {
 named!(key_parser<&[u8], &[u8]>, is_not!(" \t\r\n\0"));
 
 fn unwrap_noreply(tag: Option<&[u8]>) -> bool {
-    match tag {
-        Some(b"noreply") => false,
-        Some(_) => panic!(format!("can't unwrap noreply tag {:?}", tag)),
-        None => true,
-    }
+    // the parsers only ever give us noreply itself, but if anything else
+    // turns up they can still have their reply
+    !matches!(tag, Some(b"noreply"))
 }
 
 named!(u32_digit<u32>,
@@ -185,6 +189,42 @@ named!(cmd_get<&[u8], CommandConfig>,
     )
 );
 
+named!(parse_gat_name,
+    alt!(
+        tag!("gats") |
+        tag!("gat")
+    )
+);
+
+// gat <exptime> <key>*\r\n
+// gats <exptime> <key>*\r\n
+named!(cmd_gat<&[u8], CommandConfig<'_>>,
+    chain!(
+        gat_name: parse_gat_name ~
+        space ~
+        ttl: u32_digit ~
+        space ~
+        keys: separated_nonempty_list!(space, key_parser) ~
+        crlf,
+        || {
+            let getter = if gat_name == b"gats" {
+                GetterType::Gets
+            } else {
+                GetterType::Get
+            };
+
+            CommandConfig {
+                should_reply: true,
+                command: ServerCommand::GetAndTouch {
+                    getter,
+                    ttl,
+                    keys
+                }
+            }
+        }
+    )
+);
+
 // delete <key> [noreply]\r\n
 // TODO there's a rumour that this can take a time?
 named!(cmd_delete<&[u8], CommandConfig>,
@@ -311,16 +351,303 @@ named!(cmd_quit<&[u8], CommandConfig>,
     )
 );
 
-// flush_all\r\n
-named!(cmd_flushall<&[u8], CommandConfig>,
+// flush_all [delay] [noreply]\r\n
+named!(cmd_flushall<&[u8], CommandConfig<'_>>,
     chain!(
         tag!("flush_all") ~
+        delay: chain!(space ~ x: u32_digit, || {x})? ~
         noreply: chain!(space ~ x: tag!("noreply"), || {x})? ~
         crlf,
         || {
             CommandConfig {
                 should_reply: unwrap_noreply(noreply),
-                command: ServerCommand::FlushAll
+                command: ServerCommand::FlushAll {
+                    delay: delay.unwrap_or(0),
+                }
+            }
+        }
+    )
+);
+
+// snapshot\r\n
+named!(cmd_snapshot<&[u8], CommandConfig<'_>>,
+    chain!(
+        tag!("snapshot") ~
+        crlf,
+        || {
+            CommandConfig {
+                should_reply: true,
+                command: ServerCommand::Snapshot
+            }
+        }
+    )
+);
+
+fn automove_mode(mode: usize) -> Option<usize> {
+    if mode <= 2 {
+        Some(mode)
+    } else {
+        None
+    }
+}
+
+// slabs reassign <source class> <dest class>\r\n
+// slabs automove <0|1|2>\r\n
+named!(cmd_slabs<&[u8], CommandConfig<'_>>,
+    chain!(
+        tag!("slabs") ~
+        space ~
+        command: alt!(
+            chain!(
+                tag!("reassign") ~
+                space ~
+                src: usize_digit ~
+                space ~
+                dst: usize_digit,
+                || {
+                    ServerCommand::SlabsReassign {
+                        src,
+                        dst,
+                    }
+                }
+            ) |
+            chain!(
+                tag!("automove") ~
+                space ~
+                mode: map_opt!(usize_digit, automove_mode),
+                || { ServerCommand::SlabsAutomove(mode) }
+            )
+        ) ~
+        crlf,
+        || {
+            CommandConfig {
+                should_reply: true,
+                command
+            }
+        }
+    )
+);
+
+fn crawler_sleep(sleep: u32) -> Option<u32> {
+    // like memcached, no more than a second
+    if sleep <= 1000000 {
+        Some(sleep)
+    } else {
+        None
+    }
+}
+
+fn dump_class(class: &[u8]) -> Option<Option<usize>> {
+    // a class number, or all of them
+    if class == b"all" {
+        return Some(None);
+    }
+    from_utf8(class)
+        .ok()
+        .and_then(|class| FromStr::from_str(class).ok())
+        .map(Some)
+}
+
+// lru_crawler <enable|disable>\r\n
+// lru_crawler sleep <microseconds>\r\n
+// lru_crawler tocrawl <items>\r\n
+// lru_crawler metadump <all|class>\r\n
+named!(cmd_lru_crawler<&[u8], CommandConfig<'_>>,
+    chain!(
+        tag!("lru_crawler") ~
+        space ~
+        command: alt!(
+            chain!(
+                tag!("enable"),
+                || { ServerCommand::LruCrawler(CrawlerCommand::Enable) }
+            ) |
+            chain!(
+                tag!("disable"),
+                || { ServerCommand::LruCrawler(CrawlerCommand::Disable) }
+            ) |
+            chain!(
+                tag!("sleep") ~
+                space ~
+                sleep: map_opt!(u32_digit, crawler_sleep),
+                || { ServerCommand::LruCrawler(CrawlerCommand::Sleep(sleep)) }
+            ) |
+            chain!(
+                tag!("tocrawl") ~
+                space ~
+                tocrawl: u32_digit,
+                || {
+                    ServerCommand::LruCrawler(CrawlerCommand::ToCrawl(tocrawl))
+                }
+            ) |
+            chain!(
+                tag!("metadump") ~
+                space ~
+                class: map_opt!(is_not!(" \t\r\n"), dump_class),
+                || { ServerCommand::MetaDump { class } }
+            )
+        ) ~
+        crlf,
+        || {
+            CommandConfig {
+                should_reply: true,
+                command
+            }
+        }
+    )
+);
+
+pub fn map_stats_name(res: &[u8]) -> Option<StatsType> {
+    match res {
+        b"items" => Some(StatsType::Items),
+        b"slabs" => Some(StatsType::Slabs),
+        b"sizes" => Some(StatsType::Sizes),
+        b"settings" => Some(StatsType::Settings),
+        _ => None,
+    }
+}
+
+// stats [items|slabs|sizes|settings]\r\n
+named!(cmd_stats<&[u8], CommandConfig<'_>>,
+    chain!(
+        tag!("stats") ~
+        stats_type: chain!(
+            space ~
+            x: map_opt!(is_not!(" \t\r\n"), map_stats_name),
+            || {x}
+        )? ~
+        crlf,
+        || {
+            CommandConfig {
+                should_reply: true,
+                command: ServerCommand::Stats(
+                    stats_type.unwrap_or(StatsType::General))
+            }
+        }
+    )
+);
+
+// the flag tokens that follow the key in the meta commands
+named!(meta_flags<&[u8], MetaFlags<'_>>,
+    map_opt!(
+        many0!(chain!(space ~ token: is_not!(" \t\r\n"), || {token})),
+        meta::parse_flags
+    )
+);
+
+// mg <key> <flag>*\r\n
+named!(cmd_meta_get<&[u8], CommandConfig<'_>>,
+    chain!(
+        tag!("mg") ~
+        space ~
+        key: key_parser ~
+        flags: meta_flags ~
+        crlf,
+        || {
+            CommandConfig {
+                should_reply: true,
+                command: ServerCommand::MetaGet {
+                    key,
+                    flags,
+                }
+            }
+        }
+    )
+);
+
+// ms <key> <datalen> <flag>*\r\n<data>\r\n
+named!(cmd_meta_set<&[u8], CommandConfig<'_>>,
+    chain!(
+        tag!("ms") ~
+        space ~
+        key: key_parser ~
+        space ~
+        bytes: usize_digit ~
+        flags: meta_flags ~
+        crlf ~
+        payload: take!(bytes) ~
+        crlf,
+        || {
+            CommandConfig {
+                should_reply: true,
+                command: ServerCommand::MetaSet {
+                    key,
+                    data: payload,
+                    flags,
+                }
+            }
+        }
+    )
+);
+
+// md <key> <flag>*\r\n
+named!(cmd_meta_delete<&[u8], CommandConfig<'_>>,
+    chain!(
+        tag!("md") ~
+        space ~
+        key: key_parser ~
+        flags: meta_flags ~
+        crlf,
+        || {
+            CommandConfig {
+                should_reply: true,
+                command: ServerCommand::MetaDelete {
+                    key,
+                    flags,
+                }
+            }
+        }
+    )
+);
+
+// ma <key> <flag>*\r\n
+named!(cmd_meta_arithmetic<&[u8], CommandConfig<'_>>,
+    chain!(
+        tag!("ma") ~
+        space ~
+        key: key_parser ~
+        flags: meta_flags ~
+        crlf,
+        || {
+            CommandConfig {
+                should_reply: true,
+                command: ServerCommand::MetaArithmetic {
+                    key,
+                    flags,
+                }
+            }
+        }
+    )
+);
+
+// me <key> <flag>*\r\n
+named!(cmd_meta_debug<&[u8], CommandConfig<'_>>,
+    chain!(
+        tag!("me") ~
+        space ~
+        key: key_parser ~
+        flags: meta_flags ~
+        crlf,
+        || {
+            CommandConfig {
+                should_reply: true,
+                command: ServerCommand::MetaDebug {
+                    key,
+                    flags,
+                }
+            }
+        }
+    )
+);
+
+// mn\r\n
+named!(cmd_meta_noop<&[u8], CommandConfig<'_>>,
+    chain!(
+        tag!("mn") ~
+        crlf,
+        || {
+            CommandConfig {
+                should_reply: true,
+                command: ServerCommand::Noop
             }
         }
     )
@@ -340,12 +667,198 @@ named!(cmd_bad<&[u8], CommandConfig>,
     )
 );
 
+// the longest command line we'll wait for the end of. Multi-gets can name as
+// many keys as they like, so theirs can go up to the item size limit instead
+pub const MAX_LINE: usize = 2048;
+
+// a command that we won't buffer the rest of
+#[derive(Debug,PartialEq,Eq)]
+pub enum Oversized {
+    // a command line that's gone on too long without ending. There's no
+    // telling where the next command starts after it
+    Line,
+    // a storage command with a key longer than store::MAX_KEY. `skip` is how
+    // much of the stream it takes up, including the payload that we may not
+    // have seen yet
+    Key { skip: usize, should_reply: bool },
+    // a storage command whose payload is bigger than the item size limit
+    Data { skip: usize, should_reply: bool },
+}
+
+// the part of a storage command before its payload: the key, if it's one
+// that we can check, the payload's length, and whether it wants a reply
+named!(storage_header<&[u8], (Option<&[u8]>, usize, bool)>,
+    chain!(
+        alt!(parse_setter_name | tag!("cas")) ~
+        space ~
+        key: key_parser ~
+        space ~
+        u32_digit ~
+        space ~
+        u32_digit ~
+        space ~
+        bytes: usize_digit ~
+        rest: is_not!("\r\n")? ~
+        crlf,
+        || {
+            let noreply = rest.is_some_and(|rest| rest.ends_with(b"noreply"));
+            (Some(key), bytes, !noreply)
+        }
+    )
+);
+
+// ms keys might be base64, so the store checks those once they're decoded
+named!(meta_set_header<&[u8], (Option<&[u8]>, usize, bool)>,
+    chain!(
+        tag!("ms") ~
+        space ~
+        key_parser ~
+        space ~
+        bytes: usize_digit ~
+        is_not!("\r\n")? ~
+        crlf,
+        || { (None, bytes, true) }
+    )
+);
+
+// the verbs of the commands that storage_header and meta_set_header are for
+named!(storage_verb,
+    chain!(
+        verb: alt!(parse_setter_name | tag!("cas") | tag!("ms")) ~
+        space,
+        || { verb }
+    )
+);
+
+fn line_length(input: &[u8]) -> Option<usize> {
+    // how far it is to the end of the first line, including the \r\n
+    input.windows(2).position(|pair| pair == b"\r\n").map(|end| end + 2)
+}
+
+fn parse_storage_header(line: &[u8]) -> Option<(Option<&[u8]>, usize, bool)> {
+    match storage_header(line) {
+        IResult::Done(_, header) => Some(header),
+        _ => {
+            match meta_set_header(line) {
+                IResult::Done(_, header) => Some(header),
+                _ => None,
+            }
+        }
+    }
+}
+
+pub fn oversized(input: &[u8], item_size_max: usize) -> Option<Oversized> {
+    // whether the command at the start of `input` breaks the limits on what
+    // we'll hold on to for a client, which we can tell from its first line
+    let line_limit = if input.starts_with(b"get") || input.starts_with(b"gat") {
+        item_size_max
+    } else {
+        MAX_LINE
+    };
+    let searched = &input[..cmp::min(input.len(), line_limit + 2)];
+    let line = match line_length(searched) {
+        Some(length) => &input[..length],
+        None if input.len() > line_limit => return Some(Oversized::Line),
+        None => return None,
+    };
+    let (key, bytes, should_reply) = parse_storage_header(line)?;
+    // the payload and the \r\n after it
+    let skip = line.len().saturating_add(bytes).saturating_add(2);
+    if key.is_some_and(|key| key.len() > MAX_KEY) {
+        Some(Oversized::Key {
+            skip,
+            should_reply,
+        })
+    } else if bytes > item_size_max {
+        Some(Oversized::Data {
+            skip,
+            should_reply,
+        })
+    } else {
+        None
+    }
+}
+
+// how to get back in step after a command that parse_command couldn't make
+// sense of
+#[derive(Debug,PartialEq,Eq)]
+pub enum Malformed {
+    // a command line we couldn't parse. `skip` takes us past it, and past
+    // its payload if it's a storage command that told us how long that is
+    Line { skip: usize, should_reply: bool },
+    // a storage command whose payload wasn't followed by a \r\n
+    Chunk { skip: usize, should_reply: bool },
+}
+
+pub fn malformed(input: &[u8]) -> Option<Malformed> {
+    // what's wrong with a storage command at the start of `input` that
+    // parse_command called Bad. Anything else Bad is just an unknown command
+    let line = match line_length(input) {
+        Some(length) => &input[..length],
+        None => return None,
+    };
+    match parse_storage_header(line) {
+        Some((_, bytes, should_reply)) => {
+            let payload = &input[line.len()..];
+            let skip = line.len().saturating_add(bytes).saturating_add(2);
+            if payload.len() >= bytes.saturating_add(2) &&
+               &payload[bytes..bytes + 2] != b"\r\n" {
+                // the client's payload was longer than they said, so the
+                // next command most likely starts after the next \r\n
+                let skip = match line_length(&payload[bytes..]) {
+                    Some(length) => line.len() + bytes + length,
+                    None => skip,
+                };
+                Some(Malformed::Chunk {
+                    skip,
+                    should_reply,
+                })
+            } else {
+                // it was something else in the command line that was wrong,
+                // but the payload's still coming
+                Some(Malformed::Line {
+                    skip,
+                    should_reply,
+                })
+            }
+        }
+        None => {
+            match storage_verb(line) {
+                IResult::Done(..) => {
+                    Some(Malformed::Line {
+                        skip: line.len(),
+                        should_reply: true,
+                    })
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+pub fn resync(input: &[u8]) -> Option<Malformed> {
+    // how to recover from an Error from parse_command: like malformed, or
+    // for anything that isn't a storage command, by carrying on after the
+    // next \r\n. None until that's arrived
+    malformed(input).or_else(|| {
+        line_length(input).map(|length| {
+            Malformed::Line {
+                skip: length,
+                should_reply: true,
+            }
+        })
+    })
+}
+
 named!(pub parse_command<&[u8], CommandConfig>,
     alt!(
         // these short ones need to go first to work around a bug in nom where
         // it thinks it needs more data than it does
-        cmd_quit | cmd_version | cmd_flushall | cmd_verbosity
-        | cmd_set | cmd_cas | cmd_get | cmd_delete | cmd_incr | cmd_touch
+        cmd_quit | cmd_version | cmd_flushall | cmd_verbosity | cmd_stats
+        | cmd_slabs | cmd_lru_crawler | cmd_snapshot
+        | cmd_meta_noop | cmd_meta_get | cmd_meta_set | cmd_meta_delete
+        | cmd_meta_arithmetic | cmd_meta_debug
+        | cmd_set | cmd_cas | cmd_get | cmd_gat | cmd_delete | cmd_incr | cmd_touch
         | cmd_bad
     )
 );
@@ -380,6 +893,9 @@ mod tests {
     use store::IncrementerType;
     use store::GetterType;
     use store::SetterType;
+    use store::StatsType;
+    use store::CrawlerCommand;
+    use meta::MetaFlags;
 
     #[test]
     pub fn commands() {
@@ -419,6 +935,13 @@ mod tests {
             ("gets foo1 foo2\r\n",
              IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Getter { getter: GetterType::Gets, keys: vec![b"foo1", b"foo2"] } })),
 
+            ("gat 10 foo\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::GetAndTouch { getter: GetterType::Get, ttl: 10, keys: vec![b"foo"] } })),
+            ("gats 10 foo1 foo2\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::GetAndTouch { getter: GetterType::Gets, ttl: 10, keys: vec![b"foo1", b"foo2"] } })),
+            ("gat foo\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Bad(b"gat foo") })),
+
             ("delete foo\r\n",
              IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Delete { key: b"foo" } })),
             ("delete foo noreply\r\n",
@@ -439,18 +962,88 @@ mod tests {
              IResult::Done(b"", CommandConfig { should_reply: false, command: ServerCommand::Touch { key: b"foo", ttl: 5 } })),
 
             ("flush_all\r\n",
-             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::FlushAll })),
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::FlushAll { delay: 0 } })),
             ("flush_all noreply\r\n",
-             IResult::Done(b"", CommandConfig { should_reply: false, command: ServerCommand::FlushAll })),
+             IResult::Done(b"", CommandConfig { should_reply: false, command: ServerCommand::FlushAll { delay: 0 } })),
+            ("flush_all 30\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::FlushAll { delay: 30 } })),
+            ("flush_all 30 noreply\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: false, command: ServerCommand::FlushAll { delay: 30 } })),
             ("version\r\n",
              IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Version })),
             ("quit\r\n",
              IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Quit })),
             ("verbosity 10\r\n",
              IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Verbosity })),
+            ("stats\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Stats(StatsType::General) })),
+            ("stats items\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Stats(StatsType::Items) })),
+            ("stats slabs\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Stats(StatsType::Slabs) })),
+            ("stats sizes\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Stats(StatsType::Sizes) })),
+            ("stats settings\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Stats(StatsType::Settings) })),
+            ("stats bogus\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Bad(b"stats bogus") })),
+            ("slabs reassign 3 12\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::SlabsReassign { src: 3, dst: 12 } })),
+            ("slabs automove 2\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::SlabsAutomove(2) })),
+            ("slabs automove 3\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Bad(b"slabs automove 3") })),
+            ("lru_crawler enable\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::LruCrawler(CrawlerCommand::Enable) })),
+            ("lru_crawler disable\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::LruCrawler(CrawlerCommand::Disable) })),
+            ("lru_crawler sleep 250\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::LruCrawler(CrawlerCommand::Sleep(250)) })),
+            ("lru_crawler sleep 1000001\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Bad(b"lru_crawler sleep 1000001") })),
+            ("lru_crawler tocrawl 500\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::LruCrawler(CrawlerCommand::ToCrawl(500)) })),
+            ("lru_crawler metadump all\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::MetaDump { class: None } })),
+            ("lru_crawler metadump 12\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::MetaDump { class: Some(12) } })),
+            ("lru_crawler metadump some\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Bad(b"lru_crawler metadump some") })),
+            ("snapshot\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Snapshot })),
+            ("lru_crawler frobnicate\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Bad(b"lru_crawler frobnicate") })),
+            ("slabs reassign 3\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Bad(b"slabs reassign 3") })),
             ("verbosity 10 noreply\r\n",
              IResult::Done(b"", CommandConfig { should_reply: false, command: ServerCommand::Verbosity })),
 
+            ("mn\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Noop })),
+            ("mg foo\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::MetaGet { key: b"foo", flags: MetaFlags::default() } })),
+            ("mg foo v t Oxyz q\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::MetaGet { key: b"foo", flags: MetaFlags { return_value: true, return_ttl: true, opaque: Some(b"xyz"), quiet: true, ..MetaFlags::default() } } })),
+            ("mg foo v Tsoon\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Bad(b"mg foo v Tsoon") })),
+            ("ms foo 5 T34 F12 MA\r\ndata!\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::MetaSet { key: b"foo", data: b"data!", flags: MetaFlags { ttl: Some(34), client_flags: Some(12), mode: Some(b'A'), ..MetaFlags::default() } } })),
+            ("ms foo 5\r\ndata!\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::MetaSet { key: b"foo", data: b"data!", flags: MetaFlags::default() } })),
+            ("md Zm9v b C99\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::MetaDelete { key: b"Zm9v", flags: MetaFlags { base64: true, compare_cas: Some(99), ..MetaFlags::default() } } })),
+            ("ma foo N0 J10 D2 MD v\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::MetaArithmetic { key: b"foo", flags: MetaFlags { vivify: Some(0), initial: Some(10), delta: Some(2), mode: Some(b'D'), return_value: true, ..MetaFlags::default() } } })),
+            ("md foo I T30 q\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::MetaDelete { key: b"foo", flags: MetaFlags { invalidate: true, ttl: Some(30), quiet: true, ..MetaFlags::default() } } })),
+            ("mg foo N30 R10 v c\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::MetaGet { key: b"foo", flags: MetaFlags { vivify: Some(30), recache: Some(10), return_value: true, return_cas: true, ..MetaFlags::default() } } })),
+            // a command cut off after a space isn't finished yet
+            ("mg foo ", IResult::Incomplete(Needed::Size(9))),
+            ("mg foo v ", IResult::Incomplete(Needed::Size(11))),
+            ("me foo\r\n",
+             IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::MetaDebug { key: b"foo", flags: MetaFlags::default() } })),
+
             ("foo bar\r\n",
              IResult::Done(b"", CommandConfig { should_reply: true, command: ServerCommand::Bad(b"foo bar") })),
             ("version foo bar\r\n",
@@ -475,6 +1068,80 @@ mod tests {
         }
     }
 
+    #[test]
+    pub fn limits() {
+        let long_key = "k".repeat(MAX_KEY + 1);
+        let long_line = format!("get {}", "k ".repeat(1500));
+        let tests = vec![
+            ("set foo 0 0 5\r\nda".to_string(), None),
+            ("set foo 0 0 5000\r\n".to_string(),
+             Some(Oversized::Data { skip: 18 + 5002, should_reply: true })),
+            ("cas foo 0 0 5000 12 noreply\r\n".to_string(),
+             Some(Oversized::Data { skip: 29 + 5002, should_reply: false })),
+            ("ms foo 5000 T0\r\n".to_string(),
+             Some(Oversized::Data { skip: 16 + 5002, should_reply: true })),
+            (format!("add {} 0 0 1\r\nx\r\n", long_key),
+             Some(Oversized::Key { skip: 268 + 3, should_reply: true })),
+            // only storage commands have to be stopped before the store
+            (format!("get {}\r\n", long_key), None),
+            // we haven't seen the end of the line yet, but it could be fine
+            ("set foo 0 0 5000".to_string(), None),
+            ("x".repeat(MAX_LINE + 1), Some(Oversized::Line)),
+            // multi-gets are allowed more room
+            (long_line.clone(), None),
+            (long_line.repeat(3), Some(Oversized::Line)),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(oversized(input.as_bytes(), 4096), expected);
+        }
+    }
+
+    #[test]
+    pub fn recovery() {
+        let tests: Vec<(&str, Option<Malformed>)> = vec![
+            // a payload that's longer than they said
+            ("set foo 0 0 3\r\nabcd\r\n",
+             Some(Malformed::Chunk { skip: 15 + 6, should_reply: true })),
+            ("append foo 0 0 3 noreply\r\nabcd\r\n",
+             Some(Malformed::Chunk { skip: 26 + 6, should_reply: false })),
+            // a command line that's wrong, but still tells us how long the
+            // payload is
+            ("cas foo 0 0 3\r\nabc\r\n",
+             Some(Malformed::Line { skip: 15 + 5, should_reply: true })),
+            ("set foo 0 0 3 bogus\r\nabc\r\n",
+             Some(Malformed::Line { skip: 21 + 5, should_reply: true })),
+            ("ms foo 3 Tsoon\r\nabc\r\n",
+             Some(Malformed::Line { skip: 16 + 5, should_reply: true })),
+            // one that doesn't
+            ("set foo bar 0 3\r\nabc\r\n",
+             Some(Malformed::Line { skip: 17, should_reply: true })),
+            // unknown commands are just that
+            ("foo bar\r\n", None),
+            ("settle foo 0 0 3\r\n", None),
+        ];
+
+        for (input, expected) in tests {
+            match parse_command(input.as_bytes()) {
+                IResult::Done(_, CommandConfig {
+                    command: ServerCommand::Bad(_), ..
+                }) => {}
+                parsed => panic!("{:?} parsed as {:?}", input, parsed),
+            }
+            assert_eq!(malformed(input.as_bytes()), expected);
+        }
+
+        // a lone \n is something nom can't make sense of at all, and we
+        // pick up again after the next \r\n
+        let input = b"\nversion\r\nversion\r\n";
+        assert!(parse_command(input).is_err());
+        assert_eq!(resync(input),
+                   Some(Malformed::Line { skip: 10, should_reply: true }));
+        assert_eq!(resync(b"\nvers"), None);
+
+        assert!(unwrap_noreply(Some(b"bogus")));
+    }
+
     #[test]
     pub fn parse_sizes() {
         let tests = vec![
@@ -0,0 +1,995 @@
+//! Eviction policies. Each of the cache's pools has one, which it tells about
+//! everything that's stored, used, and removed, and asks what should go next
+//! when it has to make room
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::Arc;
+
+use lru::Timestamp;
+
+pub trait EvictionPolicy<K>: Debug + Send {
+    // `key` has just been stored, to expire at `expires`
+    fn insert(&mut self,
+              key: &Arc<K>,
+              expires: Option<Timestamp>,
+              now: Timestamp);
+    // `key` is being used again. It was last used at `used`
+    fn access(&mut self, key: &Arc<K>, used: Timestamp, now: Timestamp);
+    // `key` has gone away. `evicted` means that it was thrown away to make
+    // room, rather than deleted or replaced
+    fn remove(&mut self, key: &Arc<K>, used: Timestamp, evicted: bool);
+    // what should be thrown away next, if we have anything
+    fn victim(&self) -> Option<Arc<K>>;
+    // whether `key`, which isn't stored yet, deserves to take the place of
+    // `victim`. Only asked when storing it means throwing away something
+    // that's still alive, and if the answer's no then `key` isn't stored
+    fn admit(&mut self, _key: &K, _victim: &Arc<K>) -> bool {
+        true
+    }
+    // called every so often from a background thread, for policies that have
+    // housekeeping they'd rather not do while somebody's waiting
+    fn maintain(&mut self, _now: Timestamp) {}
+    // how many keys are in each of the policy's segments, if it has any, for
+    // the operator to look at
+    fn segments(&self) -> Vec<(&'static str, usize)> {
+        Vec::new()
+    }
+    fn clear(&mut self);
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum PolicyType {
+    Lru,
+    Arc,
+    TinyLfu,
+    Segmented(SegmentLimits),
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct SegmentLimits {
+    // how much of a pool HOT and WARM may each hold, in percent. COLD gets
+    // whatever's left
+    pub hot_pct: usize,
+    pub warm_pct: usize,
+    // items that expire sooner than this many seconds after they're stored
+    // go in TEMP. 0 turns it off
+    pub temporary_ttl: Timestamp,
+}
+
+impl Default for SegmentLimits {
+    fn default() -> SegmentLimits {
+        // memcached's defaults
+        SegmentLimits {
+            hot_pct: 20,
+            warm_pct: 40,
+            temporary_ttl: 61,
+        }
+    }
+}
+
+impl PolicyType {
+    pub fn from_name(name: &str) -> Option<PolicyType> {
+        match name {
+            "lru" => Some(PolicyType::Lru),
+            "arc" => Some(PolicyType::Arc),
+            "tinylfu" => Some(PolicyType::TinyLfu),
+            "segmented" => {
+                Some(PolicyType::Segmented(SegmentLimits::default()))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PolicyType::Lru => "lru",
+            PolicyType::Arc => "arc",
+            PolicyType::TinyLfu => "tinylfu",
+            PolicyType::Segmented(_) => "segmented",
+        }
+    }
+
+    pub fn make<K>(&self) -> Box<dyn EvictionPolicy<K>>
+        where K: Ord + Hash + Debug + Send + Sync + 'static
+    {
+        match *self {
+            PolicyType::Lru => Box::new(LruPolicy::new()),
+            PolicyType::Arc => Box::new(ArcPolicy::new()),
+            PolicyType::TinyLfu => Box::new(TinyLfuPolicy::new()),
+            PolicyType::Segmented(limits) => {
+                Box::new(SegmentedPolicy::new(limits))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LruPolicy<K> {
+    // ordered by when they were last used. The key breaks ties so that
+    // everything has its own place
+    lru: BTreeSet<(Timestamp, Arc<K>)>,
+}
+
+impl<K: Ord> LruPolicy<K> {
+    pub fn new() -> LruPolicy<K> {
+        LruPolicy { lru: BTreeSet::new() }
+    }
+}
+
+impl<K: Ord + Debug + Send + Sync> EvictionPolicy<K> for LruPolicy<K> {
+    fn insert(&mut self,
+              key: &Arc<K>,
+              _expires: Option<Timestamp>,
+              now: Timestamp) {
+        self.lru.insert((now, key.clone()));
+    }
+
+    fn access(&mut self, key: &Arc<K>, used: Timestamp, now: Timestamp) {
+        // only move it if its place would change
+        if used != now {
+            self.lru.remove(&(used, key.clone()));
+            self.lru.insert((now, key.clone()));
+        }
+    }
+
+    fn remove(&mut self, key: &Arc<K>, used: Timestamp, _evicted: bool) {
+        self.lru.remove(&(used, key.clone()));
+    }
+
+    fn victim(&self) -> Option<Arc<K>> {
+        self.lru.iter().next().map(|(_, key)| key.clone())
+    }
+
+    fn clear(&mut self) {
+        self.lru.clear();
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum List {
+    // stored entries that have only been used once
+    Recent,
+    // stored entries that have been used since they were stored
+    Frequent,
+    // keys that we've thrown away from the other two, so that we can tell
+    // when we did that too soon
+    RecentGhost,
+    FrequentGhost,
+}
+
+/// Adaptive Replacement Cache (Megiddo and Modha). Entries that have only been
+/// used once are kept apart from those that have been used again, so that a
+/// scan through lots of keys can't flush out the ones that are used all the
+/// time. How much of the pool goes to each adapts to how often we turn out to
+/// have thrown something away from one of them too soon
+///
+/// The paper has a fixed capacity in pages, but our pools grow and shrink
+/// with the slab allocator, so how many entries we're holding stands in for
+/// it
+#[derive(Debug)]
+pub struct ArcPolicy<K> {
+    // which list each key is in, and its place in that list
+    places: HashMap<Arc<K>, (List, u64)>,
+    // each ordered from least to most recently used
+    recent: BTreeMap<u64, Arc<K>>,
+    frequent: BTreeMap<u64, Arc<K>>,
+    recent_ghosts: BTreeMap<u64, Arc<K>>,
+    frequent_ghosts: BTreeMap<u64, Arc<K>>,
+    // the most recently used end of every list
+    counter: u64,
+    // how many of the stored entries we'd like to be recent ones
+    target: usize,
+}
+
+impl<K: Hash + Eq> ArcPolicy<K> {
+    pub fn new() -> ArcPolicy<K> {
+        ArcPolicy {
+            places: HashMap::new(),
+            recent: BTreeMap::new(),
+            frequent: BTreeMap::new(),
+            recent_ghosts: BTreeMap::new(),
+            frequent_ghosts: BTreeMap::new(),
+            counter: 0,
+            target: 0,
+        }
+    }
+
+    fn list(&mut self, list: List) -> &mut BTreeMap<u64, Arc<K>> {
+        match list {
+            List::Recent => &mut self.recent,
+            List::Frequent => &mut self.frequent,
+            List::RecentGhost => &mut self.recent_ghosts,
+            List::FrequentGhost => &mut self.frequent_ghosts,
+        }
+    }
+
+    fn stored(&self) -> usize {
+        self.recent.len() + self.frequent.len()
+    }
+
+    fn push(&mut self, list: List, key: &Arc<K>) {
+        // onto the most recently used end
+        self.counter += 1;
+        let place = self.counter;
+        self.list(list).insert(place, key.clone());
+        self.places.insert(key.clone(), (list, place));
+    }
+
+    fn take(&mut self, key: &Arc<K>) -> Option<List> {
+        // out of whichever list it's in, if any
+        let (list, place) = self.places.remove(key)?;
+        self.list(list).remove(&place);
+        Some(list)
+    }
+
+    fn forget_oldest(&mut self, list: List) {
+        let oldest = match self.list(list).iter().next() {
+            Some((&place, _)) => place,
+            None => return,
+        };
+        let key = self.list(list).remove(&oldest).unwrap();
+        self.places.remove(&key);
+    }
+
+    fn trim_ghosts(&mut self) {
+        // the paper keeps the recent entries and their ghosts within the
+        // capacity, and all of the ghosts within it too
+        let capacity = self.stored();
+        while self.recent.len() + self.recent_ghosts.len() > capacity &&
+              !self.recent_ghosts.is_empty() {
+            self.forget_oldest(List::RecentGhost);
+        }
+        while self.recent_ghosts.len() + self.frequent_ghosts.len() >
+              capacity && !self.frequent_ghosts.is_empty() {
+            self.forget_oldest(List::FrequentGhost);
+        }
+    }
+}
+
+impl<K> EvictionPolicy<K> for ArcPolicy<K>
+    where K: Hash + Eq + Debug + Send + Sync
+{
+    fn insert(&mut self,
+              key: &Arc<K>,
+              _expires: Option<Timestamp>,
+              _now: Timestamp) {
+        match self.take(key) {
+            Some(List::RecentGhost) => {
+                // we threw this away too soon, so give the recent entries
+                // more room. The +1s count the ghost we just took
+                let step = cmp::max(self.frequent_ghosts.len() /
+                                    (self.recent_ghosts.len() + 1),
+                                    1);
+                self.target += step;
+                self.push(List::Frequent, key);
+            }
+            Some(List::FrequentGhost) => {
+                let step = cmp::max(self.recent_ghosts.len() /
+                                    (self.frequent_ghosts.len() + 1),
+                                    1);
+                self.target = self.target.saturating_sub(step);
+                self.push(List::Frequent, key);
+            }
+            _ => self.push(List::Recent, key),
+        }
+        self.target = cmp::min(self.target, self.stored());
+        self.trim_ghosts();
+    }
+
+    fn access(&mut self, key: &Arc<K>, _used: Timestamp, _now: Timestamp) {
+        // anything used a second time is a frequent one from now on
+        if self.take(key).is_some() {
+            self.push(List::Frequent, key);
+        }
+    }
+
+    fn remove(&mut self, key: &Arc<K>, _used: Timestamp, evicted: bool) {
+        // only remember the ones we chose to throw away. If it was deleted
+        // there's nothing to learn if it comes back
+        match self.take(key) {
+            Some(List::Recent) if evicted => {
+                self.push(List::RecentGhost, key)
+            }
+            Some(List::Frequent) if evicted => {
+                self.push(List::FrequentGhost, key)
+            }
+            _ => (),
+        }
+        self.trim_ghosts();
+    }
+
+    fn victim(&self) -> Option<Arc<K>> {
+        // the least recently used recent entry while there are more of those
+        // than we want, otherwise the least recently used frequent one
+        let from_recent = !self.recent.is_empty() &&
+                          (self.recent.len() > self.target ||
+                           self.frequent.is_empty());
+        let list = if from_recent {
+            &self.recent
+        } else {
+            &self.frequent
+        };
+        list.values().next().cloned()
+    }
+
+    fn clear(&mut self) {
+        self.places.clear();
+        self.recent.clear();
+        self.frequent.clear();
+        self.recent_ghosts.clear();
+        self.frequent_ghosts.clear();
+        self.target = 0;
+    }
+}
+
+// how many counters each row of a FrequencySketch starts with. It grows to
+// have at least as many as there are entries
+const SKETCH_MIN_WIDTH: usize = 64;
+// each key has a counter in every row, and its estimate is the smallest
+const SKETCH_ROWS: usize = 4;
+// counters are four bits in the paper, so that's as high as they go here too
+const SKETCH_MAX_COUNT: u8 = 15;
+// how many increments per counter in a row before everything is halved
+const SKETCH_SAMPLE_FACTOR: usize = 10;
+
+/// A count-min sketch of how often keys have been used, recently. Every so
+/// often all of the counts are halved, so that things that used to be
+/// popular don't stay that way forever
+#[derive(Debug)]
+struct FrequencySketch {
+    // SKETCH_ROWS rows of `width` counters each
+    counters: Vec<u8>,
+    width: usize,
+    // increments since we last halved everything, and how many we allow
+    additions: usize,
+    sample: usize,
+}
+
+impl FrequencySketch {
+    fn new() -> FrequencySketch {
+        FrequencySketch::with_width(SKETCH_MIN_WIDTH)
+    }
+
+    fn with_width(width: usize) -> FrequencySketch {
+        // `width` has to be a power of two so that we can mask hashes with it
+        FrequencySketch {
+            counters: vec![0; SKETCH_ROWS * width],
+            width,
+            additions: 0,
+            sample: SKETCH_SAMPLE_FACTOR * width,
+        }
+    }
+
+    fn ensure_capacity(&mut self, entries: usize) {
+        // with fewer counters than entries the estimates would be mostly
+        // collisions. Like Caffeine we start over when we grow
+        if entries > self.width {
+            *self = FrequencySketch::with_width(entries.next_power_of_two());
+        }
+    }
+
+    fn indexes<K: Hash>(&self, key: &K) -> [usize; SKETCH_ROWS] {
+        // one hash split in two and recombined gives each row its own
+        // (Kirsch and Mitzenmacher)
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (low, high) = (hash & 0xffffffff, (hash >> 32) | 1);
+        let mut indexes = [0; SKETCH_ROWS];
+        for (row, index) in indexes.iter_mut().enumerate() {
+            let column = low.wrapping_add(row as u64 * high) as usize &
+                         (self.width - 1);
+            *index = row * self.width + column;
+        }
+        indexes
+    }
+
+    fn increment<K: Hash>(&mut self, key: &K) {
+        for index in self.indexes(key).iter() {
+            let counter = &mut self.counters[*index];
+            if *counter < SKETCH_MAX_COUNT {
+                *counter += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= self.sample {
+            self.age();
+        }
+    }
+
+    fn estimate<K: Hash>(&self, key: &K) -> u8 {
+        self.indexes(key)
+            .iter()
+            .map(|index| self.counters[*index])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn age(&mut self) {
+        for counter in &mut self.counters {
+            *counter /= 2;
+        }
+        self.additions /= 2;
+    }
+
+    fn clear(&mut self) {
+        *self = FrequencySketch::with_width(self.width);
+    }
+}
+
+// how much of a pool's entries go in the window and, of the rest, how much
+// is protected, in percent
+const WINDOW_PERCENT: usize = 1;
+const PROTECTED_PERCENT: usize = 80;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Segment {
+    // where everything starts out, in plain LRU order
+    Window,
+    // the main region. Things that come out of the window go on probation,
+    // and are protected once they've been used again
+    Probation,
+    Protected,
+}
+
+/// Window TinyLFU (Einziger, Friedman and Manes). New entries go into a small
+/// LRU window, and only get into the main region if the frequency sketch
+/// thinks they're used more often than what they'd push out of it. Entries
+/// that are only ever used once don't get to flush out the ones that are used
+/// all the time. The main region is a segmented LRU, so that of the entries
+/// that made it in, the ones that have been used again are kept longest
+///
+/// As with ARC, how many entries we're holding stands in for the capacity
+#[derive(Debug)]
+pub struct TinyLfuPolicy<K> {
+    // which segment each key is in, and its place in that segment
+    places: HashMap<Arc<K>, (Segment, u64)>,
+    // each ordered from least to most recently used
+    window: BTreeMap<u64, Arc<K>>,
+    probation: BTreeMap<u64, Arc<K>>,
+    protected: BTreeMap<u64, Arc<K>>,
+    // the most recently used end of every segment
+    counter: u64,
+    sketch: FrequencySketch,
+}
+
+impl<K: Hash + Eq> TinyLfuPolicy<K> {
+    pub fn new() -> TinyLfuPolicy<K> {
+        TinyLfuPolicy {
+            places: HashMap::new(),
+            window: BTreeMap::new(),
+            probation: BTreeMap::new(),
+            protected: BTreeMap::new(),
+            counter: 0,
+            sketch: FrequencySketch::new(),
+        }
+    }
+
+    fn segment(&mut self, segment: Segment) -> &mut BTreeMap<u64, Arc<K>> {
+        match segment {
+            Segment::Window => &mut self.window,
+            Segment::Probation => &mut self.probation,
+            Segment::Protected => &mut self.protected,
+        }
+    }
+
+    fn stored(&self) -> usize {
+        self.window.len() + self.probation.len() + self.protected.len()
+    }
+
+    fn window_target(&self) -> usize {
+        cmp::max(self.stored() * WINDOW_PERCENT / 100, 1)
+    }
+
+    fn protected_target(&self) -> usize {
+        let main = self.probation.len() + self.protected.len();
+        cmp::max(main * PROTECTED_PERCENT / 100, 1)
+    }
+
+    fn push(&mut self, segment: Segment, key: &Arc<K>) {
+        // onto the most recently used end
+        self.counter += 1;
+        let place = self.counter;
+        self.segment(segment).insert(place, key.clone());
+        self.places.insert(key.clone(), (segment, place));
+    }
+
+    fn take(&mut self, key: &Arc<K>) -> Option<Segment> {
+        // out of whichever segment it's in, if any
+        let (segment, place) = self.places.remove(key)?;
+        self.segment(segment).remove(&place);
+        Some(segment)
+    }
+
+    fn move_oldest(&mut self, from: Segment, to: Segment) {
+        let oldest = match self.segment(from).values().next() {
+            Some(key) => key.clone(),
+            None => return,
+        };
+        self.take(&oldest);
+        self.push(to, &oldest);
+    }
+}
+
+impl<K> EvictionPolicy<K> for TinyLfuPolicy<K>
+    where K: Hash + Eq + Debug + Send + Sync
+{
+    fn insert(&mut self,
+              key: &Arc<K>,
+              _expires: Option<Timestamp>,
+              _now: Timestamp) {
+        self.take(key);
+        self.push(Segment::Window, key);
+        let stored = self.stored();
+        self.sketch.ensure_capacity(stored);
+        self.sketch.increment(key);
+    }
+
+    fn access(&mut self, key: &Arc<K>, _used: Timestamp, _now: Timestamp) {
+        self.sketch.increment(key);
+        match self.take(key) {
+            Some(Segment::Window) => self.push(Segment::Window, key),
+            Some(_) => {
+                // used again, so it's protected now. That may bump the
+                // least recently used protected one back onto probation
+                self.push(Segment::Protected, key);
+                while self.protected.len() > self.protected_target() {
+                    self.move_oldest(Segment::Protected, Segment::Probation);
+                }
+            }
+            None => (),
+        }
+    }
+
+    fn remove(&mut self, key: &Arc<K>, _used: Timestamp, evicted: bool) {
+        self.take(key);
+        if evicted {
+            // whatever's left over in the window has either just won its
+            // place in the main region, or there's room for it there anyway
+            while self.window.len() > self.window_target() {
+                self.move_oldest(Segment::Window, Segment::Probation);
+            }
+        }
+    }
+
+    fn victim(&self) -> Option<Arc<K>> {
+        // once the window's grown past its share, its least recently used
+        // entry has to compete with the main region's for a place there, and
+        // whichever's used less often loses
+        let candidate = self.window.values().next();
+        let main_victim = self.probation
+            .values()
+            .next()
+            .or_else(|| self.protected.values().next());
+        let victim = match (candidate, main_victim) {
+            (Some(candidate), Some(main_victim))
+                if self.window.len() > self.window_target() => {
+                if self.sketch.estimate(candidate) >
+                   self.sketch.estimate(main_victim) {
+                    main_victim
+                } else {
+                    candidate
+                }
+            }
+            (_, Some(main_victim)) => main_victim,
+            (candidate, None) => return candidate.cloned(),
+        };
+        Some(victim.clone())
+    }
+
+    fn admit(&mut self, key: &K, victim: &Arc<K>) -> bool {
+        // counting this attempt, which insert will do if it gets in
+        let admitted = self.sketch.estimate(key) as usize + 1 >=
+                       self.sketch.estimate(victim) as usize;
+        if !admitted {
+            self.sketch.increment(key);
+        }
+        admitted
+    }
+
+    fn clear(&mut self) {
+        self.places.clear();
+        self.window.clear();
+        self.probation.clear();
+        self.protected.clear();
+        self.sketch.clear();
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum SubLru {
+    // where new items start out
+    Hot,
+    // items that have shown they're worth keeping
+    Warm,
+    // everything else, and where evictions come from
+    Cold,
+    // items that are going to expire soon anyway, which are never moved
+    Temp,
+}
+
+/// Memcached's segmented LRU. New items go into HOT and flow down into COLD
+/// unless they're used while they're there, which sends them to WARM instead.
+/// WARM keeps anything that keeps being used, and we evict from COLD, so that
+/// a scan through lots of keys that are only used once can only push out
+/// other things that haven't been used. Something in COLD has to be used
+/// twice to be bumped back up to WARM, and that waits for the maintainer so
+/// that reads don't have to shuffle things around. Items with short TTLs go
+/// in TEMP and stay there until they expire
+///
+/// Like memcached, HOT and WARM are only allowed a share of the pool, but
+/// it's a share of how many entries it has rather than of its memory
+#[derive(Debug)]
+pub struct SegmentedPolicy<K> {
+    limits: SegmentLimits,
+    // which LRU each key is in, its place in that LRU, and whether it's been
+    // used since it got there
+    places: HashMap<Arc<K>, (SubLru, u64, bool)>,
+    // each ordered from least to most recently arrived
+    hot: BTreeMap<u64, Arc<K>>,
+    warm: BTreeMap<u64, Arc<K>>,
+    cold: BTreeMap<u64, Arc<K>>,
+    temp: BTreeMap<u64, Arc<K>>,
+    // the most recently arrived end of every LRU
+    counter: u64,
+    // COLD items that have been used twice, waiting to be moved up to WARM
+    bumps: Vec<Arc<K>>,
+}
+
+impl<K: Hash + Eq> SegmentedPolicy<K> {
+    pub fn new(limits: SegmentLimits) -> SegmentedPolicy<K> {
+        SegmentedPolicy {
+            limits,
+            places: HashMap::new(),
+            hot: BTreeMap::new(),
+            warm: BTreeMap::new(),
+            cold: BTreeMap::new(),
+            temp: BTreeMap::new(),
+            counter: 0,
+            bumps: Vec::new(),
+        }
+    }
+
+    fn lru(&mut self, lru: SubLru) -> &mut BTreeMap<u64, Arc<K>> {
+        match lru {
+            SubLru::Hot => &mut self.hot,
+            SubLru::Warm => &mut self.warm,
+            SubLru::Cold => &mut self.cold,
+            SubLru::Temp => &mut self.temp,
+        }
+    }
+
+    fn limit(&self, pct: usize) -> usize {
+        // TEMP doesn't count towards the others' shares
+        let stored = self.hot.len() + self.warm.len() + self.cold.len();
+        cmp::max(stored * pct / 100, 1)
+    }
+
+    fn push(&mut self, lru: SubLru, key: &Arc<K>) {
+        // onto the most recently arrived end, not used there yet
+        self.counter += 1;
+        let place = self.counter;
+        self.lru(lru).insert(place, key.clone());
+        self.places.insert(key.clone(), (lru, place, false));
+    }
+
+    fn take(&mut self, key: &Arc<K>) -> Option<(SubLru, bool)> {
+        // out of whichever LRU it's in, if any
+        let (lru, place, active) = self.places.remove(key)?;
+        self.lru(lru).remove(&place);
+        Some((lru, active))
+    }
+
+    fn take_oldest(&mut self, lru: SubLru) -> Option<(Arc<K>, bool)> {
+        let oldest = match self.lru(lru).values().next() {
+            Some(key) => key.clone(),
+            None => return None,
+        };
+        self.take(&oldest).map(|(_, active)| (oldest, active))
+    }
+
+    fn juggle(&mut self) {
+        // move things down out of HOT and WARM until they're within their
+        // limits. Whatever's been used gets to go to (or stay in) WARM, but
+        // only once: it has to be used again to stay longer
+        let hot_limit = self.limit(self.limits.hot_pct);
+        while self.hot.len() > hot_limit {
+            match self.take_oldest(SubLru::Hot) {
+                Some((key, true)) => self.push(SubLru::Warm, &key),
+                Some((key, false)) => self.push(SubLru::Cold, &key),
+                None => break,
+            }
+        }
+        let warm_limit = self.limit(self.limits.warm_pct);
+        while self.warm.len() > warm_limit {
+            match self.take_oldest(SubLru::Warm) {
+                Some((key, true)) => self.push(SubLru::Warm, &key),
+                Some((key, false)) => self.push(SubLru::Cold, &key),
+                None => break,
+            }
+        }
+    }
+}
+
+impl<K> EvictionPolicy<K> for SegmentedPolicy<K>
+    where K: Hash + Eq + Debug + Send + Sync
+{
+    fn insert(&mut self,
+              key: &Arc<K>,
+              expires: Option<Timestamp>,
+              now: Timestamp) {
+        self.take(key);
+        let ttl = self.limits.temporary_ttl;
+        let temporary = match expires {
+            Some(expires) => ttl > 0 && expires.saturating_sub(now) < ttl,
+            None => false,
+        };
+        if temporary {
+            self.push(SubLru::Temp, key);
+        } else {
+            self.push(SubLru::Hot, key);
+            self.juggle();
+        }
+    }
+
+    fn access(&mut self, key: &Arc<K>, _used: Timestamp, _now: Timestamp) {
+        // nothing moves here. We just remember that it was used for when it
+        // gets to the end of its LRU, or for the maintainer if it's in COLD
+        // and this is the second time
+        let bump = match self.places.get_mut(key) {
+            Some(&mut (SubLru::Cold, _, ref mut active)) => {
+                let bump = *active;
+                *active = true;
+                bump
+            }
+            Some(&mut (_, _, ref mut active)) => {
+                *active = true;
+                false
+            }
+            None => false,
+        };
+        if bump {
+            self.bumps.push(key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &Arc<K>, _used: Timestamp, _evicted: bool) {
+        // if it's waiting to be bumped, the maintainer won't find it
+        self.take(key);
+    }
+
+    fn victim(&self) -> Option<Arc<K>> {
+        // COLD's oldest. When there isn't anything in COLD yet, HOT's
+        // oldest, and then the ones that'll be gone soon anyway before the
+        // ones that have been used
+        self.cold
+            .values()
+            .next()
+            .or_else(|| self.hot.values().next())
+            .or_else(|| self.temp.values().next())
+            .or_else(|| self.warm.values().next())
+            .cloned()
+    }
+
+    fn maintain(&mut self, _now: Timestamp) {
+        let bumps = mem::take(&mut self.bumps);
+        for key in &bumps {
+            let cold = matches!(self.places.get(key),
+                                Some(&(SubLru::Cold, _, _)));
+            if cold {
+                self.take(key);
+                self.push(SubLru::Warm, key);
+            }
+        }
+        self.juggle();
+    }
+
+    fn segments(&self) -> Vec<(&'static str, usize)> {
+        vec![("hot", self.hot.len()),
+             ("warm", self.warm.len()),
+             ("cold", self.cold.len()),
+             ("temp", self.temp.len())]
+    }
+
+    fn clear(&mut self) {
+        self.places.clear();
+        self.hot.clear();
+        self.warm.clear();
+        self.cold.clear();
+        self.temp.clear();
+        self.bumps.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn k(key: &str) -> Arc<String> {
+        Arc::new(key.to_string())
+    }
+
+    fn evict(policy: &mut dyn EvictionPolicy<String>) -> String {
+        // ARC doesn't care when things were used
+        let victim = policy.victim().unwrap();
+        policy.remove(&victim, 0, true);
+        (*victim).clone()
+    }
+
+    #[test]
+    fn lru() {
+        let mut policy = PolicyType::Lru.make();
+        policy.insert(&k("a"), None, 1);
+        policy.insert(&k("b"), None, 2);
+        policy.insert(&k("c"), None, 3);
+        policy.access(&k("a"), 1, 4);
+        policy.remove(&k("c"), 3, false);
+
+        assert_eq!(policy.victim(), Some(k("b")));
+        policy.remove(&k("b"), 2, true);
+        assert_eq!(policy.victim(), Some(k("a")));
+        policy.remove(&k("a"), 4, true);
+        assert!(policy.victim().is_none());
+    }
+
+    #[test]
+    fn arc_scan() {
+        // things that have been used again outlast a stream of new ones
+        let mut policy = PolicyType::Arc.make();
+        policy.insert(&k("hot"), None, 1);
+        policy.access(&k("hot"), 1, 2);
+        for key in &["a", "b", "c"] {
+            policy.insert(&k(key), None, 3);
+        }
+
+        assert_eq!(evict(&mut *policy), "a");
+        assert_eq!(evict(&mut *policy), "b");
+        assert_eq!(evict(&mut *policy), "c");
+        assert_eq!(evict(&mut *policy), "hot");
+        assert!(policy.victim().is_none());
+    }
+
+    #[test]
+    fn arc_adapts() {
+        let mut policy = ArcPolicy::new();
+        policy.insert(&k("hot"), None, 1);
+        policy.access(&k("hot"), 1, 2);
+        policy.insert(&k("a"), None, 3);
+        policy.insert(&k("b"), None, 3);
+
+        // a comes back after we threw it away, so it was a mistake to prefer
+        // the frequent ones, and a recent one is allowed to stay
+        assert_eq!(evict(&mut policy), "a");
+        assert_eq!(policy.target, 0);
+        policy.insert(&k("a"), None, 4);
+        assert_eq!(policy.target, 1);
+        assert_eq!(policy.places[&k("a")].0, List::Frequent);
+        assert_eq!(evict(&mut policy), "hot");
+
+        // deleting things doesn't leave ghosts behind
+        policy.remove(&k("b"), 3, false);
+        assert!(!policy.places.contains_key(&k("b")));
+
+        // and it goes back the other way when a frequent one comes back
+        policy.insert(&k("c"), None, 5);
+        policy.insert(&k("hot"), None, 5);
+        assert_eq!(policy.target, 0);
+
+        policy.clear();
+        assert!(policy.victim().is_none());
+        assert!(policy.places.is_empty());
+    }
+
+    #[test]
+    fn sketch() {
+        let mut sketch = FrequencySketch::new();
+        for _ in 0..20 {
+            sketch.increment(&"a");
+        }
+        sketch.increment(&"b");
+        // counts stop at the most four bits can hold
+        assert_eq!(sketch.estimate(&"a"), SKETCH_MAX_COUNT);
+        assert_eq!(sketch.estimate(&"b"), 1);
+        assert_eq!(sketch.estimate(&"c"), 0);
+
+        // and every so often they're all halved
+        for _ in 0..sketch.sample - sketch.additions {
+            sketch.increment(&"z");
+        }
+        assert_eq!(sketch.estimate(&"a"), SKETCH_MAX_COUNT / 2);
+        assert_eq!(sketch.estimate(&"b"), 0);
+
+        // growing starts over
+        sketch.ensure_capacity(SKETCH_MIN_WIDTH + 1);
+        assert_eq!(sketch.width, SKETCH_MIN_WIDTH * 2);
+        assert_eq!(sketch.estimate(&"a"), 0);
+    }
+
+    #[test]
+    fn tinylfu() {
+        let mut policy = TinyLfuPolicy::new();
+        policy.insert(&k("hot"), None, 1);
+        policy.access(&k("hot"), 1, 2);
+        policy.insert(&k("a"), None, 3);
+
+        // nothing's made it out of the window yet, so it's plain LRU
+        assert_eq!(evict(&mut policy), "hot");
+        assert_eq!(policy.places[&k("a")].0, Segment::Window);
+
+        // once there's something in the main region, the window's overflow
+        // has to be used more often than that to get in
+        policy.insert(&k("b"), None, 4);
+        policy.access(&k("b"), 4, 5);
+        policy.insert(&k("c"), None, 6);
+        assert_eq!(evict(&mut policy), "a");
+        assert_eq!(policy.places[&k("b")].0, Segment::Probation);
+        policy.insert(&k("e"), None, 6);
+        assert_eq!(evict(&mut policy), "c");
+        assert_eq!(policy.places[&k("e")].0, Segment::Window);
+
+        // something that's never been seen isn't worth throwing b away for,
+        // but if it keeps turning up it is
+        assert!(!policy.admit(&k("d"), &k("b")));
+        assert!(policy.admit(&k("d"), &k("b")));
+
+        // used again on probation, so it's protected now
+        policy.access(&k("b"), 5, 7);
+        assert_eq!(policy.places[&k("b")].0, Segment::Protected);
+
+        policy.clear();
+        assert!(policy.victim().is_none());
+        assert_eq!(policy.sketch.estimate(&k("b")), 0);
+    }
+
+    #[test]
+    fn segmented() {
+        let mut policy = SegmentedPolicy::new(SegmentLimits::default());
+        let lru = |policy: &SegmentedPolicy<String>, key| {
+            policy.places[&k(key)].0
+        };
+
+        // short TTLs go straight to TEMP
+        policy.insert(&k("temp"), Some(10), 1);
+        assert_eq!(lru(&policy, "temp"), SubLru::Temp);
+
+        // there's only room for one in HOT, so each new one pushes the last
+        // one out. To WARM if it was used there, and COLD if it wasn't
+        policy.insert(&k("a"), None, 1);
+        policy.access(&k("a"), 1, 2);
+        policy.insert(&k("b"), None, 3);
+        assert_eq!(lru(&policy, "a"), SubLru::Warm);
+        policy.insert(&k("c"), None, 4);
+        assert_eq!(lru(&policy, "b"), SubLru::Cold);
+        assert_eq!(policy.victim(), Some(k("b")));
+
+        // once isn't enough to get out of COLD, and twice waits for the
+        // maintainer. That fills WARM up, so its oldest goes down to COLD
+        policy.access(&k("b"), 3, 5);
+        policy.maintain(5);
+        assert_eq!(lru(&policy, "b"), SubLru::Cold);
+        policy.access(&k("b"), 5, 6);
+        assert_eq!(lru(&policy, "b"), SubLru::Cold);
+        policy.maintain(6);
+        assert_eq!(lru(&policy, "b"), SubLru::Warm);
+        assert_eq!(lru(&policy, "a"), SubLru::Cold);
+        assert_eq!(policy.segments(),
+                   vec![("hot", 1), ("warm", 1), ("cold", 1), ("temp", 1)]);
+
+        // COLD goes first, then HOT, then things that'll expire soon anyway
+        assert_eq!(evict(&mut policy), "a");
+        assert_eq!(evict(&mut policy), "c");
+        assert_eq!(evict(&mut policy), "temp");
+        assert_eq!(evict(&mut policy), "b");
+
+        policy.insert(&k("d"), None, 7);
+        policy.clear();
+        assert!(policy.victim().is_none());
+        assert!(policy.places.is_empty());
+    }
+}
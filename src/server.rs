@@ -1,15 +1,35 @@
-use std::thread::spawn;
-use std::sync::{Arc, Mutex};
-use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::thread::{spawn, JoinHandle};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::cmp;
+use std::fs;
+use std::mem;
+use std::process;
+use std::ptr;
+use std::time::{Duration, Instant};
 
 use std::io;
 use std::io::{Read, Write};
 
-use store::Store;
+use shards;
+use shards::{ShardedStore, MetaDump};
 use store::Response;
 use store::ServerCommand;
+use stats::ConnectionStats;
+use settings::Settings;
 use parser::CommandConfig;
 use parser;
+use binary;
+use udp;
+use epoll;
+use epoll::{Epoll, Token, Waker};
+use libc;
 
 pub const NAME: &'static [u8] = b"rustcache";
 pub const VERSION: &'static [u8] = b"0.1.0";
@@ -86,7 +106,53 @@ fn format_response(response: Response, socket: &mut Write) -> io::Result<()> {
             try!(socket.write(b"\r\n"));
         }
         Response::TooBig => {
-            try!(socket.write(b"SERVER_ERROR object too large for cache"));
+            socket.write_all(b"SERVER_ERROR object too large for cache\r\n")?;
+        }
+        Response::Stats { stats } => {
+            for (name, value) in &stats {
+                socket.write_all(b"STAT ")?;
+                socket.write_all(name.as_bytes())?;
+                socket.write_all(b" ")?;
+                socket.write_all(value.as_bytes())?;
+                socket.write_all(b"\r\n")?;
+            }
+            socket.write_all(b"END\r\n")?;
+        }
+        Response::Noop => {
+            socket.write_all(b"MN\r\n")?;
+        }
+        Response::Reassign(result) => {
+            socket.write_all(result.as_bytes())?;
+            socket.write_all(b"\r\n")?;
+        }
+        Response::Meta(meta) => {
+            // in quiet mode we leave out the responses they don't care about
+            if !meta.silent {
+                socket.write_all(meta.code.as_bytes())?;
+                if let Some(ref data) = meta.data {
+                    socket.write_all(format!(" {}", data.len()).as_bytes())?;
+                }
+                for returned in &meta.returned {
+                    socket.write_all(b" ")?;
+                    socket.write_all(&returned.to_bytes())?;
+                }
+                socket.write_all(b"\r\n")?;
+                if let Some(ref data) = meta.data {
+                    socket.write_all(data)?;
+                    socket.write_all(b"\r\n")?;
+                }
+            }
+        }
+        Response::MetaDebug { key, stats } => {
+            socket.write_all(b"ME ")?;
+            socket.write_all(key)?;
+            for (name, value) in &stats {
+                socket.write_all(b" ")?;
+                socket.write_all(name.as_bytes())?;
+                socket.write_all(b"=")?;
+                socket.write_all(value.as_bytes())?;
+            }
+            socket.write_all(b"\r\n")?;
         }
         Response::Version => {
             try!(socket.write(b"VERSION "));
@@ -102,126 +168,1266 @@ fn format_response(response: Response, socket: &mut Write) -> io::Result<()> {
     Ok(())
 }
 
-fn client(locked_store: Arc<Mutex<Store>>,
-          mut socket: TcpStream,
-          verbose: bool) {
+// what the client loop should do after handling what's in its buffer
+pub enum Processed {
+    // this many bytes were handled. That can be more than the caller has, if
+    // they're the payload of a command we refused and have to throw away as
+    // it arrives
+    Consumed(usize),
+    // consumed a metadump, which is too big to answer all at once. The
+    // caller writes it out a bit at a time with ShardedStore::dump_batch
+    Dump(usize, MetaDump),
+    NeedMore,
+    Disconnect,
+}
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+enum Protocol {
+    Ascii,
+    Binary,
+}
+
+pub fn ascii_command(store: &ShardedStore,
+                     parse_state: &[u8],
+                     socket: &mut dyn Write,
+                     verbose: bool)
+                     -> Processed {
+    if let Some(oversized) = parser::oversized(parse_state,
+                                               store.item_size_max()) {
+        return refuse(oversized, socket, verbose);
+    }
+    match parser::parse_command(parse_state) {
+        parser::IResult::Done(remaining, command_config) => {
+            let CommandConfig { should_reply, command } = command_config;
+
+            let response = match command {
+                ServerCommand::Quit => {
+                    // no response, just disconnect them and quit
+                    return Processed::Disconnect;
+                }
+                ServerCommand::Bad(text) => {
+                    if let Some(malformed) = parser::malformed(parse_state) {
+                        return recover(malformed, socket, verbose);
+                    }
+                    if verbose {
+                        println!("bad client command: {:?}",
+                                 String::from_utf8_lossy(text))
+                    }
+                    Response::Error
+                }
+                ServerCommand::MetaDump { class } => {
+                    let consumed = parse_state.len() - remaining.len();
+                    match store.start_dump(class) {
+                        Some(dump) => return Processed::Dump(consumed, dump),
+                        None => {
+                            Response::ClientError { message: b"bad class id" }
+                        }
+                    }
+                }
+                // all others must be sent to the store
+                _ => store.apply(command),
+            };
+            if should_reply {
+                if let Err(err) = format_response(response, socket) {
+                    if verbose {
+                        println!("client write error {:?}", err);
+                    }
+                    // TODO right now we just disconnect them
+                    return Processed::Disconnect;
+                }
+            }
+            Processed::Consumed(parse_state.len() - remaining.len())
+        }
+        parser::IResult::Error(err) => {
+            if verbose {
+                println!("parser error? {:?}", err);
+            }
+            match parser::resync(parse_state) {
+                Some(malformed) => recover(malformed, socket, verbose),
+                None => Processed::NeedMore,
+            }
+        }
+        parser::IResult::Incomplete(_needed) => Processed::NeedMore,
+    }
+}
+
+fn refuse(oversized: parser::Oversized,
+          socket: &mut dyn Write,
+          verbose: bool)
+          -> Processed {
+    // turn down a command that's too big to buffer, without waiting for the
+    // rest of it
+    let too_long = Response::ClientError { message: b"line too long" };
+    let (response, should_reply, processed) = match oversized {
+        parser::Oversized::Line => (too_long, true, Processed::Disconnect),
+        parser::Oversized::Key { skip, should_reply } => {
+            (too_long, should_reply, Processed::Consumed(skip))
+        }
+        parser::Oversized::Data { skip, should_reply } => {
+            (Response::TooBig, should_reply, Processed::Consumed(skip))
+        }
+    };
+    if verbose {
+        println!("refused oversized command");
+    }
+    respond(response, should_reply, processed, socket, verbose)
+}
+
+fn recover(malformed: parser::Malformed,
+           socket: &mut dyn Write,
+           verbose: bool)
+           -> Processed {
+    // tell them what was wrong with a command and carry on after it
+    let (message, skip, should_reply) = match malformed {
+        parser::Malformed::Line { skip, should_reply } => {
+            (&b"bad command line format"[..], skip, should_reply)
+        }
+        parser::Malformed::Chunk { skip, should_reply } => {
+            (&b"bad data chunk"[..], skip, should_reply)
+        }
+    };
     if verbose {
-        println!("client connect");
+        println!("malformed command: {}", String::from_utf8_lossy(message));
+    }
+    respond(Response::ClientError { message },
+            should_reply,
+            Processed::Consumed(skip),
+            socket,
+            verbose)
+}
+
+fn respond(response: Response,
+           should_reply: bool,
+           processed: Processed,
+           socket: &mut dyn Write,
+           verbose: bool)
+           -> Processed {
+    // send them `response` if they want it, then carry on as `processed` says
+    if should_reply {
+        if let Err(err) = format_response(response, socket) {
+            if verbose {
+                println!("client write error {:?}", err);
+            }
+            return Processed::Disconnect;
+        }
+    }
+    processed
+}
+
+fn binary_command(store: &ShardedStore,
+                  parse_state: &[u8],
+                  socket: &mut dyn Write,
+                  verbose: bool)
+                  -> Processed {
+    if let Some((response, skip)) = binary::oversized(parse_state,
+                                                      store.item_size_max()) {
+        if verbose {
+            println!("refused oversized command");
+        }
+        if let Err(err) = binary::format_response(response, socket) {
+            if verbose {
+                println!("client write error {:?}", err);
+            }
+            return Processed::Disconnect;
+        }
+        // the body is thrown away as it arrives, like an ASCII payload
+        return Processed::Consumed(skip);
+    }
+    match binary::parse_command(parse_state) {
+        binary::IResult::Done(remaining, command) => {
+            let quit = command.is_quit();
+            let response = binary::apply(store, command);
+            if response.should_reply() {
+                if let Err(err) = binary::format_response(response, socket) {
+                    if verbose {
+                        println!("client write error {:?}", err);
+                    }
+                    return Processed::Disconnect;
+                }
+            }
+            if quit {
+                return Processed::Disconnect;
+            }
+            Processed::Consumed(parse_state.len() - remaining.len())
+        }
+        binary::IResult::Error(err) => {
+            // without sane framing there's no way to find the next packet
+            if verbose {
+                println!("binary parser error? {:?}", err);
+            }
+            Processed::Disconnect
+        }
+        binary::IResult::Incomplete(_needed) => Processed::NeedMore,
     }
+}
 
-    // this buffer on our stack is the largest amount that we can read from the
-    // wire in a single go. bigger means fewer copies but more memory used per
-    // client connection
-    let mut buff: [u8; 10240] = [0; 10240];
+// anything we can hand to a worker as a client connection
+trait Stream: Read + Write + AsRawFd + Send {}
+impl<S: Read + Write + AsRawFd + Send> Stream for S {}
 
-    // the accumulated data that's been read but not parsed yet.  TODO we can
-    // avoid a lot of copies here by trying to use buff directly when possible
-    // and only spilling onto the heap when necessary. TODO this can be become
-    // infinite in size. We need provisions for booting clients that grow it too
-    // big, and for shrinking it occasionally so every client doesn't have
-    // megabytes of buffer just because they used that much once in the past
-    let mut parse_state: Vec<u8> = Vec::with_capacity(buff.len());
+// this is the largest amount that we read from the wire in a single go. bigger
+// means fewer copies but more memory used per read
+const READ_SIZE: usize = 16384;
 
-    loop {
-        match socket.read(&mut buff) {
+// the token for the waker in each worker's epoll. Everything else is keyed by
+// its file descriptor, which can never be this
+const WAKER: Token = u64::MAX;
+
+// a buffer that's grown past this is cut back down once it's emptied out, so
+// that a client that sent or asked for something big once doesn't keep the
+// memory for the rest of its life
+const BUFFER_KEEP: usize = 4 * READ_SIZE;
+
+// how often a worker looks for idle clients, if it's looking at all
+const IDLE_SWEEP_MS: i32 = 1000;
+
+// how long the acceptor waits before trying again when we've run out of file
+// descriptors
+const ACCEPT_BACKOFF_MS: u64 = 100;
+
+// what a client can make us hold on to, and how long they can keep us
+// waiting, before we hang up on them
+#[derive(Debug,Clone,Copy)]
+struct ClientLimits {
+    read_buf_max: usize,
+    write_buf_max: usize,
+    idle_timeout: Option<Duration>,
+}
+
+impl ClientLimits {
+    fn new(settings: &Settings) -> ClientLimits {
+        // whatever we've been told, there's always room for the biggest item
+        // with its command line or VALUE header, and for whatever came in
+        // with it in the same read
+        let needed = settings.item_size_max + parser::MAX_LINE + READ_SIZE;
+        ClientLimits {
+            read_buf_max: cmp::max(settings.read_buf_max, needed),
+            write_buf_max: cmp::max(settings.write_buf_max, needed),
+            idle_timeout: match settings.idle_timeout {
+                0 => None,
+                timeout => Some(Duration::from_secs(timeout as u64)),
+            },
+        }
+    }
+}
+
+fn shrink(buf: &mut Vec<u8>) {
+    if buf.capacity() > BUFFER_KEEP && buf.len() <= READ_SIZE {
+        buf.shrink_to(READ_SIZE);
+    }
+}
+
+struct Connection {
+    socket: Box<dyn Stream>,
+    limits: ClientLimits,
+    // the accumulated data that's been read but not parsed yet. The parser
+    // won't wait for more of a command than the item size limit, and we
+    // hang up on anybody that gets it past limits.read_buf_max anyway
+    read_buf: Vec<u8>,
+    // how much more of a refused command is still to come, which we throw
+    // away as it arrives
+    skip: usize,
+    // responses that the socket wasn't ready to take yet, of which we've
+    // already sent `written` bytes
+    write_buf: Vec<u8>,
+    written: usize,
+    // like memcached, we decide which protocol a client speaks from the first
+    // byte that they send us and hold them to it for the life of the
+    // connection
+    protocol: Option<Protocol>,
+    // what we've told epoll we're waiting for
+    interest: u32,
+    // we're hanging up on them as soon as they've got everything we've sent
+    closing: bool,
+    // a metadump that we're part way through sending them. We don't look at
+    // anything else they've sent until it's done
+    dump: Option<MetaDump>,
+    // the last time they sent us anything or took anything from us
+    last_active: Instant,
+    // we hung up on them for breaking one of our limits
+    booted: bool,
+}
+
+impl Connection {
+    fn new(socket: Box<dyn Stream>, limits: ClientLimits) -> Connection {
+        Connection {
+            socket,
+            limits,
+            read_buf: Vec::new(),
+            skip: 0,
+            write_buf: Vec::new(),
+            written: 0,
+            protocol: None,
+            interest: epoll::READABLE,
+            closing: false,
+            dump: None,
+            last_active: Instant::now(),
+            booted: false,
+        }
+    }
+
+    fn boot(&mut self, reason: &str, verbose: bool) -> bool {
+        // hang up on them straight away, whatever we still owe them
+        if verbose {
+            println!("booting client: {}", reason);
+        }
+        self.booted = true;
+        false
+    }
+
+    fn idle(&self, now: Instant) -> bool {
+        match self.limits.idle_timeout {
+            Some(timeout) => now.duration_since(self.last_active) >= timeout,
+            None => false,
+        }
+    }
+
+    fn unsent(&self) -> usize {
+        self.write_buf.len() - self.written
+    }
+
+    fn ready(&mut self,
+             events: u32,
+             store: &ShardedStore,
+             verbose: bool)
+             -> bool {
+        // handle a readiness notification. Returns whether to keep them around
+        if events & epoll::WRITABLE != 0 {
+            if !self.flush(verbose) {
+                return false;
+            }
+            if self.write_buf.is_empty() && self.dump.is_some() &&
+               !self.continue_dump(store, verbose) {
+                return false;
+            }
+        }
+        if events & (epoll::READABLE | epoll::HANGUP) != 0 &&
+           !self.read(store, verbose) {
+            return false;
+        }
+        !(self.closing && self.write_buf.is_empty())
+    }
+
+    fn read(&mut self,
+            store: &ShardedStore,
+            verbose: bool)
+            -> bool {
+        let mut buff = [0; READ_SIZE];
+        match self.socket.read(&mut buff) {
+            Ok(0) => {
+                if verbose {
+                    println!("client disconnect");
+                }
+                return false; // eof
+            }
+            Ok(size) => {
+                self.read_buf.extend_from_slice(&buff[..size]);
+                self.last_active = Instant::now();
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                            err.kind() == io::ErrorKind::Interrupted => {
+                // we'll hear about it again when there really is something
+                return true;
+            }
             Err(err) => {
                 if verbose {
                     println!("client err: {:?}", err)
                 }
-                return;
+                return false;
             }
-            Ok(size) if size == 0 => {
+        }
+
+        if self.protocol.is_none() {
+            self.protocol = if self.read_buf[0] == binary::REQUEST_MAGIC {
+                Some(Protocol::Binary)
+            } else {
+                Some(Protocol::Ascii)
+            };
+        }
+
+        self.process(store, verbose)
+    }
+
+    fn process(&mut self, store: &ShardedStore, verbose: bool) -> bool {
+        // handle everything that they've pipelined before we go back to
+        // waiting on the socket
+        let skipped = cmp::min(self.skip, self.read_buf.len());
+        self.read_buf.drain(..skipped);
+        self.skip -= skipped;
+        while self.dump.is_none() && !self.closing {
+            let processed = match self.protocol {
+                Some(Protocol::Binary) => {
+                    binary_command(store,
+                                   &self.read_buf,
+                                   &mut self.write_buf,
+                                   verbose)
+                }
+                _ => {
+                    ascii_command(store,
+                                  &self.read_buf,
+                                  &mut self.write_buf,
+                                  verbose)
+                }
+            };
+            match processed {
+                Processed::Consumed(consumed) => {
+                    let drained = cmp::min(consumed, self.read_buf.len());
+                    self.read_buf.drain(..drained);
+                    self.skip = consumed - drained;
+                }
+                Processed::Dump(consumed, dump) => {
+                    self.read_buf.drain(..consumed);
+                    self.dump = Some(dump);
+                }
+                Processed::NeedMore => break,
+                Processed::Disconnect => {
+                    // but not before they get any responses we owe them
+                    self.closing = true;
+                }
+            }
+            if self.unsent() > self.limits.write_buf_max {
+                // they get a chance to take some of it before we decide that
+                // they aren't going to
+                if !self.flush(verbose) {
+                    return false;
+                }
+                if self.unsent() > self.limits.write_buf_max {
+                    return self.boot("too many unread replies", verbose);
+                }
+            }
+        }
+
+        if self.read_buf.len() > self.limits.read_buf_max {
+            return self.boot("too much unparsed input", verbose);
+        }
+        shrink(&mut self.read_buf);
+        self.flush(verbose)
+    }
+
+    fn continue_dump(&mut self, store: &ShardedStore, verbose: bool) -> bool {
+        // the next part of their metadump, once they've taken the last one.
+        // When it's finished we can get on with whatever they sent after it
+        let done = match self.dump {
+            Some(ref mut dump) => store.dump_batch(dump, &mut self.write_buf),
+            None => return true,
+        };
+        if done {
+            self.dump = None;
+            self.process(store, verbose)
+        } else {
+            self.flush(verbose)
+        }
+    }
+
+    fn flush(&mut self, verbose: bool) -> bool {
+        // send as much of the write buffer as the socket will take
+        while self.written < self.write_buf.len() {
+            match self.socket.write(&self.write_buf[self.written..]) {
+                Ok(0) => return false,
+                Ok(size) => {
+                    self.written += size;
+                    self.last_active = Instant::now();
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    if verbose {
+                        println!("client write error {:?}", err);
+                    }
+                    return false;
+                }
+            }
+        }
+        if self.written == self.write_buf.len() {
+            self.write_buf.clear();
+            self.written = 0;
+            shrink(&mut self.write_buf);
+        }
+        true
+    }
+
+    fn wanted_interest(&self) -> u32 {
+        // while they still have responses waiting we stop reading from them,
+        // so a client that never reads can't make us buffer forever
+        if self.write_buf.is_empty() && self.dump.is_none() {
+            epoll::READABLE
+        } else {
+            epoll::WRITABLE
+        }
+    }
+}
+
+// how the acceptor hands new connections to a worker thread
+#[derive(Clone)]
+struct WorkerHandle {
+    inbox: Sender<Box<dyn Stream>>,
+    waker: Arc<Waker>,
+}
+
+impl WorkerHandle {
+    fn adopt(&self, socket: Box<dyn Stream>) {
+        // the client has already been counted in with ConnectionStats::admit.
+        // The worker only goes away if it panicked, and then there's not much
+        // we can do about it
+        self.inbox.send(socket).unwrap();
+        self.waker.wake().unwrap();
+    }
+}
+
+fn worker(store: Arc<ShardedStore>,
+          connections: Arc<ConnectionStats>,
+          inbox: Receiver<Box<dyn Stream>>,
+          waker: Arc<Waker>,
+          limits: ClientLimits,
+          verbose: bool) {
+    let epoll = Epoll::new().unwrap();
+    epoll.add(waker.as_raw_fd(), WAKER, epoll::READABLE).unwrap();
+
+    let mut clients: HashMap<Token, Connection> = HashMap::new();
+    let mut events = Vec::new();
+    let timeout = if limits.idle_timeout.is_some() {
+        IDLE_SWEEP_MS
+    } else {
+        -1
+    };
+    let mut swept = Instant::now();
+
+    loop {
+        epoll.wait(&mut events, timeout).unwrap();
+
+        for event in &events {
+            if event.token == WAKER {
+                waker.reset();
+                while let Ok(socket) = inbox.try_recv() {
+                    let fd = socket.as_raw_fd();
+                    if let Err(err) = epoll.add(fd,
+                                                fd as Token,
+                                                epoll::READABLE) {
+                        println!("couldn't watch client: {:?}", err);
+                        // so that the acceptor has their place back
+                        connections.disconnected();
+                        continue;
+                    }
+                    if verbose {
+                        println!("client connect");
+                    }
+                    clients.insert(fd as Token,
+                                   Connection::new(socket, limits));
+                }
+                continue;
+            }
+
+            let keep = match clients.get_mut(&event.token) {
+                Some(client) => {
+                    if client.ready(event.events, &store, verbose) {
+                        let wanted = client.wanted_interest();
+                        if wanted != client.interest {
+                            client.interest = wanted;
+                            epoll.modify(event.token as RawFd,
+                                         event.token,
+                                         wanted)
+                                .is_ok()
+                        } else {
+                            true
+                        }
+                    } else {
+                        false
+                    }
+                }
+                // we hung up on them earlier in this batch of events
+                None => continue,
+            };
+
+            if !keep {
+                if clients[&event.token].booted {
+                    connections.booted(false);
+                }
+                let _ = epoll.delete(event.token as RawFd);
+                clients.remove(&event.token);
+                connections.disconnected();
+            }
+        }
+
+        if timeout >= 0 &&
+           swept.elapsed() >= Duration::from_millis(IDLE_SWEEP_MS as u64) {
+            swept = Instant::now();
+            let idle: Vec<Token> = clients.iter()
+                .filter(|&(_, client)| client.idle(swept))
+                .map(|(&token, _)| token)
+                .collect();
+            for token in idle {
                 if verbose {
-                    println!("client disconnect");
+                    println!("booting client: idle");
                 }
-                return; // eof
+                connections.booted(true);
+                let _ = epoll.delete(token as RawFd);
+                clients.remove(&token);
+                connections.disconnected();
             }
-            Ok(size) => {
-                parse_state.extend_from_slice(&buff[0..size]);
-
-                // TODO this is all sorts of slow. we hold the lock until the
-                // client is done receiving all of our bits!
-
-                match parser::parse_command(&parse_state.to_vec()) { // TODO copy
-                    parser::IResult::Done(remaining, command_config) => {
-                        let CommandConfig { should_reply, command } =
-                            command_config;
-
-                        let response = match command {
-                            ServerCommand::Quit => {
-                                // no response, just disconnect them and quit
-                                return;
-                            }
-                            ServerCommand::Bad(text) => {
-                                if verbose {
-                                    println!("bad client command: {:?}",
-                                             String::from_utf8_lossy(text))
-                                }
-                                Response::Error
-                            }
-                            _ => {
-                                // all others must be sent to the store
-                                let mut unlocked_store = locked_store.lock()
-                                    .unwrap();
-                                unlocked_store.apply(command)
-                            }
-                        };
-                        if should_reply {
-                            match format_response(response, &mut socket) {
-                                Result::Ok(_) => (),
-                                Result::Err(err) => {
-                                    if verbose {
-                                        println!("client write error {:?}", err);
-                                    }
-                                    // TODO right now we just disconnect them
-                                    return;
-                                }
-                            }
+        }
+    }
+}
+
+fn spawn_workers(count: usize,
+                 store: &Arc<ShardedStore>,
+                 connections: &Arc<ConnectionStats>,
+                 limits: ClientLimits,
+                 verbose: bool)
+                 -> (Vec<WorkerHandle>, Vec<JoinHandle<()>>) {
+    let mut handles = Vec::with_capacity(count);
+    let mut threads = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (sender, receiver) = channel();
+        let waker = Arc::new(Waker::new().unwrap());
+        handles.push(WorkerHandle {
+            inbox: sender,
+            waker: waker.clone(),
+        });
+
+        let store = store.clone();
+        let connections = connections.clone();
+        threads.push(spawn(move || {
+            worker(store, connections, receiver, waker, limits, verbose)
+        }));
+    }
+    (handles, threads)
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        // workers need the sockets to never block
+        match *self {
+            Listener::Tcp(ref listener) => {
+                let (socket, _) = listener.accept()?;
+                socket.set_nonblocking(true)?;
+                socket.set_nodelay(true)?;
+                Ok(Box::new(socket))
+            }
+            Listener::Unix(ref listener) => {
+                let (socket, _) = listener.accept()?;
+                socket.set_nonblocking(true)?;
+                Ok(Box::new(socket))
+            }
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Listener::Tcp(ref listener) => listener.as_raw_fd(),
+            Listener::Unix(ref listener) => listener.as_raw_fd(),
+        }
+    }
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.set_nonblocking(true),
+            Listener::Unix(ref listener) => listener.set_nonblocking(true),
+        }
+    }
+}
+
+fn running_out_of_files(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+fn acceptor(listeners: Vec<Listener>,
+            workers: Vec<WorkerHandle>,
+            connections: Arc<ConnectionStats>,
+            max_conns: usize,
+            verbose: bool) {
+    // accept new connections from all of our listeners and deal them out to
+    // the workers round-robin, turning away any past max_conns
+    let epoll = Epoll::new().unwrap();
+    for (token, listener) in listeners.iter().enumerate() {
+        listener.set_nonblocking().unwrap();
+        epoll.add(listener.as_raw_fd(), token as Token, epoll::READABLE)
+            .unwrap();
+    }
+
+    let mut next_worker = 0;
+    let mut events = Vec::new();
+
+    loop {
+        epoll.wait(&mut events, -1).unwrap();
+        let mut backoff = false;
+
+        for event in &events {
+            let listener = &listeners[event.token as usize];
+            loop {
+                match listener.accept() {
+                    Ok(mut socket) => {
+                        if !connections.admit(max_conns) {
+                            // it's a new socket, so there's room for this
+                            let _ = socket.write(b"ERROR Too many open \
+                                                   connections\r\n");
+                            connections.rejected();
+                            continue;
                         }
-                        // TODO this does all sorts of copying
-                        parse_state.clear();
-                        parse_state.extend_from_slice(remaining);
+                        workers[next_worker].adopt(socket);
+                        next_worker = (next_worker + 1) % workers.len();
                     }
-                    parser::IResult::Error(err) => {
+                    Err(ref err) if err.kind() ==
+                                    io::ErrorKind::WouldBlock => {
+                        break;
+                    }
+                    Err(ref err) if running_out_of_files(err) => {
+                        // they'll still be waiting when we come back, and
+                        // until some clients go away trying again straight
+                        // off is only going to fail the same way
                         if verbose {
-                            println!("parser error? {:?}", err);
+                            println!("out of file descriptors, not accepting \
+                                      for a while");
                         }
-                        // TODO can we recover from this?
-                        return;
+                        backoff = true;
+                        break;
                     }
-                    parser::IResult::Incomplete(_needed) => {
-                        continue;
+                    Err(err) => {
+                        if verbose {
+                            println!("client accept error: {:?}", err);
+                        }
+                        break;
                     }
                 }
             }
         }
+
+        if backoff {
+            connections.listen_disabled();
+            thread::sleep(Duration::from_millis(ACCEPT_BACKOFF_MS));
+        }
     }
 }
 
-fn start_client(locked_store: Arc<Mutex<Store>>,
-                socket: TcpStream,
-                verbose: bool) {
-    spawn(move || client(locked_store, socket, verbose));
+fn bind_unix(path: &str, mask: u32) -> io::Result<UnixListener> {
+    // a socket file left behind by a previous run would stop us from binding,
+    // so clear it out. But only if it really is a socket and nobody's still
+    // listening on it
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() &&
+           UnixStream::connect(path).is_err() {
+            fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mask))?;
+    Ok(listener)
 }
 
-pub fn start(port: u16, capacity: usize, verbose: bool) {
-    let store = Store::new(capacity);
-    let locked_store = Arc::new(Mutex::new(store));
-    let uri = format!("0.0.0.0:{}", port);
-    let uri: &str = &uri;
+fn block_sigterm() -> libc::sigset_t {
+    // so that only the thread waiting for it with sigwait hears about it.
+    // Threads inherit this, so it has to happen before we start any
+    unsafe {
+        let mut signals: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+        signals
+    }
+}
+
+fn spawn_snapshotter(store: Arc<ShardedStore>,
+                     signals: libc::sigset_t,
+                     path: String,
+                     verbose: bool)
+                     -> JoinHandle<()> {
+    // waits for SIGTERM, and writes everything out before we go
+    spawn(move || {
+        let mut signal = 0;
+        unsafe {
+            libc::sigwait(&signals, &mut signal);
+        }
+        match store.save(&path) {
+            Ok(saved) => {
+                if verbose {
+                    println!("saved {} items to {}", saved, path);
+                }
+            }
+            Err(err) => eprintln!("couldn't write snapshot {}: {}", path, err),
+        }
+        process::exit(0);
+    })
+}
+
+pub fn start(settings: Settings) {
+    let port = settings.port;
+    let udp_port = settings.udp_port;
+    let socket_path = settings.socket_path.clone();
+    let socket_mask = settings.socket_mask;
+    let threads = settings.threads;
+    let max_conns = settings.max_conns;
+    let verbose = settings.verbose;
+    let snapshot_file = settings.snapshot_file.clone();
+    let restore_file = settings.restore_file.clone();
+    let write_log = settings.write_log.clone();
+    let log_fsync = settings.log_fsync;
+    let limits = ClientLimits::new(&settings);
+    let signals = snapshot_file.as_ref().map(|_| block_sigterm());
+    let mut store = ShardedStore::new(settings);
 
     if verbose {
         println!("starting server");
     }
 
-    for client_stream in TcpListener::bind(&uri).unwrap().incoming() {
-        match client_stream {
-            Ok(client_stream) => {
-                start_client(locked_store.clone(), client_stream, verbose);
+    if let Some(path) = restore_file {
+        // a cache that's cold is better than no cache at all, so if this
+        // doesn't work out we carry on without it
+        match store.restore(&path) {
+            Ok(restored) => {
+                if verbose {
+                    println!("restored {} items from {}", restored, path);
+                }
             }
-            Err(err) => {
-                println!("client accept error: {:?}", err);
+            Err(err) => eprintln!("couldn't restore from {}: {}", path, err),
+        }
+    }
+
+    if let Some(path) = write_log {
+        // but if we've been asked to keep everything we can't do without it.
+        // What it has replaces anything from the snapshot
+        match store.open_log(&path, log_fsync) {
+            Ok(replayed) => {
+                if verbose {
+                    println!("replayed {} records from {}", replayed, path);
+                }
             }
+            Err(err) => panic!("couldn't open write log {}: {:?}", path, err),
+        }
+    }
+
+    let store = Arc::new(store);
+    let connections = store.connection_stats();
+
+    // bind everything up front so that we fail early if we can't
+    let mut listeners = Vec::new();
+    if port != 0 {
+        let uri = format!("0.0.0.0:{}", port);
+        let uri: &str = &uri;
+        listeners.push(Listener::Tcp(TcpListener::bind(uri).unwrap()));
+    }
+    if let Some(path) = socket_path {
+        match bind_unix(&path, socket_mask) {
+            Ok(listener) => listeners.push(Listener::Unix(listener)),
+            Err(err) => panic!("couldn't listen on {}: {:?}", path, err),
+        }
+    }
+
+    // these run for as long as we do, but nobody waits for them
+    shards::spawn_mover(store.clone());
+    shards::spawn_maintainer(store.clone());
+    shards::spawn_crawler(store.clone());
+    shards::spawn_log_keeper(store.clone());
+    if let (Some(signals), Some(path)) = (signals, snapshot_file) {
+        spawn_snapshotter(store.clone(), signals, path, verbose);
+    }
+
+    let mut running = Vec::new();
+
+    if udp_port != 0 {
+        let udp_store = store.clone();
+        running.push(spawn(move || udp::serve(udp_store, udp_port, verbose)));
+    }
+
+    if !listeners.is_empty() {
+        let (workers, worker_threads) =
+            spawn_workers(threads, &store, &connections, limits, verbose);
+        running.extend(worker_threads);
+        running.push(spawn(move || {
+            acceptor(listeners, workers, connections, max_conns, verbose)
+        }));
+    }
+
+    for thread in running {
+        thread.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+
+    use shards::ShardedStore;
+    use settings::Settings;
+    use stats::ConnectionStats;
+
+    // a worker that we hand clients to directly, doing the acceptor's
+    // counting for it
+    struct TestWorker {
+        handle: WorkerHandle,
+        connections: Arc<ConnectionStats>,
+    }
+
+    impl TestWorker {
+        fn adopt(&self, socket: Box<dyn Stream>) {
+            assert!(self.connections.admit(usize::MAX));
+            self.handle.adopt(socket);
+        }
+    }
+
+    fn start_worker(capacity: usize) -> TestWorker {
+        start_worker_with(Settings {
+            maxbytes: capacity,
+            shards: 4,
+            ..Settings::default()
+        })
+    }
+
+    fn start_worker_with(settings: Settings) -> TestWorker {
+        let limits = ClientLimits::new(&settings);
+        let store = Arc::new(ShardedStore::new(settings));
+        let connections = Arc::new(ConnectionStats::default());
+        let (mut workers, _) =
+            spawn_workers(1, &store, &connections, limits, false);
+        TestWorker {
+            handle: workers.pop().unwrap(),
+            connections,
         }
     }
+
+    fn booted(connections: &ConnectionStats) -> Vec<String> {
+        let mut stats = Vec::new();
+        connections.report(&mut stats);
+        stats[2..4].iter().map(|(_, value)| value.clone()).collect()
+    }
+
+    #[test]
+    pub fn unix_socket() {
+        let path = env::temp_dir()
+            .join(format!("rustcached-test-{}.sock", ::std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // the first listener leaves its socket file behind when it goes away,
+        // and the second has to clean it up
+        drop(bind_unix(path, 0o700).unwrap());
+        assert!(fs::metadata(path).is_ok());
+        let listener = Listener::Unix(bind_unix(path, 0o750).unwrap());
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+
+        let mut socket = UnixStream::connect(path).unwrap();
+        start_worker(1000).adopt(listener.accept().unwrap());
+
+        socket.write_all(b"set foo 0 0 3\r\nbar\r\nget foo\r\n").unwrap();
+        let expected = b"STORED\r\nVALUE foo 0 3\r\nbar\r\nEND\r\n";
+        let mut response = vec![0; expected.len()];
+        socket.read_exact(&mut response).unwrap();
+        assert_eq!(response, expected.to_vec());
+
+        // ...but it won't take over a socket somebody's listening on
+        assert!(bind_unix(path, 0o700).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    pub fn max_conns() {
+        let path = env::temp_dir()
+            .join(format!("rustcached-conns-{}.sock", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        let listener = Listener::Unix(bind_unix(path, 0o700).unwrap());
+        let store = Arc::new(ShardedStore::new(Settings {
+            shards: 4,
+            ..Settings::default()
+        }));
+        let connections = Arc::new(ConnectionStats::default());
+        let limits = ClientLimits::new(&Settings::default());
+        let (workers, _) =
+            spawn_workers(1, &store, &connections, limits, false);
+        let acceptor_connections = connections.clone();
+        spawn(move || {
+            acceptor(vec![listener], workers, acceptor_connections, 1, false)
+        });
+
+        let mut first = UnixStream::connect(path).unwrap();
+        first.write_all(b"version\r\n").unwrap();
+        let mut response = [0; 8];
+        first.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"VERSION ");
+
+        // there's no room for them while the first is still around
+        let mut second = UnixStream::connect(path).unwrap();
+        let mut response = Vec::new();
+        second.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"ERROR Too many open connections\r\n".to_vec());
+        let mut stats = Vec::new();
+        connections.report(&mut stats);
+        assert_eq!(stats[4],
+                   ("rejected_connections".to_string(), "1".to_string()));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    pub fn max_conns_burst() {
+        let path = env::temp_dir()
+            .join(format!("rustcached-burst-{}.sock", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        let listener = Listener::Unix(bind_unix(path, 0o700).unwrap());
+
+        // a worker that never gets around to taking anybody, so nobody is
+        // counted in unless the acceptor does it
+        let (sender, _inbox) = channel();
+        let worker = WorkerHandle {
+            inbox: sender,
+            waker: Arc::new(Waker::new().unwrap()),
+        };
+        let mut sockets: Vec<UnixStream> = (0..5)
+            .map(|_| UnixStream::connect(path).unwrap())
+            .collect();
+        let connections = Arc::new(ConnectionStats::default());
+        let acceptor_connections = connections.clone();
+        spawn(move || {
+            acceptor(vec![listener],
+                     vec![worker],
+                     acceptor_connections,
+                     2,
+                     false)
+        });
+
+        // they're accepted in the order they connected
+        for socket in &mut sockets[2..] {
+            let mut response = Vec::new();
+            socket.read_to_end(&mut response).unwrap();
+            assert_eq!(response,
+                       b"ERROR Too many open connections\r\n".to_vec());
+        }
+        let mut stats = Vec::new();
+        connections.report(&mut stats);
+        assert_eq!(stats[0], ("curr_connections".to_string(), "2".to_string()));
+        assert_eq!(stats[4],
+                   ("rejected_connections".to_string(), "3".to_string()));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    pub fn partial_writes() {
+        let (mut socket, accepted) = UnixStream::pair().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        start_worker(4 * 1024 * 1024).adopt(Box::new(accepted));
+
+        // ask for far more than fits in the socket's buffer before we read
+        // any of it, so the worker has to hold on to the rest until we do
+        let value = vec![b'x'; 512 * 1024];
+        let mut request = b"set big 0 0 524288\r\n".to_vec();
+        request.extend_from_slice(&value);
+        request.extend_from_slice(b"\r\n");
+        for _ in 0..4 {
+            request.extend_from_slice(b"get big\r\n");
+        }
+        request.extend_from_slice(b"quit\r\n");
+        socket.write_all(&request).unwrap();
+
+        let mut expected = b"STORED\r\n".to_vec();
+        for _ in 0..4 {
+            expected.extend_from_slice(b"VALUE big 0 524288\r\n");
+            expected.extend_from_slice(&value);
+            expected.extend_from_slice(b"\r\nEND\r\n");
+        }
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).unwrap();
+        assert!(response == expected);
+    }
+
+    #[test]
+    pub fn oversized() {
+        let worker = start_worker(4 * 1024 * 1024);
+        let (mut socket, accepted) = UnixStream::pair().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        worker.adopt(Box::new(accepted));
+
+        // the payload is thrown away as it arrives rather than buffered, and
+        // then we carry on with what comes after it
+        let mut request = b"set big 0 0 2000000\r\n".to_vec();
+        request.extend_from_slice(&vec![b'x'; 2000000]);
+        request.extend_from_slice(b"\r\nset foo 0 0 3\r\nbar\r\n\
+                                    get foo\r\nquit\r\n");
+        socket.write_all(&request).unwrap();
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(),
+                   "SERVER_ERROR object too large for cache\r\nSTORED\r\n\
+                    VALUE foo 0 3\r\nbar\r\nEND\r\n");
+
+        // but a line that never ends leaves us nowhere to pick up from
+        let (mut socket, accepted) = UnixStream::pair().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        worker.adopt(Box::new(accepted));
+        socket.write_all(&vec![b'x'; 3000]).unwrap();
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"CLIENT_ERROR line too long\r\n".to_vec());
+    }
+
+    #[test]
+    pub fn binary_oversized() {
+        let (mut socket, accepted) = UnixStream::pair().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        start_worker(4 * 1024 * 1024).adopt(Box::new(accepted));
+
+        // a set of foo with a 2MB value, which is too big, then a quit
+        let mut request = b"\x80\x01\x00\x03\x08\x00\x00\x00\x00\x1e\x84\x8b\
+                            \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+                            \x00\x00\x00\x00\x00\x00\x00\x00foo"
+            .to_vec();
+        request.extend_from_slice(&vec![b'x'; 2000000]);
+        request.extend_from_slice(&[0x80, 0x07]);
+        request.extend_from_slice(&[0; 22]);
+        socket.write_all(&request).unwrap();
+
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).unwrap();
+        assert_eq!(response.len(), 24 + 10 + 24);
+        assert_eq!(&response[..8], b"\x81\x01\x00\x00\x00\x00\x00\x03");
+        assert_eq!(&response[24..34], b"Too large.");
+        assert_eq!(&response[34..36], b"\x81\x07");
+    }
+
+    #[test]
+    pub fn unread_replies() {
+        let worker = start_worker_with(Settings {
+            shards: 4,
+            write_buf_max: 0,
+            ..Settings::default()
+        });
+        let (mut socket, accepted) = UnixStream::pair().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        worker.adopt(Box::new(accepted));
+
+        // they ask for far more than our limit and never read any of it
+        let mut request = b"set big 0 0 524288\r\n".to_vec();
+        request.extend_from_slice(&vec![b'x'; 524288]);
+        request.extend_from_slice(b"\r\n");
+        for _ in 0..20 {
+            request.extend_from_slice(b"get big\r\n");
+        }
+        socket.write_all(&request).unwrap();
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).unwrap();
+        assert!(response.len() < 20 * 524288);
+        assert_eq!(booted(&worker.connections), vec!["1", "0"]);
+    }
+
+    #[test]
+    pub fn idle_timeout() {
+        let worker = start_worker_with(Settings {
+            shards: 4,
+            idle_timeout: 1,
+            ..Settings::default()
+        });
+        let (mut socket, accepted) = UnixStream::pair().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        worker.adopt(Box::new(accepted));
+
+        socket.write_all(b"version\r\n").unwrap();
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"VERSION "));
+        assert_eq!(booted(&worker.connections), vec!["1", "1"]);
+    }
+
+    #[test]
+    pub fn buffers_shrink() {
+        let store = ShardedStore::new(Settings {
+            shards: 4,
+            ..Settings::default()
+        });
+        let (mut socket, accepted) = UnixStream::pair().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        let limits = ClientLimits::new(&Settings::default());
+        let mut client = Connection::new(Box::new(accepted), limits);
+
+        let value = vec![b'x'; 524288];
+        client.read_buf.extend_from_slice(b"set big 0 0 524288\r\n");
+        client.read_buf.extend_from_slice(&value);
+        client.read_buf.extend_from_slice(b"\r\nget big\r\n");
+        assert!(client.process(&store, false));
+        assert!(client.read_buf.capacity() <= READ_SIZE);
+
+        // the reply doesn't fit in the socket, so the rest of it waits
+        let expected = b"STORED\r\nVALUE big 0 524288\r\n".len() + 524288 +
+                       b"\r\nEND\r\n".len();
+        let mut buff = vec![0; 65536];
+        let mut received = 0;
+        assert!(client.write_buf.capacity() > BUFFER_KEEP);
+        while received < expected {
+            received += socket.read(&mut buff).unwrap();
+            assert!(client.flush(false));
+        }
+        assert!(client.write_buf.capacity() <= READ_SIZE);
+    }
+
+    #[test]
+    pub fn malformed() {
+        let (mut socket, accepted) = UnixStream::pair().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        start_worker(1024 * 1024).adopt(Box::new(accepted));
+
+        // none of which costs them their connection
+        socket.write_all(b"set foo 0 0 3\r\nabcd\r\n\
+                           set foo bar 0 3\r\n\
+                           \nget foo\r\n\
+                           cas foo 0 0 3\r\nabc\r\n\
+                           ms foo 3 Tsoon\r\nabc\r\n\
+                           bogus\r\n\
+                           set foo 0 0 3 noreply\r\nabcdef\r\n\
+                           set foo 0 0 3\r\nbar\r\nget foo\r\nquit\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        let lines: Vec<&str> = response.lines().collect();
+        assert_eq!(lines,
+                   vec!["CLIENT_ERROR bad data chunk",
+                        "CLIENT_ERROR bad command line format",
+                        "CLIENT_ERROR bad command line format",
+                        "CLIENT_ERROR bad command line format",
+                        "CLIENT_ERROR bad command line format",
+                        "ERROR",
+                        "STORED",
+                        "VALUE foo 0 3",
+                        "bar",
+                        "END"]);
+    }
+
+    #[test]
+    pub fn metadump() {
+        let (mut socket, accepted) = UnixStream::pair().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        start_worker(1024 * 1024).adopt(Box::new(accepted));
+
+        let mut request = Vec::new();
+        for i in 0..500 {
+            request.extend_from_slice(format!("set k{} 0 0 1 noreply\r\nv\r\n",
+                                              i)
+                .as_bytes());
+        }
+        // what they pipeline after it waits until the dump's done
+        request.extend_from_slice(b"lru_crawler metadump all\r\n\
+                                    lru_crawler metadump 999\r\n\
+                                    get k7\r\nquit\r\n");
+        socket.write_all(&request).unwrap();
+
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        let lines: Vec<&str> = response.lines().collect();
+        assert_eq!(lines.len(), 500 + 5);
+        assert!(lines[..500].iter().all(|line| line.starts_with("key=k")));
+        assert_eq!(&lines[500..],
+                   &["END", "CLIENT_ERROR bad class id", "VALUE k7 0 1", "v",
+                     "END"]);
+    }
 }
@@ -0,0 +1,150 @@
+//! The effective configuration of the server, as assembled from the command
+//! line by cmd.rs
+
+use std::cmp;
+
+use policy::PolicyType;
+use writelog::FsyncPolicy;
+use slabs;
+use stats;
+use stats::Stat;
+use store;
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Settings {
+    pub port: u16,
+    // 0 means we don't listen on UDP at all
+    pub udp_port: u16,
+    // a unix socket to listen on as well, and the permissions to give it
+    pub socket_path: Option<String>,
+    pub socket_mask: u32,
+    // how many worker threads handle client connections
+    pub threads: usize,
+    // how many clients can be connected at once. Any more are turned away
+    pub max_conns: usize,
+    // how many pieces the keyspace and memory budget are split into, at
+    // most. See shard_count
+    pub shards: usize,
+    pub maxbytes: usize,
+    // how the slab allocator's size classes are laid out
+    pub growth_factor: f64,
+    pub chunk_size: usize,
+    // how eagerly we move memory between the size classes by ourselves. 0
+    // never does, 2 does as soon as any class has to evict anything
+    pub slab_automove: usize,
+    // how each size class chooses what to throw away when it's full
+    pub eviction: PolicyType,
+    // whether the background crawler reclaims expired items before anybody
+    // needs their room, how many microseconds it pauses between stretches
+    // of work, and how many it reclaims from each class per crawl (0 for no
+    // limit)
+    pub lru_crawler: bool,
+    pub lru_crawler_sleep: u32,
+    pub lru_crawler_tocrawl: u32,
+    // where we write everything on SIGTERM or a snapshot command, and what
+    // we load at startup, for restarting without losing the cache
+    pub snapshot_file: Option<String>,
+    pub restore_file: Option<String>,
+    // where we write down every change as it happens, to replay at startup,
+    // and how often we make sure it's on disk
+    pub write_log: Option<String>,
+    pub log_fsync: FsyncPolicy,
+    pub verbose: bool,
+    // the biggest value we'll store. Anything bigger than the biggest slab
+    // chunk is split over several
+    pub item_size_max: usize,
+    // the most of a client's input that we'll hold on to waiting for the end
+    // of a command, and the most of our replies that we'll hold for one
+    // that isn't reading them, before we hang up on them. Neither is ever
+    // less than the biggest item needs, so 0 means just that
+    pub read_buf_max: usize,
+    pub write_buf_max: usize,
+    // how many seconds a client can go without us hearing from them before
+    // we hang up on them. 0 is for as long as they like
+    pub idle_timeout: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            port: 11211,
+            udp_port: 0,
+            socket_path: None,
+            socket_mask: 0o700,
+            threads: 4,
+            max_conns: 1024,
+            shards: 16,
+            maxbytes: 64 * 1024 * 1024,
+            growth_factor: slabs::GROWTH_FACTOR,
+            chunk_size: slabs::MIN_CHUNK,
+            slab_automove: 1,
+            eviction: PolicyType::Lru,
+            lru_crawler: true,
+            lru_crawler_sleep: 100,
+            lru_crawler_tocrawl: 0,
+            snapshot_file: None,
+            restore_file: None,
+            write_log: None,
+            log_fsync: FsyncPolicy::EverySec,
+            verbose: false,
+            item_size_max: store::MAX_DATA,
+            read_buf_max: 0,
+            write_buf_max: 64 * 1024 * 1024,
+            idle_timeout: 0,
+        }
+    }
+}
+
+impl Settings {
+    pub fn shard_count(&self) -> usize {
+        // as many shards as we were asked for, as long as each one's share of
+        // the memory still has room for the biggest item
+        let room = cmp::max(1, self.maxbytes / self.item_size_max);
+        cmp::min(self.shards, room)
+    }
+
+    pub fn report(&self, stats: &mut Vec<Stat>) {
+        stats::push(stats, "maxbytes", self.maxbytes);
+        stats::push(stats, "tcpport", self.port);
+        stats::push(stats, "udpport", self.udp_port);
+        stats::push(stats,
+                    "domain_socket",
+                    self.socket_path.as_ref().map_or("NULL", |path| &path[..]));
+        stats::push(stats, "umask", format!("{:o}", self.socket_mask));
+        stats::push(stats, "verbosity", if self.verbose { 1 } else { 0 });
+        stats::push(stats, "evictions", "on");
+        stats::push(stats,
+                    "growth_factor",
+                    format!("{:.2}", self.growth_factor));
+        stats::push(stats, "chunk_size", self.chunk_size);
+        stats::push(stats, "num_threads", self.threads);
+        stats::push(stats, "maxconns", self.max_conns);
+        stats::push(stats, "item_size_max", self.item_size_max);
+        stats::push(stats, "slab_reassign", "yes");
+        stats::push(stats, "slab_automove", self.slab_automove);
+        stats::push(stats, "eviction_policy", self.eviction.name());
+        stats::push(stats,
+                    "lru_crawler",
+                    if self.lru_crawler { "yes" } else { "no" });
+        stats::push(stats, "lru_crawler_sleep", self.lru_crawler_sleep);
+        stats::push(stats, "lru_crawler_tocrawl", self.lru_crawler_tocrawl);
+        stats::push(stats,
+                    "snapshot_file",
+                    self.snapshot_file.as_ref().map_or("NULL", |path| &path[..]));
+        stats::push(stats,
+                    "write_log",
+                    self.write_log.as_ref().map_or("NULL", |path| &path[..]));
+        stats::push(stats, "log_fsync", self.log_fsync.name());
+        stats::push(stats, "read_buf_max", self.read_buf_max);
+        stats::push(stats, "write_buf_max", self.write_buf_max);
+        stats::push(stats, "idle_timeout", self.idle_timeout);
+        if let PolicyType::Segmented(limits) = self.eviction {
+            stats::push(stats, "lru_segmented", "yes");
+            stats::push(stats, "hot_lru_pct", limits.hot_pct);
+            stats::push(stats, "warm_lru_pct", limits.warm_pct);
+            stats::push(stats, "temporary_ttl", limits.temporary_ttl);
+        } else {
+            stats::push(stats, "lru_segmented", "no");
+        }
+    }
+}
@@ -0,0 +1,893 @@
+//! The keyspace is hash-partitioned across several independent `Store`s, each
+//! behind its own lock and with its own share of the memory budget, so that
+//! clients working on different keys don't wait on each other
+
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{BufReader, BufWriter};
+use std::iter::Peekable;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec;
+
+use meta;
+use settings::Settings;
+use snapshot;
+use snapshot::Record;
+use stats::ConnectionStats;
+use store;
+use store::{Store, ServerCommand, Response, GetterType, Key};
+use writelog;
+use writelog::{WriteLog, FsyncPolicy};
+
+// how often the automover looks at how the shards' memory is being used
+const AUTOMOVE_INTERVAL: u64 = 10;
+// how often the eviction policies get to do their housekeeping
+const MAINTAIN_INTERVAL: u64 = 1;
+// how often the expiry crawler starts over, and how long it may hold a shard
+// for at a time
+const CRAWL_INTERVAL: u64 = 1;
+const CRAWL_BUDGET: Duration = Duration::from_millis(1);
+
+// how many keys lru_crawler metadump looks up each time it takes a shard's
+// lock
+const DUMP_BATCH: usize = 100;
+
+// how often the write log is fsynced under FsyncPolicy::EverySec, and looked
+// at to see whether it's time to compact it
+const LOG_INTERVAL: u64 = 1;
+
+pub struct ShardedStore {
+    shards: Vec<Mutex<Store>>,
+    connections: Arc<ConnectionStats>,
+    // where the shards get their CAS uniques from
+    cas_ids: Arc<AtomicU64>,
+    log: Option<Arc<WriteLog>>,
+    // the biggest value the shards will take, which the parser needs before
+    // it waits for one
+    item_size_max: usize,
+}
+
+// how far through an lru_crawler metadump we've got
+#[derive(Debug)]
+pub struct MetaDump {
+    class: Option<usize>,
+    // the shard we're on, and the keys in it that we haven't done yet
+    shard: usize,
+    keys: Option<vec::IntoIter<Arc<store::StoredKey>>>,
+}
+
+fn shard_key<'a>(command: &ServerCommand<'a>) -> Option<Cow<'a, [u8]>> {
+    // the key that decides which shard a single-key command goes to
+    match *command {
+        ServerCommand::Setter { key, .. } |
+        ServerCommand::Delete { key } |
+        ServerCommand::Touch { key, .. } |
+        ServerCommand::Incrementer { key, .. } => Some(Cow::Borrowed(key)),
+        ServerCommand::MetaGet { key, ref flags } |
+        ServerCommand::MetaSet { key, ref flags, .. } |
+        ServerCommand::MetaDelete { key, ref flags } |
+        ServerCommand::MetaArithmetic { key, ref flags } |
+        ServerCommand::MetaDebug { key, ref flags } => {
+            // base64 keys have to land in the same place as their plain
+            // versions. If it doesn't decode the store will complain about
+            // it, and it doesn't matter which one does
+            if flags.base64 {
+                meta::decode_key(key, true).map(Cow::Owned)
+            } else {
+                Some(Cow::Borrowed(key))
+            }
+        }
+        _ => None,
+    }
+}
+
+impl ShardedStore {
+    pub fn new(settings: Settings) -> ShardedStore {
+        let count = settings.shard_count();
+        let connections = Arc::new(ConnectionStats::default());
+        let cas_ids = Arc::new(AtomicU64::new(0));
+        let shards = (0..count)
+            .map(|_| {
+                Mutex::new(Store::shard(settings.clone(),
+                                        settings.maxbytes / count,
+                                        connections.clone(),
+                                        cas_ids.clone()))
+            })
+            .collect();
+        ShardedStore {
+            shards,
+            connections,
+            cas_ids,
+            log: None,
+            item_size_max: settings.item_size_max,
+        }
+    }
+
+    pub fn connection_stats(&self) -> Arc<ConnectionStats> {
+        // the server loops keep these up to date for us
+        self.connections.clone()
+    }
+
+    pub fn item_size_max(&self) -> usize {
+        self.item_size_max
+    }
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    pub fn lock(&self, key: &[u8]) -> MutexGuard<'_, Store> {
+        // the shard that holds `key`
+        self.shards[self.shard_index(key)].lock().unwrap()
+    }
+
+    fn lock_all(&self) -> Vec<MutexGuard<'_, Store>> {
+        // always in the same order, so two of these can't deadlock
+        self.shards.iter().map(|shard| shard.lock().unwrap()).collect()
+    }
+
+    pub fn apply<'a>(&self, command: ServerCommand<'a>) -> Response<'a> {
+        if let Some(key) = shard_key(&command) {
+            return self.lock(&key).apply(command);
+        }
+
+        match command {
+            ServerCommand::Getter { getter, keys } => {
+                self.fan_out(getter, keys, |keys| {
+                    ServerCommand::Getter {
+                        getter,
+                        keys,
+                    }
+                })
+            }
+            ServerCommand::GetAndTouch { getter, ttl, keys } => {
+                self.fan_out(getter, keys, |keys| {
+                    ServerCommand::GetAndTouch {
+                        getter,
+                        ttl,
+                        keys,
+                    }
+                })
+            }
+            ServerCommand::FlushAll { delay } => {
+                // hold every shard while we do it, so nobody can see some of
+                // them flushed and some not
+                let mut shards = self.lock_all();
+                let now = store::epoch_time();
+                for shard in shards.iter_mut().skip(1) {
+                    shard.flush(delay, now);
+                }
+                // but only count it once
+                shards[0].apply(ServerCommand::FlushAll { delay })
+            }
+            ServerCommand::Stats(stats_type) => {
+                let shards = self.lock_all();
+                let shards: Vec<&Store> = shards.iter().map(|s| &**s).collect();
+                Response::Stats {
+                    stats: store::stats_report(&shards,
+                                               stats_type,
+                                               store::epoch_time()),
+                }
+            }
+            ServerCommand::SlabsReassign { src, dst } => {
+                // each shard has its own pages, so take one from whichever
+                // has the most of that class's
+                let mut shards = self.lock_all();
+                let index = (0..shards.len())
+                    .max_by_key(|&index| shards[index].class_pages(src))
+                    .unwrap();
+                shards[index].apply(ServerCommand::SlabsReassign {
+                    src,
+                    dst,
+                })
+            }
+            ServerCommand::SlabsAutomove(mode) => {
+                for shard in &self.shards {
+                    let mut shard = shard.lock().unwrap();
+                    shard.apply(ServerCommand::SlabsAutomove(mode));
+                }
+                Response::Ok
+            }
+            ServerCommand::LruCrawler(crawler) => {
+                for shard in &self.shards {
+                    let mut shard = shard.lock().unwrap();
+                    shard.apply(ServerCommand::LruCrawler(crawler));
+                }
+                Response::Ok
+            }
+            ServerCommand::Snapshot => {
+                let path = self.shards[0]
+                    .lock()
+                    .unwrap()
+                    .settings()
+                    .snapshot_file
+                    .clone();
+                match path.map(|path| self.save(&path)) {
+                    Some(Ok(_)) => Response::Ok,
+                    Some(Err(_)) => {
+                        Response::ServerError {
+                            message: b"couldn't write snapshot",
+                        }
+                    }
+                    None => {
+                        Response::ServerError {
+                            message: b"no snapshot file configured",
+                        }
+                    }
+                }
+            }
+            // nothing else cares about what's stored, so any shard will do
+            command => self.shards[0].lock().unwrap().apply(command),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<usize> {
+        // write everything to `path` for a warm restart, returning how many
+        // items that was. Each shard is only held while it's being written,
+        // and the file only replaces the last one once it's all there
+        let temp = format!("{}.tmp", path);
+        let file = File::create(&temp)?;
+        let mut out = BufWriter::new(file);
+        snapshot::write_header(&mut out,
+                               self.cas_ids.load(Ordering::Relaxed))?;
+        let mut saved = 0;
+        for shard in &self.shards {
+            saved += shard.lock().unwrap().snapshot(&mut out)?;
+        }
+        snapshot::write_end(&mut out)?;
+        let file = out.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        fs::rename(&temp, path)?;
+        Ok(saved)
+    }
+
+    pub fn restore(&self, path: &str) -> io::Result<usize> {
+        // load a snapshot written by save, returning how many items are
+        // still good. They may not end up in the same shards as before, so
+        // we put them all back in the order they were last used rather than
+        // shard by shard
+        let file = File::open(path)?;
+        let (last_cas_id, mut items) =
+            snapshot::read(&mut BufReader::new(file))?;
+        items.sort_by_key(|item| item.used);
+
+        // whatever we hand out next has to be newer than anything we had
+        let newest = items.iter().map(|item| item.unique).max().unwrap_or(0);
+        self.cas_ids.fetch_max(last_cas_id.max(newest), Ordering::Relaxed);
+
+        let mut restored = 0;
+        for item in items {
+            if self.lock(&item.key).restore(item) {
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    pub fn open_log(&mut self,
+                    path: &str,
+                    fsync: FsyncPolicy)
+                    -> io::Result<usize> {
+        // replay the write log at `path`, and write every change to it from
+        // now on. Returns how many records we replayed. It's compacted
+        // straight away, which gets rid of anything we were half way through
+        // writing when we went away, and takes in anything restored from a
+        // snapshot
+        let (last_cas_id, records) = writelog::replay(path)?;
+        let replayed = records.len();
+        let mut newest = last_cas_id;
+        for record in records {
+            let index = match record {
+                Record::Item(ref item) => {
+                    newest = newest.max(item.unique);
+                    self.shard_index(&item.key)
+                }
+                Record::Delete(ref key) => self.shard_index(key),
+                Record::Flush(deadline) => {
+                    for shard in &self.shards {
+                        shard.lock().unwrap().replay(Record::Flush(deadline));
+                    }
+                    continue;
+                }
+            };
+            self.shards[index].lock().unwrap().replay(record);
+        }
+        self.cas_ids.fetch_max(newest, Ordering::Relaxed);
+
+        let log = Arc::new(WriteLog::new(path, fsync)?);
+        for shard in &self.shards {
+            shard.lock().unwrap().set_log(log.clone());
+        }
+        self.log = Some(log);
+        self.compact()?;
+        Ok(replayed)
+    }
+
+    pub fn compact(&self) -> io::Result<()> {
+        // rewrite the write log from what's in the cache now. Each shard is
+        // only held while it's being written, and whatever's logged in the
+        // meantime goes on the end
+        let log = match self.log {
+            Some(ref log) => log,
+            None => return Ok(()),
+        };
+        let mut out =
+            log.start_compaction(self.cas_ids.load(Ordering::Relaxed))?;
+        // a flush_all that's still to come is the same in every shard, and
+        // has to go first to take whatever's older than it when it comes
+        let pending = self.shards[0].lock().unwrap().pending_flush();
+        if let Some(deadline) = pending {
+            if let Err(err) = snapshot::write_flush(&mut out, deadline) {
+                log.abandon_compaction();
+                return Err(err);
+            }
+        }
+        for shard in &self.shards {
+            if let Err(err) = shard.lock().unwrap().snapshot(&mut out) {
+                log.abandon_compaction();
+                return Err(err);
+            }
+        }
+        log.finish_compaction(out).inspect_err(|_| {
+            log.abandon_compaction();
+        })
+    }
+
+    pub fn rebalance(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().rebalance();
+        }
+    }
+
+    pub fn maintain(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().maintain();
+        }
+    }
+
+    pub fn start_dump(&self, class: Option<usize>) -> Option<MetaDump> {
+        // None if there's no such class
+        if let Some(class) = class {
+            let classes = self.shards[0].lock().unwrap().pools();
+            if class < 1 || class > classes {
+                return None;
+            }
+        }
+        Some(MetaDump {
+            class,
+            shard: 0,
+            keys: None,
+        })
+    }
+
+    pub fn dump_batch(&self, dump: &mut MetaDump, out: &mut Vec<u8>) -> bool {
+        // the next few lines of a metadump, so that nobody waits on a shard
+        // for the whole thing and we don't have to hold all of it in memory.
+        // Returns whether that was the end of it
+        while dump.shard < self.shards.len() {
+            let mut shard = self.shards[dump.shard].lock().unwrap();
+            if dump.keys.is_none() {
+                dump.keys = Some(shard.dump_keys(dump.class).into_iter());
+            }
+            let batch: Vec<_> = dump.keys
+                .as_mut()
+                .unwrap()
+                .by_ref()
+                .take(DUMP_BATCH)
+                .collect();
+            if batch.is_empty() {
+                dump.shard += 1;
+                dump.keys = None;
+                continue;
+            }
+            shard.dump(&batch, out);
+            return false;
+        }
+        out.extend_from_slice(b"END\r\n");
+        true
+    }
+
+    pub fn crawl(&self) {
+        // one pass of the expiry crawler over every class of every shard.
+        // Each shard is only held for CRAWL_BUDGET at a time, with a pause
+        // in between so that the clients waiting on it get a turn
+        for shard in &self.shards {
+            let pools = shard.lock().unwrap().pools();
+            for pool in 0..pools {
+                let mut crawled = 0;
+                loop {
+                    let (more, sleep) = {
+                        let mut shard = shard.lock().unwrap();
+                        let (enabled, sleep, tocrawl) = {
+                            let settings = shard.settings();
+                            (settings.lru_crawler,
+                             settings.lru_crawler_sleep,
+                             settings.lru_crawler_tocrawl as usize)
+                        };
+                        if !enabled {
+                            return;
+                        }
+                        let limit = if tocrawl == 0 {
+                            usize::MAX
+                        } else {
+                            tocrawl.saturating_sub(crawled)
+                        };
+                        let deadline = Instant::now() + CRAWL_BUDGET;
+                        let (reclaimed, more) =
+                            shard.crawl(pool, limit, deadline);
+                        crawled += reclaimed;
+                        let finished = tocrawl != 0 && crawled >= tocrawl;
+                        (more && !finished, sleep)
+                    };
+                    if !more {
+                        break;
+                    }
+                    thread::sleep(Duration::from_micros(sleep as u64));
+                }
+            }
+        }
+    }
+
+    fn fan_out<'a, F>(&self,
+                      getter: GetterType,
+                      keys: Vec<Key<'a>>,
+                      make_command: F)
+                      -> Response<'a>
+        where F: Fn(Vec<Key<'a>>) -> ServerCommand<'a>
+    {
+        // split up a multi-key get so that each shard only gets locked once,
+        // then put the results back in the order they were asked for
+        let indices: Vec<usize> =
+            keys.iter().map(|key| self.shard_index(key)).collect();
+
+        let mut by_shard: Vec<Vec<Key<'a>>> =
+            vec![Vec::new(); self.shards.len()];
+        for (&key, &index) in keys.iter().zip(&indices) {
+            by_shard[index].push(key);
+        }
+
+        let mut found_by_shard: Vec<Peekable<vec::IntoIter<_>>> =
+            Vec::with_capacity(self.shards.len());
+        for (shard, shard_keys) in self.shards.iter().zip(by_shard) {
+            let found = if shard_keys.is_empty() {
+                Vec::new()
+            } else {
+                match shard.lock().unwrap().apply(make_command(shard_keys)) {
+                    Response::Data { responses } |
+                    Response::Gets { responses } => responses,
+                    _ => unreachable!("a get that didn't return data?"),
+                }
+            };
+            found_by_shard.push(found.into_iter().peekable());
+        }
+
+        // each shard answers in the order it was asked, and only for the keys
+        // that it found
+        let mut found = Vec::with_capacity(keys.len());
+        for (&key, &index) in keys.iter().zip(&indices) {
+            let shard_found = &mut found_by_shard[index];
+            let hit = shard_found.peek().is_some_and(|item| item.key == key);
+            if hit {
+                found.push(shard_found.next().unwrap());
+            }
+        }
+
+        match getter {
+            GetterType::Get => Response::Data { responses: found },
+            GetterType::Gets => Response::Gets { responses: found },
+        }
+    }
+}
+
+pub fn spawn_mover(store: Arc<ShardedStore>) -> thread::JoinHandle<()> {
+    // the background thread that moves memory between slab classes as they
+    // need it, when `slabs automove` is on
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(AUTOMOVE_INTERVAL));
+        store.rebalance();
+    })
+}
+
+pub fn spawn_maintainer(store: Arc<ShardedStore>) -> thread::JoinHandle<()> {
+    // the background thread that moves things between the segments of the
+    // segmented LRU. The other policies don't need it, but it's cheap
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(MAINTAIN_INTERVAL));
+        store.maintain();
+    })
+}
+
+pub fn spawn_crawler(store: Arc<ShardedStore>) -> thread::JoinHandle<()> {
+    // the background thread that throws away expired items without waiting
+    // for somebody to need their room, when `lru_crawler` is enabled
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(CRAWL_INTERVAL));
+        store.crawl();
+    })
+}
+
+pub fn spawn_log_keeper(store: Arc<ShardedStore>) -> thread::JoinHandle<()> {
+    // fsyncs and compacts the write log, if there is one
+    thread::spawn(move || {
+        let log = match store.log {
+            Some(ref log) => log.clone(),
+            None => return,
+        };
+        loop {
+            thread::sleep(Duration::from_secs(LOG_INTERVAL));
+            if log.fsync() == FsyncPolicy::EverySec {
+                log.sync();
+            }
+            if log.needs_compaction() {
+                // if it doesn't work out the log counts it as an error, and
+                // we'll try again next time
+                let _ = store.compact();
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    use settings::Settings;
+    use store::{ServerCommand, Response, GetterType, SetterType, StatsType,
+                CrawlerCommand, IncrementerType};
+
+    fn make_store() -> ShardedStore {
+        ShardedStore::new(Settings {
+            maxbytes: 40000,
+            shards: 4,
+            // small enough for all four to get their share
+            item_size_max: store::MIN_DATA_LIMIT,
+            ..Settings::default()
+        })
+    }
+
+    fn set(store: &ShardedStore, key: &'static str) {
+        let res = store.apply(ServerCommand::Setter {
+            setter: SetterType::Set,
+            key: key.as_bytes(),
+            data: key.as_bytes(),
+            ttl: 0,
+            flags: 0,
+        });
+        assert_eq!(res, Response::Stored);
+    }
+
+    fn stat(store: &ShardedStore, name: &str) -> String {
+        match store.apply(ServerCommand::Stats(StatsType::General)) {
+            Response::Stats { stats } => {
+                stats.into_iter().find(|s| s.0 == name).unwrap().1
+            }
+            other => panic!("bad stats response {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn multi_get() {
+        let store = make_store();
+        let keys = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        for key in keys.iter() {
+            set(&store, key);
+        }
+
+        // they don't all end up in the same place
+        let indices: Vec<usize> =
+            keys.iter().map(|k| store.shard_index(k.as_bytes())).collect();
+        assert!(indices.iter().any(|&i| i != indices[0]));
+
+        let asked: Vec<&[u8]> = vec![b"h", b"missing", b"a", b"e", b"a"];
+        match store.apply(ServerCommand::Getter {
+            getter: GetterType::Gets,
+            keys: asked,
+        }) {
+            Response::Gets { responses } => {
+                let found: Vec<&[u8]> =
+                    responses.iter().map(|r| r.key).collect();
+                let expected: Vec<&[u8]> = vec![b"h", b"a", b"e", b"a"];
+                assert_eq!(found, expected);
+                // and cas uniques are unique across shards
+                assert_eq!(responses[0].unique, 8);
+                assert_eq!(responses[1].unique, 1);
+            }
+            other => panic!("bad get response {:?}", other),
+        }
+        assert_eq!(stat(&store, "get_hits"), "4");
+        assert_eq!(stat(&store, "get_misses"), "1");
+        assert_eq!(stat(&store, "curr_items"), "8");
+        assert_eq!(stat(&store, "limit_maxbytes"), "40000");
+    }
+
+    #[test]
+    pub fn small_memory() {
+        // the default 16 shards of 8m would be too small for a 1m item, so
+        // there are fewer of them
+        let store = ShardedStore::new(Settings {
+            maxbytes: 8 * 1024 * 1024,
+            ..Settings::default()
+        });
+        assert_eq!(store.shards.len(), 8);
+        let data = vec![b'x'; store.item_size_max()];
+        for key in ["a", "b", "c", "d", "e", "f", "g", "h"].iter() {
+            let res = store.apply(ServerCommand::Setter {
+                setter: SetterType::Set,
+                key: key.as_bytes(),
+                data: &data,
+                ttl: 0,
+                flags: 0,
+            });
+            assert_eq!(res, Response::Stored);
+        }
+    }
+
+    #[test]
+    pub fn flush_all() {
+        let store = make_store();
+        let keys = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        for key in keys.iter() {
+            set(&store, key);
+        }
+
+        assert_eq!(store.apply(ServerCommand::FlushAll { delay: 0 }),
+                   Response::Ok);
+        assert_eq!(stat(&store, "curr_items"), "0");
+        assert_eq!(stat(&store, "cmd_flush"), "1");
+    }
+
+    #[test]
+    pub fn lru_crawler() {
+        let store = make_store();
+        let setting = |store: &ShardedStore, name: &str| -> String {
+            match store.apply(ServerCommand::Stats(StatsType::Settings)) {
+                Response::Stats { stats } => {
+                    stats.into_iter().find(|s| s.0 == name).unwrap().1
+                }
+                other => panic!("bad stats response {:?}", other),
+            }
+        };
+        assert_eq!(setting(&store, "lru_crawler"), "yes");
+
+        // every shard hears about it
+        let commands = [CrawlerCommand::Disable,
+                        CrawlerCommand::Sleep(250),
+                        CrawlerCommand::ToCrawl(10)];
+        for &command in commands.iter() {
+            assert_eq!(store.apply(ServerCommand::LruCrawler(command)),
+                       Response::Ok);
+        }
+        for shard in &store.shards {
+            let shard = shard.lock().unwrap();
+            assert!(!shard.settings().lru_crawler);
+            assert_eq!(shard.settings().lru_crawler_sleep, 250);
+            assert_eq!(shard.settings().lru_crawler_tocrawl, 10);
+        }
+        assert_eq!(setting(&store, "lru_crawler"), "no");
+
+        // and crawling leaves live items alone
+        set(&store, "a");
+        store.apply(ServerCommand::LruCrawler(CrawlerCommand::Enable));
+        store.crawl();
+        assert_eq!(stat(&store, "curr_items"), "1");
+        assert_eq!(stat(&store, "crawler_reclaimed"), "0");
+    }
+
+    #[test]
+    pub fn metadump() {
+        let store = make_store();
+        assert!(store.start_dump(Some(0)).is_none());
+        assert!(store.start_dump(Some(1000)).is_none());
+
+        let keys: Vec<String> = (0..250).map(|i| format!("k{}", i)).collect();
+        for key in &keys {
+            store.apply(ServerCommand::Setter {
+                setter: SetterType::Set,
+                key: key.as_bytes(),
+                data: b"v",
+                ttl: 0,
+                flags: 0,
+            });
+        }
+
+        // it comes out a batch at a time, across every shard
+        let mut dump = store.start_dump(None).unwrap();
+        let mut out = Vec::new();
+        let mut batches = 0;
+        while !store.dump_batch(&mut dump, &mut out) {
+            batches += 1;
+        }
+        assert!(batches >= 4);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.ends_with("END\r\n"));
+        let mut dumped: Vec<&str> = out.lines()
+            .filter(|line| line.starts_with("key="))
+            .map(|line| &line[4..line.find(' ').unwrap()])
+            .collect();
+        dumped.sort();
+        let mut expected: Vec<&str> = keys.iter().map(|k| &k[..]).collect();
+        expected.sort();
+        assert_eq!(dumped, expected);
+    }
+
+    #[test]
+    pub fn save_and_restore() {
+        let path = env::temp_dir()
+            .join(format!("rustcached-test-{}.snapshot", process::id()));
+        let path = path.to_str().unwrap();
+
+        let store = make_store();
+        let keys = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        for key in keys.iter() {
+            set(&store, key);
+        }
+        store.apply(ServerCommand::Delete { key: b"h" });
+        assert_eq!(store.save(path).unwrap(), 7);
+
+        // fewer shards than before is fine too
+        let restored = ShardedStore::new(Settings {
+            maxbytes: 40000,
+            shards: 3,
+            ..Settings::default()
+        });
+        assert_eq!(restored.restore(path).unwrap(), 7);
+        assert_eq!(stat(&restored, "curr_items"), "7");
+        match restored.apply(ServerCommand::Getter {
+            getter: GetterType::Gets,
+            keys: vec![b"a", b"g", b"h"],
+        }) {
+            Response::Gets { responses } => {
+                assert_eq!(responses.len(), 2);
+                assert_eq!(responses[0].data, b"a".to_vec());
+                assert_eq!(responses[0].unique, 1);
+                assert_eq!(responses[1].unique, 7);
+            }
+            other => panic!("bad get response {:?}", other),
+        }
+
+        // and CAS uniques carry on from where they were
+        set(&restored, "i");
+        match restored.apply(ServerCommand::Getter {
+            getter: GetterType::Gets,
+            keys: vec![b"i"],
+        }) {
+            Response::Gets { responses } => assert_eq!(responses[0].unique, 9),
+            other => panic!("bad get response {:?}", other),
+        }
+
+        fs::remove_file(path).unwrap();
+        assert!(restored.restore(path).is_err());
+    }
+
+    #[test]
+    pub fn write_log() {
+        let path = env::temp_dir()
+            .join(format!("rustcached-test-{}.wal", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let get = |store: &ShardedStore, key: &'static [u8]| {
+            match store.apply(ServerCommand::Getter {
+                getter: GetterType::Gets,
+                keys: vec![key],
+            }) {
+                Response::Gets { mut responses } => {
+                    responses.pop().map(|r| (r.data, r.unique))
+                }
+                other => panic!("bad get response {:?}", other),
+            }
+        };
+
+        let mut store = make_store();
+        assert_eq!(store.open_log(path, FsyncPolicy::No).unwrap(), 0);
+        for key in ["a", "b", "c", "n"].iter() {
+            set(&store, key);
+        }
+        store.apply(ServerCommand::FlushAll { delay: 0 });
+        set(&store, "a");
+        set(&store, "b");
+        store.apply(ServerCommand::Setter {
+            setter: SetterType::Append,
+            key: b"a",
+            data: b"ppend",
+            ttl: 0,
+            flags: 0,
+        });
+        store.apply(ServerCommand::Delete { key: b"b" });
+        // things that don't change anything don't get written down
+        store.apply(ServerCommand::Setter {
+            setter: SetterType::Add,
+            key: b"a",
+            data: b"nope",
+            ttl: 0,
+            flags: 0,
+        });
+        assert_eq!(get(&store, b"a"), Some((b"append".to_vec(), 7)));
+        // and neither do meta commands that miss
+        let size = stat(&store, "write_log_bytes");
+        let flags = || meta::MetaFlags { ttl: Some(30), ..Default::default() };
+        store.apply(ServerCommand::MetaGet {
+            key: b"missing",
+            flags: flags(),
+        });
+        store.apply(ServerCommand::MetaDelete {
+            key: b"missing",
+            flags: flags(),
+        });
+        store.apply(ServerCommand::MetaArithmetic {
+            key: b"missing",
+            flags: flags(),
+        });
+        assert_eq!(stat(&store, "write_log_bytes"), size);
+
+        // compacting halfway through loses nothing
+        store.compact().unwrap();
+        store.apply(ServerCommand::Setter {
+            setter: SetterType::Set,
+            key: b"n",
+            data: b"41",
+            ttl: 0,
+            flags: 0,
+        });
+        store.apply(ServerCommand::Incrementer {
+            incrementer: IncrementerType::Incr,
+            key: b"n",
+            value: 1,
+        });
+        drop(store);
+
+        let mut replayed = make_store();
+        assert_eq!(replayed.open_log(path, FsyncPolicy::No).unwrap(), 3);
+        assert_eq!(stat(&replayed, "curr_items"), "2");
+        assert_eq!(get(&replayed, b"a"), Some((b"append".to_vec(), 7)));
+        assert_eq!(get(&replayed, b"b"), None);
+        assert_eq!(get(&replayed, b"c"), None);
+        assert_eq!(get(&replayed, b"n").unwrap().0, b"42".to_vec());
+        // and it was compacted again when we opened it
+        assert_eq!(stat(&replayed, "write_log_compactions"), "1");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    pub fn write_log_pending_flush() {
+        let path = env::temp_dir()
+            .join(format!("rustcached-test-{}-flush.wal", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let pending = |store: &ShardedStore| {
+            store.shards[0].lock().unwrap().pending_flush()
+        };
+
+        let mut store = make_store();
+        store.open_log(path, FsyncPolicy::No).unwrap();
+        set(&store, "a");
+        store.apply(ServerCommand::FlushAll { delay: 100 });
+        let deadline = pending(&store);
+        assert!(deadline.is_some());
+        store.compact().unwrap();
+        drop(store);
+
+        // it survives being compacted, over and over as we restart
+        for _ in 0..2 {
+            let mut replayed = make_store();
+            assert_eq!(replayed.open_log(path, FsyncPolicy::No).unwrap(), 2);
+            assert_eq!(pending(&replayed), deadline);
+            assert_eq!(stat(&replayed, "curr_items"), "1");
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,868 @@
+//! The slab allocator. Memory is handed out in pages that each belong to one
+//! size class and get cut up into that class's chunks, and every value lives
+//! in the smallest chunk that fits it. A page only goes back once every
+//! chunk in it is free, when `slabs reassign` or the automover empties it to
+//! move its memory to another class, so however the sizes of the values churn
+//! we never hold more than the memory limit. Values bigger than the biggest
+//! chunk are stored as a chain of them, so that even a 128MB item doesn't
+//! need 128MB in one piece
+
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Deref;
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use lru::{Weight, Timestamp};
+use stats::{push, Stat};
+
+pub const PAGE_SIZE: Weight = 1024 * 1024;
+pub const GROWTH_FACTOR: f64 = 1.25;
+pub const MIN_CHUNK: Weight = 96;
+// the biggest chunk we cut out of a page. Anything bigger goes in several
+pub const SLAB_CHUNK_MAX: Weight = PAGE_SIZE;
+// smaller budgets (like a shard's share of a small -m) get smaller pages, so
+// that they can still be spread over this many of them
+const MIN_PAGES: Weight = 64;
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct SizeClasses {
+    sizes: Vec<Weight>,
+}
+
+#[derive(Debug,Default,Clone,PartialEq,Eq)]
+pub struct ClassSummary {
+    pub number: u64,
+    pub oldest_used: Option<Timestamp>,
+    pub evicted: u64,
+    // how many items are in each of the eviction policy's segments
+    pub segments: Vec<(&'static str, u64)>,
+}
+
+// what a class has been given, and how much of it is in use
+#[derive(Debug,Default,Clone,PartialEq,Eq)]
+pub struct ClassUsage {
+    pub pages: usize,
+    pub chunks: usize,
+    pub free_chunks: usize,
+    // how much of the used chunks the values actually take up
+    pub requested: Weight,
+    pub malloced: Weight,
+}
+
+impl SizeClasses {
+    pub fn new(min_chunk: Weight,
+               factor: f64,
+               item_size_max: Weight)
+               -> SizeClasses {
+        let mut sizes = Vec::new();
+        // like memcached, keep the chunks 8-byte aligned
+        let mut size = (min_chunk + 7) & !7;
+        while (size as f64) * factor < item_size_max as f64 {
+            sizes.push(size);
+            size = ((size as f64 * factor) as Weight + 7) & !7;
+        }
+        sizes.push(item_size_max);
+        SizeClasses { sizes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sizes.len()
+    }
+
+    pub fn class_for(&self, weight: Weight) -> usize {
+        // memcached numbers its classes from 1. Things slightly bigger than
+        // the biggest class (weights include some overhead) get lumped in with
+        // it
+        match self.sizes.iter().position(|&size| weight <= size) {
+            Some(idx) => idx + 1,
+            None => self.sizes.len(),
+        }
+    }
+
+    pub fn chunk_size(&self, class: usize) -> Weight {
+        self.sizes[class - 1]
+    }
+
+    pub fn chunks_per_page(&self, class: usize, page_size: Weight) -> usize {
+        // chunks bigger than a page get a page to themselves
+        cmp::max(1, page_size / self.chunk_size(class))
+    }
+
+    pub fn summarise<I>(&self,
+                        entries: I,
+                        evicted_by_pool: &BTreeMap<usize, u64>)
+                        -> Vec<ClassSummary>
+        where I: Iterator<Item = (usize, Timestamp)>
+    {
+        // this walks everything we're holding, but stats items and slabs are
+        // rare operator commands so we don't track these continuously
+        let mut summaries = vec![ClassSummary::default(); self.len()];
+        for (class, used) in entries {
+            let summary = &mut summaries[class - 1];
+            summary.number += 1;
+            summary.oldest_used = match summary.oldest_used {
+                Some(oldest) if oldest <= used => Some(oldest),
+                _ => Some(used),
+            };
+        }
+        // the LRU pools are numbered from 0
+        for (&pool, &evicted) in evicted_by_pool {
+            summaries[pool].evicted += evicted;
+        }
+        summaries
+    }
+
+    pub fn items_report(&self,
+                        stats: &mut Vec<Stat>,
+                        summaries: &[ClassSummary],
+                        now: Timestamp) {
+        for (idx, summary) in summaries.iter().enumerate() {
+            if summary.number == 0 && summary.evicted == 0 {
+                continue;
+            }
+            let class = idx + 1;
+            let age = summary.oldest_used
+                .map_or(0, |used| now.saturating_sub(used));
+            push(stats, &format!("items:{}:number", class), summary.number);
+            for &(segment, number) in &summary.segments {
+                push(stats,
+                     &format!("items:{}:number_{}", class, segment),
+                     number);
+            }
+            push(stats, &format!("items:{}:age", class), age);
+            push(stats, &format!("items:{}:evicted", class), summary.evicted);
+        }
+    }
+
+    pub fn slabs_report(&self,
+                        stats: &mut Vec<Stat>,
+                        usages: &[ClassUsage],
+                        page_size: Weight) {
+        let mut active_slabs = 0;
+        let mut total_malloced = 0;
+        for (idx, usage) in usages.iter().enumerate() {
+            if usage.pages == 0 {
+                continue;
+            }
+            let class = idx + 1;
+            let used_chunks = usage.chunks - usage.free_chunks;
+
+            push(stats,
+                 &format!("{}:chunk_size", class),
+                 self.chunk_size(class));
+            push(stats,
+                 &format!("{}:chunks_per_page", class),
+                 self.chunks_per_page(class, page_size));
+            push(stats, &format!("{}:total_pages", class), usage.pages);
+            push(stats, &format!("{}:total_chunks", class), usage.chunks);
+            push(stats, &format!("{}:used_chunks", class), used_chunks);
+            push(stats, &format!("{}:free_chunks", class), usage.free_chunks);
+            push(stats, &format!("{}:mem_requested", class), usage.requested);
+
+            active_slabs += 1;
+            total_malloced += usage.malloced;
+        }
+        push(stats, "active_slabs", active_slabs);
+        push(stats, "total_malloced", total_malloced);
+    }
+}
+
+impl ClassSummary {
+    pub fn add_segments(&mut self, segments: &[(&'static str, usize)]) {
+        // for adding up the segments of several shards, which all have the
+        // same ones in the same order
+        if self.segments.is_empty() {
+            self.segments =
+                segments.iter().map(|&(name, _)| (name, 0)).collect();
+        }
+        for (total, &(_, number)) in self.segments.iter_mut().zip(segments) {
+            total.1 += number as u64;
+        }
+    }
+}
+
+impl ClassUsage {
+    pub fn add(&mut self, other: &ClassUsage) {
+        // for adding up the slabs of several shards
+        self.pages += other.pages;
+        self.chunks += other.chunks;
+        self.free_chunks += other.free_chunks;
+        self.requested += other.requested;
+        self.malloced += other.malloced;
+    }
+}
+
+// the outcomes of `slabs reassign`, as memcached words them
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Reassigned {
+    Ok,
+    BadClass,
+    NoSpare,
+    Same,
+}
+
+impl Reassigned {
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match *self {
+            Reassigned::Ok => b"OK",
+            Reassigned::BadClass => b"BADCLASS invalid src or dst class id",
+            Reassigned::NoSpare => b"NOSPARE source class has no spare pages",
+            Reassigned::Same => b"SAME src and dst class are identical",
+        }
+    }
+}
+
+// how many checks in a row the conservative automover wants to see a class
+// being the worst off (or going without evictions) before it moves anything
+const AUTOMOVE_STREAK: u32 = 3;
+
+// decides when memory should move between classes by itself, from how many
+// evictions each class has had since it last looked
+#[derive(Debug,Default)]
+pub struct Automover {
+    evictions: Vec<u64>,
+    // how many checks in a row each class has had no evictions
+    idle: Vec<u32>,
+    // the class that had the most evictions last time, and for how many
+    // checks in a row it's been that one
+    worst: Option<(usize, u32)>,
+}
+
+impl Automover {
+    pub fn check(&mut self,
+                 mode: usize,
+                 usages: &[ClassUsage],
+                 evictions: &[u64])
+                 -> Option<(usize, usize)> {
+        // `evictions` are the totals by class so far. Returns the classes to
+        // move a page from and to, if any
+        self.evictions.resize(evictions.len(), 0);
+        self.idle.resize(evictions.len(), 0);
+
+        let mut worst: Option<(usize, u64)> = None;
+        for (idx, &total) in evictions.iter().enumerate() {
+            let recent = total.saturating_sub(self.evictions[idx]);
+            self.evictions[idx] = total;
+            if recent == 0 {
+                self.idle[idx] += 1;
+                continue;
+            }
+            self.idle[idx] = 0;
+            if worst.is_none_or(|(_, most)| recent > most) {
+                worst = Some((idx + 1, recent));
+            }
+        }
+        self.worst = match (worst, self.worst) {
+            (Some((class, _)), Some((last, streak))) if class == last => {
+                Some((class, streak + 1))
+            }
+            (Some((class, _)), _) => Some((class, 1)),
+            (None, _) => None,
+        };
+
+        let (dst, streak) = self.worst?;
+        let conservative = mode == 1;
+        if mode == 0 || (conservative && streak < AUTOMOVE_STREAK) {
+            return None;
+        }
+
+        // take it from whoever has the most memory sitting unused
+        let idle = &self.idle;
+        let src = usages.iter()
+            .enumerate()
+            .map(|(idx, usage)| (idx + 1, usage))
+            .filter(|&(class, usage)| {
+                class != dst && usage.pages > 1 &&
+                (!conservative || idle[class - 1] >= AUTOMOVE_STREAK)
+            })
+            .max_by_key(|&(_, usage)| {
+                usage.free_chunks * usage.malloced / cmp::max(usage.chunks, 1)
+            })
+            .map(|(class, _)| class);
+
+        if src.is_some() {
+            // and give it a chance to settle before moving any more
+            self.worst = None;
+        }
+        src.map(|src| (src, dst))
+    }
+}
+
+struct Page {
+    // pages are only ever touched through the chunks cut out of them, so we
+    // hold on to them as raw pointers and turn them back into Boxes when
+    // they're given back. Those leave a null behind for the next one to use
+    start: *mut u8,
+    size: Weight,
+    class: usize,
+}
+
+struct Arena {
+    pages: Vec<Page>,
+    // by class, the (page, offset) of every chunk that isn't in use
+    free: Vec<Vec<(usize, Weight)>>,
+    usage: Vec<ClassUsage>,
+    malloced: Weight,
+    limit: Weight,
+    page_size: Weight,
+}
+
+// the pages are only reachable through the arena (behind its lock) and through
+// chunks, which each own their part of a page exclusively
+unsafe impl Send for Arena {}
+
+impl Arena {
+    fn grow(&mut self, classes: &SizeClasses, class: usize) -> bool {
+        // give the class another page, if there's room for one. Near the
+        // limit the last page may be smaller than usual
+        let chunk_size = classes.chunk_size(class);
+        let room = (self.limit - self.malloced) / chunk_size;
+        let per_page = classes.chunks_per_page(class, self.page_size);
+        let chunks = cmp::min(per_page, room);
+        if chunks == 0 {
+            return false;
+        }
+
+        let size = chunks * chunk_size;
+        let memory = vec![0u8; size].into_boxed_slice();
+        let new_page = Page {
+            start: Box::into_raw(memory) as *mut u8,
+            size,
+            class,
+        };
+        let page = match self.pages.iter().position(|p| p.start.is_null()) {
+            Some(page) => {
+                self.pages[page] = new_page;
+                page
+            }
+            None => {
+                self.pages.push(new_page);
+                self.pages.len() - 1
+            }
+        };
+
+        // hand them out from the start of the page
+        let free = &mut self.free[class - 1];
+        for idx in (0..chunks).rev() {
+            free.push((page, idx * chunk_size));
+        }
+
+        let usage = &mut self.usage[class - 1];
+        usage.pages += 1;
+        usage.chunks += chunks;
+        usage.free_chunks += chunks;
+        usage.malloced += size;
+        self.malloced += size;
+        true
+    }
+
+    fn release(&mut self, classes: &SizeClasses, page: usize) {
+        // give a page back, once all of its chunks are free
+        let Page { start, size, class } = self.pages[page];
+        let free = &mut self.free[class - 1];
+        let before = free.len();
+        free.retain(|&(p, _)| p != page);
+        let chunks = before - free.len();
+        assert_eq!(chunks,
+                   size / classes.chunk_size(class),
+                   "releasing a page that's still in use");
+
+        let usage = &mut self.usage[class - 1];
+        usage.pages -= 1;
+        usage.chunks -= chunks;
+        usage.free_chunks -= chunks;
+        usage.malloced -= size;
+        self.malloced -= size;
+
+        free_page(start, size);
+        self.pages[page] = Page {
+            start: ptr::null_mut(),
+            size: 0,
+            class: 0,
+        };
+    }
+}
+
+fn free_page(start: *mut u8, size: Weight) {
+    unsafe {
+        let memory = slice::from_raw_parts_mut(start, size);
+        drop(Box::from_raw(memory as *mut [u8]));
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        // every chunk holds on to us, so none of them can be left by now
+        for page in &self.pages {
+            if !page.start.is_null() {
+                free_page(page.start, page.size);
+            }
+        }
+    }
+}
+
+struct Shared {
+    classes: SizeClasses,
+    arena: Mutex<Arena>,
+}
+
+#[derive(Clone)]
+pub struct Slabs {
+    shared: Arc<Shared>,
+}
+
+impl Slabs {
+    pub fn new(classes: SizeClasses, limit: Weight) -> Slabs {
+        let count = classes.len();
+        Slabs {
+            shared: Arc::new(Shared {
+                classes,
+                arena: Mutex::new(Arena {
+                    pages: Vec::new(),
+                    free: vec![Vec::new(); count],
+                    usage: vec![ClassUsage::default(); count],
+                    malloced: 0,
+                    limit,
+                    page_size: cmp::min(PAGE_SIZE, limit / MIN_PAGES),
+                }),
+            }),
+        }
+    }
+
+    pub fn classes(&self) -> &SizeClasses {
+        &self.shared.classes
+    }
+
+    pub fn class_for(&self, size: Weight) -> Option<usize> {
+        // the class that something this big would be stored in, if any
+        let classes = self.classes();
+        let class = classes.class_for(size);
+        if size <= classes.chunk_size(class) {
+            Some(class)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(test)]
+    pub fn alloc(&self, data: &[u8]) -> Option<Chunk> {
+        // copies `data` into a free chunk of the right size. Returns None if
+        // it's too big to store at all, or if that class is out of chunks
+        // and we're out of pages to give it
+        match self.class_for(data.len()) {
+            Some(class) => self.alloc_in(class, data),
+            None => None,
+        }
+    }
+
+    pub fn alloc_in(&self, class: usize, data: &[u8]) -> Option<Chunk> {
+        // like alloc, but in a class that may be bigger than it needs
+        if data.len() > self.classes().chunk_size(class) {
+            return None;
+        }
+
+        let (page, offset, start) = {
+            let mut arena = self.shared.arena.lock().unwrap();
+            if arena.free[class - 1].is_empty() &&
+               !arena.grow(&self.shared.classes, class) {
+                return None;
+            }
+            let (page, offset) = arena.free[class - 1].pop().unwrap();
+            let usage = &mut arena.usage[class - 1];
+            usage.free_chunks -= 1;
+            usage.requested += data.len();
+            (page, offset, arena.pages[page].start)
+        };
+
+        let ptr = unsafe { start.add(offset) };
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+        }
+        Some(Chunk {
+            slabs: self.clone(),
+            class,
+            page,
+            offset,
+            ptr,
+            len: data.len(),
+        })
+    }
+
+    fn free(&self, chunk: &Chunk) {
+        let mut arena = self.shared.arena.lock().unwrap();
+        arena.free[chunk.class - 1].push((chunk.page, chunk.offset));
+        let usage = &mut arena.usage[chunk.class - 1];
+        usage.free_chunks += 1;
+        usage.requested -= chunk.len;
+    }
+
+    pub fn spare_page(&self, class: usize) -> Option<usize> {
+        // the page that it'd be cheapest for a class to give up, which is
+        // whichever has the most free chunks. Like memcached we won't take a
+        // class's last page
+        let arena = self.shared.arena.lock().unwrap();
+        if arena.usage[class - 1].pages < 2 {
+            return None;
+        }
+        let mut free_by_page = BTreeMap::new();
+        for &(page, _) in &arena.free[class - 1] {
+            *free_by_page.entry(page).or_insert(0) += 1;
+        }
+        arena.pages
+            .iter()
+            .enumerate()
+            .filter(|&(_, page)| page.class == class)
+            .map(|(page, _)| page)
+            .max_by_key(|page| free_by_page.get(page).cloned().unwrap_or(0))
+    }
+
+    pub fn move_page(&self, page: usize, dst: usize) {
+        // give a page's memory to another class. Whatever was stored in it
+        // has to have been thrown away already. If the other class's chunks
+        // don't fit in it, it's left for any class that needs it
+        let mut arena = self.shared.arena.lock().unwrap();
+        arena.release(&self.shared.classes, page);
+        arena.grow(&self.shared.classes, dst);
+    }
+
+    pub fn limit(&self) -> Weight {
+        self.shared.arena.lock().unwrap().limit
+    }
+
+    pub fn page_size(&self) -> Weight {
+        self.shared.arena.lock().unwrap().page_size
+    }
+
+    pub fn usage(&self) -> Vec<ClassUsage> {
+        self.shared.arena.lock().unwrap().usage.clone()
+    }
+}
+
+impl fmt::Debug for Slabs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Slabs({:?})", self.shared.classes.sizes)
+    }
+}
+
+// a value stored in a slab. It goes back on its class's free list when it's
+// dropped
+pub struct Chunk {
+    slabs: Slabs,
+    class: usize,
+    page: usize,
+    offset: Weight,
+    ptr: *mut u8,
+    len: usize,
+}
+
+// a value stored in the slabs: usually in one chunk, but anything bigger than
+// the biggest chunk is spread over several of the biggest class
+#[derive(Debug,PartialEq)]
+pub enum Chunks {
+    Single(Chunk),
+    Chained(Vec<Chunk>),
+}
+
+// nobody else can get at the part of the page that a chunk points to
+unsafe impl Send for Chunk {}
+unsafe impl Sync for Chunk {}
+
+impl Chunk {
+    pub fn class(&self) -> usize {
+        self.class
+    }
+
+    pub fn page(&self) -> usize {
+        self.page
+    }
+
+    pub fn capacity(&self) -> Weight {
+        // how much memory it's really taking up
+        self.slabs.classes().chunk_size(self.class)
+    }
+}
+
+impl Chunks {
+    pub fn new(mut chunks: Vec<Chunk>) -> Chunks {
+        // the chunks that a value was split into, in order
+        if chunks.len() == 1 {
+            Chunks::Single(chunks.pop().unwrap())
+        } else {
+            Chunks::Chained(chunks)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            Chunks::Single(ref chunk) => chunk.len(),
+            Chunks::Chained(ref chunks) => {
+                chunks.iter().map(|chunk| chunk.len()).sum()
+            }
+        }
+    }
+
+    pub fn class(&self) -> usize {
+        // a chain's chunks are all in the same class
+        match *self {
+            Chunks::Single(ref chunk) => chunk.class(),
+            Chunks::Chained(ref chunks) => chunks[0].class(),
+        }
+    }
+
+    pub fn capacity(&self) -> Weight {
+        match *self {
+            Chunks::Single(ref chunk) => chunk.capacity(),
+            Chunks::Chained(ref chunks) => {
+                chunks.iter().map(|chunk| chunk.capacity()).sum()
+            }
+        }
+    }
+
+    pub fn uses_page(&self, page: usize) -> bool {
+        match *self {
+            Chunks::Single(ref chunk) => chunk.page() == page,
+            Chunks::Chained(ref chunks) => {
+                chunks.iter().any(|chunk| chunk.page() == page)
+            }
+        }
+    }
+
+    pub fn bytes(&self) -> Cow<'_, [u8]> {
+        // the whole value, which has to be copied if it's in pieces
+        match *self {
+            Chunks::Single(ref chunk) => Cow::Borrowed(chunk),
+            Chunks::Chained(_) => Cow::Owned(self.to_vec()),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut copied = Vec::with_capacity(self.len());
+        self.copy_into(&mut copied);
+        copied
+    }
+
+    pub fn copy_into(&self, out: &mut Vec<u8>) {
+        match *self {
+            Chunks::Single(ref chunk) => out.extend_from_slice(chunk),
+            Chunks::Chained(ref chunks) => {
+                for chunk in chunks {
+                    out.extend_from_slice(chunk);
+                }
+            }
+        }
+    }
+}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        self.slabs.free(self);
+    }
+}
+
+impl fmt::Debug for Chunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Chunk({}, {:?})", self.class, &**self)
+    }
+}
+
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Chunk) -> bool {
+        **self == **other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    pub fn size_classes() {
+        let classes = SizeClasses::new(96, 1.25, 1024);
+        assert_eq!(classes.sizes, vec![96, 120, 152, 192, 240, 304, 384, 480,
+                                       600, 752, 1024]);
+        assert_eq!(classes.class_for(1), 1);
+        assert_eq!(classes.class_for(96), 1);
+        assert_eq!(classes.class_for(97), 2);
+        assert_eq!(classes.class_for(1024), 11);
+        assert_eq!(classes.class_for(2000), 11);
+        assert_eq!(classes.chunk_size(2), 120);
+        assert_eq!(SizeClasses::new(90, 2.0, 1024).sizes,
+                   vec![96, 192, 384, 1024]);
+
+        let mut evicted = BTreeMap::new();
+        evicted.insert(2, 3);
+        let summaries = classes.summarise(vec![(2, 50), (2, 40), (11, 60)]
+                                              .into_iter(),
+                                          &evicted);
+        assert_eq!(summaries[1],
+                   ClassSummary {
+                       number: 2,
+                       oldest_used: Some(40),
+                       evicted: 0,
+                       segments: Vec::new(),
+                   });
+        assert_eq!(summaries[2].evicted, 3);
+        assert_eq!(summaries[10].number, 1);
+
+        // the shards' segments add up
+        let mut summary = ClassSummary::default();
+        summary.add_segments(&[("hot", 1), ("cold", 2)]);
+        summary.add_segments(&[("hot", 3), ("cold", 0)]);
+        assert_eq!(summary.segments, vec![("hot", 4), ("cold", 2)]);
+    }
+
+    #[test]
+    pub fn automove() {
+        let usage = |pages, chunks, free_chunks, malloced| {
+            ClassUsage {
+                pages,
+                chunks,
+                free_chunks,
+                requested: 0,
+                malloced,
+            }
+        };
+        let usages = vec![usage(4, 40, 30, 4000),
+                          usage(2, 10, 0, 1200),
+                          usage(1, 1, 1, 1000)];
+
+        // the conservative mode waits to be sure
+        let mut mover = Automover::default();
+        assert_eq!(mover.check(1, &usages, &[0, 5, 0]), None);
+        assert_eq!(mover.check(1, &usages, &[0, 10, 0]), None);
+        assert_eq!(mover.check(1, &usages, &[0, 15, 0]), Some((1, 2)));
+        assert_eq!(mover.check(1, &usages, &[0, 20, 0]), None);
+        // and only takes from classes that haven't been evicting
+        assert_eq!(mover.check(1, &usages, &[1, 25, 0]), None);
+        assert_eq!(mover.check(1, &usages, &[1, 30, 0]), None);
+        assert_eq!(mover.check(1, &usages, &[1, 35, 0]), None);
+        assert_eq!(mover.check(1, &usages, &[1, 40, 0]), Some((1, 2)));
+
+        // the aggressive one doesn't
+        let mut mover = Automover::default();
+        assert_eq!(mover.check(2, &usages, &[0, 5, 0]), Some((1, 2)));
+        assert_eq!(mover.check(2, &usages, &[0, 5, 0]), None);
+        assert_eq!(mover.check(2, &usages, &[3, 5, 0]), Some((2, 1)));
+
+        assert_eq!(Automover::default().check(0, &usages, &[0, 5, 0]), None);
+    }
+
+    #[test]
+    pub fn moving_pages() {
+        let slabs = Slabs::new(SizeClasses::new(96, 2.0, 1024), 500 * 64);
+        let kept = slabs.alloc(b"kept").unwrap();
+        let mut chunks = Vec::new();
+        for _ in 0..5 {
+            chunks.push(slabs.alloc(b"moved").unwrap());
+        }
+        assert_eq!(slabs.usage()[0].pages, 2);
+
+        // the emptier page goes
+        let page = slabs.spare_page(1).unwrap();
+        assert!(page != kept.page());
+        chunks.retain(|chunk| chunk.page() != page);
+        slabs.move_page(page, 2);
+        assert_eq!(slabs.usage()[0],
+                   ClassUsage {
+                       pages: 1,
+                       chunks: 5,
+                       free_chunks: 0,
+                       requested: 24,
+                       malloced: 480,
+                   });
+        assert_eq!(slabs.usage()[1].chunks, 2);
+        assert_eq!(slabs.spare_page(1), None);
+
+        // and its slot gets used for the new one
+        assert_eq!(slabs.alloc(&[b'x'; 150]).unwrap().page(), page);
+    }
+
+    #[test]
+    pub fn allocating() {
+        // small enough that the pages are only 500 bytes
+        let slabs = Slabs::new(SizeClasses::new(96, 2.0, 1024), 500 * 64);
+        assert_eq!(slabs.page_size(), 500);
+
+        let small = slabs.alloc(b"small").unwrap();
+        assert_eq!(&*small, b"small");
+        assert_eq!(small.class(), 1);
+        assert_eq!(small.capacity(), 96);
+        let usage = slabs.usage();
+        assert_eq!(usage[0],
+                   ClassUsage {
+                       pages: 1,
+                       chunks: 5,
+                       free_chunks: 4,
+                       requested: 5,
+                       malloced: 480,
+                   });
+        assert_eq!(slabs.alloc(&[b'x'; 1000]).unwrap().capacity(), 1024);
+        assert_eq!(slabs.class_for(2000), None);
+        assert!(slabs.alloc(&[b'x'; 2000]).is_none());
+
+        // fill up the rest of it. The last page gets cut short
+        let mut chunks = Vec::new();
+        while let Some(chunk) = slabs.alloc(b"more") {
+            chunks.push(chunk);
+        }
+        let usage = slabs.usage();
+        assert_eq!(usage[0].pages, 65);
+        assert_eq!(usage[0].chunks, chunks.len() + 1);
+        // with less than a chunk to spare
+        assert_eq!(usage[0].malloced + usage[3].malloced, 31936);
+
+        // and there's no room for a page for anything bigger, however much
+        // room the smaller class had
+        assert!(slabs.alloc(&[b'x'; 100]).is_none());
+
+        // but freed chunks get reused
+        drop(small);
+        let again = slabs.alloc(b"again").unwrap();
+        assert_eq!(&*again, b"again");
+        assert_eq!(&*chunks[3], b"more");
+        assert_eq!(slabs.usage()[0].free_chunks, 0);
+        assert!(slabs.alloc(b"more").is_none());
+    }
+
+    #[test]
+    pub fn chained() {
+        let slabs = Slabs::new(SizeClasses::new(96, 2.0, 1024), 64 * 1024);
+        let top = slabs.classes().len();
+        let value: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        assert!(slabs.alloc_in(top, &value).is_none());
+
+        let chunks: Vec<Chunk> = value.chunks(1024)
+            .map(|part| slabs.alloc_in(top, part).unwrap())
+            .collect();
+        let pages: Vec<usize> = chunks.iter().map(|c| c.page()).collect();
+        let chained = Chunks::new(chunks);
+        assert_eq!(chained.len(), 2500);
+        assert_eq!(chained.class(), top);
+        assert_eq!(chained.capacity(), 3 * 1024);
+        assert_eq!(chained.to_vec(), value);
+        assert_eq!(&*chained.bytes(), &value[..]);
+        assert!(pages.iter().all(|&page| chained.uses_page(page)));
+
+        let single = Chunks::new(vec![slabs.alloc(b"small").unwrap()]);
+        assert_eq!(single.class(), 1);
+        assert_eq!(&*single.bytes(), b"small");
+
+        // and they all go back when it's dropped
+        drop(chained);
+        drop(single);
+        assert!(slabs.usage().iter().all(|u| u.free_chunks == u.chunks));
+    }
+}
@@ -0,0 +1,243 @@
+//! The file format for warm restarts: everything in the cache, written out on
+//! SIGTERM or a `snapshot` command and read back with `--restore`.
+//!
+//! It's a header with the last CAS unique we handed out, then one record for
+//! each item, then an end marker so that we can tell a complete file from one
+//! that was cut short. Numbers are big-endian and expiry and last-used times
+//! are absolute unix times, so they mean the same thing after the restart.
+//!
+//! writelog.rs uses the same header and records, plus ones for deletes and
+//! flushes, but never has an end marker: it's read up to the last record
+//! that was completely written
+
+use std::io;
+use std::io::{Read, Write};
+
+use lru::Timestamp;
+use store::{Flags, CasUnique};
+
+const MAGIC: &[u8] = b"RCSNAP\x00\x01";
+const END: u8 = 0;
+const ITEM: u8 = 1;
+const DELETE: u8 = 2;
+const FLUSH: u8 = 3;
+
+// the most we'll believe a length field, so that a corrupt file can't make
+// us try to allocate something enormous
+const MAX_LENGTH: usize = 1024 * 1024 * 1024;
+
+#[derive(Debug,PartialEq)]
+pub struct Item {
+    pub key: Vec<u8>,
+    pub data: Vec<u8>,
+    pub flags: Flags,
+    pub unique: CasUnique,
+    pub expires: Option<Timestamp>,
+    pub used: Timestamp,
+}
+
+// what's in a write log
+#[derive(Debug,PartialEq)]
+pub enum Record {
+    Item(Item),
+    Delete(Vec<u8>),
+    // flush_all, with when it takes effect. 0 means straight away
+    Flush(Timestamp),
+}
+
+pub fn write_header(out: &mut dyn Write,
+                    last_cas_id: CasUnique)
+                    -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    put_u64(out, last_cas_id)
+}
+
+pub fn write_item(out: &mut dyn Write,
+                  key: &[u8],
+                  data: &[u8],
+                  flags: Flags,
+                  unique: CasUnique,
+                  expires: Option<Timestamp>,
+                  used: Timestamp)
+                  -> io::Result<()> {
+    out.write_all(&[ITEM])?;
+    put_u32(out, key.len() as u32)?;
+    out.write_all(key)?;
+    put_u32(out, data.len() as u32)?;
+    out.write_all(data)?;
+    put_u32(out, flags)?;
+    put_u64(out, unique)?;
+    // nothing can expire at the epoch, so 0 means it never does
+    put_u32(out, expires.unwrap_or(0))?;
+    put_u32(out, used)
+}
+
+pub fn write_delete(out: &mut dyn Write, key: &[u8]) -> io::Result<()> {
+    out.write_all(&[DELETE])?;
+    put_u32(out, key.len() as u32)?;
+    out.write_all(key)
+}
+
+pub fn write_flush(out: &mut dyn Write, deadline: Timestamp) -> io::Result<()> {
+    out.write_all(&[FLUSH])?;
+    put_u32(out, deadline)
+}
+
+pub fn write_end(out: &mut dyn Write) -> io::Result<()> {
+    out.write_all(&[END])
+}
+
+pub fn read(input: &mut dyn Read) -> io::Result<(CasUnique, Vec<Item>)> {
+    // the last CAS unique and every item, in the order they were written
+    let last_cas_id = read_header(input)?;
+    let mut items = Vec::new();
+    loop {
+        match read_record(input).map_err(truncated)? {
+            None => return Ok((last_cas_id, items)),
+            Some(Record::Item(item)) => items.push(item),
+            Some(_) => return Err(invalid("corrupt snapshot record")),
+        }
+    }
+}
+
+pub fn read_log(input: &mut dyn Read) -> io::Result<(CasUnique, Vec<Record>)> {
+    // like read, but for a write log. If we went away in the middle of
+    // writing the last record then we never said it was done, so it's as
+    // though it never happened
+    let last_cas_id = read_header(input)?;
+    let mut records = Vec::new();
+    loop {
+        match read_record(input) {
+            Ok(Some(record)) => records.push(record),
+            Ok(None) => return Err(invalid("end marker in a write log")),
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok((last_cas_id, records))
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn read_header(input: &mut dyn Read) -> io::Result<CasUnique> {
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic).map_err(truncated)?;
+    if &magic[..] != MAGIC {
+        return Err(invalid("not a snapshot file"));
+    }
+    get_u64(input).map_err(truncated)
+}
+
+fn read_record(input: &mut dyn Read) -> io::Result<Option<Record>> {
+    // the next record, or None at the end marker. Running out of file part
+    // way through is an UnexpectedEof, which read turns into an InvalidData
+    let mut tag = [0];
+    input.read_exact(&mut tag)?;
+    match tag[0] {
+        END => Ok(None),
+        ITEM => {
+            let key = get_bytes(input)?;
+            let data = get_bytes(input)?;
+            let flags = get_u32(input)?;
+            let unique = get_u64(input)?;
+            let expires = match get_u32(input)? {
+                0 => None,
+                expires => Some(expires),
+            };
+            let used = get_u32(input)?;
+            Ok(Some(Record::Item(Item {
+                key,
+                data,
+                flags,
+                unique,
+                expires,
+                used,
+            })))
+        }
+        DELETE => Ok(Some(Record::Delete(get_bytes(input)?))),
+        FLUSH => Ok(Some(Record::Flush(get_u32(input)?))),
+        _ => Err(invalid("corrupt snapshot record")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn truncated(err: io::Error) -> io::Error {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        invalid("truncated snapshot file")
+    } else {
+        err
+    }
+}
+
+fn put_u32(out: &mut dyn Write, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_be_bytes())
+}
+
+fn put_u64(out: &mut dyn Write, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_be_bytes())
+}
+
+fn get_u32(input: &mut dyn Read) -> io::Result<u32> {
+    let mut buff = [0; 4];
+    input.read_exact(&mut buff)?;
+    Ok(u32::from_be_bytes(buff))
+}
+
+fn get_u64(input: &mut dyn Read) -> io::Result<u64> {
+    let mut buff = [0; 8];
+    input.read_exact(&mut buff)?;
+    Ok(u64::from_be_bytes(buff))
+}
+
+fn get_bytes(input: &mut dyn Read) -> io::Result<Vec<u8>> {
+    let length = get_u32(input)? as usize;
+    if length > MAX_LENGTH {
+        return Err(invalid("corrupt snapshot record"));
+    }
+    let mut buff = vec![0; length];
+    input.read_exact(&mut buff)?;
+    Ok(buff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn round_trip() {
+        let mut file = Vec::new();
+        write_header(&mut file, 42).unwrap();
+        write_item(&mut file, b"foo", b"bar", 7, 40, None, 1000).unwrap();
+        write_item(&mut file, b"baz", b"", 0, 41, Some(2000), 1001).unwrap();
+        write_end(&mut file).unwrap();
+
+        let (last_cas_id, items) = read(&mut &file[..]).unwrap();
+        assert_eq!(last_cas_id, 42);
+        assert_eq!(items,
+                   vec![Item {
+                            key: b"foo".to_vec(),
+                            data: b"bar".to_vec(),
+                            flags: 7,
+                            unique: 40,
+                            expires: None,
+                            used: 1000,
+                        },
+                        Item {
+                            key: b"baz".to_vec(),
+                            data: b"".to_vec(),
+                            flags: 0,
+                            unique: 41,
+                            expires: Some(2000),
+                            used: 1001,
+                        }]);
+
+        // anything cut short is no good, even at a record boundary
+        for length in 0..file.len() {
+            let err = read(&mut &file[..length]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        assert!(read(&mut &b"RCSNAP\x00\x02"[..]).is_err());
+    }
+}
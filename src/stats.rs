@@ -0,0 +1,242 @@
+//! Counters for the `stats` command. The store keeps a `Stats` that it bumps
+//! as it applies commands, and the server loops share a `ConnectionStats` to
+//! keep track of who's connected
+
+use std::mem;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc;
+
+use lru;
+use lru::Weight;
+
+pub type Stat = (String, String);
+
+#[derive(Debug,Default,Clone,PartialEq,Eq)]
+pub struct Stats {
+    pub cmd_get: u64,
+    pub cmd_set: u64,
+    pub cmd_flush: u64,
+    pub cmd_touch: u64,
+    pub get_hits: u64,
+    pub get_misses: u64,
+    pub delete_misses: u64,
+    pub delete_hits: u64,
+    pub incr_misses: u64,
+    pub incr_hits: u64,
+    pub decr_misses: u64,
+    pub decr_hits: u64,
+    pub cas_misses: u64,
+    pub cas_hits: u64,
+    pub cas_badval: u64,
+    pub touch_hits: u64,
+    pub touch_misses: u64,
+    pub total_items: u64,
+    // pages moved between slab classes, and the items that were thrown away
+    // to free them up
+    pub slabs_moved: u64,
+    pub slab_reassign_evictions: u64,
+}
+
+#[derive(Debug,Default)]
+pub struct ConnectionStats {
+    curr_connections: AtomicUsize,
+    total_connections: AtomicUsize,
+    // clients we hung up on for going quiet, or for making us hold too much
+    // for them. idle_kicks is a part of booted_connections
+    booted_connections: AtomicUsize,
+    idle_kicks: AtomicUsize,
+    // clients we turned away for there being too many already, and the
+    // times we had to stop accepting for a while because we'd run out of
+    // file descriptors
+    rejected_connections: AtomicUsize,
+    listen_disabled_num: AtomicUsize,
+}
+
+impl ConnectionStats {
+    pub fn admit(&self, max_conns: usize) -> bool {
+        // count a new client in if there's room for them. We take the place
+        // before we look, so that the acceptor and the workers letting
+        // clients go can't both think there's one more spot than there is
+        if self.curr_connections.fetch_add(1, Ordering::Relaxed) >= max_conns {
+            self.curr_connections.fetch_sub(1, Ordering::Relaxed);
+            return false;
+        }
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub fn disconnected(&self) {
+        self.curr_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn listen_disabled(&self) {
+        self.listen_disabled_num.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn booted(&self, idle: bool) {
+        // on top of them being disconnected
+        self.booted_connections.fetch_add(1, Ordering::Relaxed);
+        if idle {
+            self.idle_kicks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn report(&self, stats: &mut Vec<Stat>) {
+        push(stats,
+             "curr_connections",
+             self.curr_connections.load(Ordering::Relaxed));
+        push(stats,
+             "total_connections",
+             self.total_connections.load(Ordering::Relaxed));
+        push(stats,
+             "booted_connections",
+             self.booted_connections.load(Ordering::Relaxed));
+        push(stats, "idle_kicks", self.idle_kicks.load(Ordering::Relaxed));
+        push(stats,
+             "rejected_connections",
+             self.rejected_connections.load(Ordering::Relaxed));
+        push(stats,
+             "listen_disabled_num",
+             self.listen_disabled_num.load(Ordering::Relaxed));
+    }
+}
+
+impl Stats {
+    pub fn add(&mut self, other: &Stats) {
+        // for adding up the stats of several shards
+        self.cmd_get += other.cmd_get;
+        self.cmd_set += other.cmd_set;
+        self.cmd_flush += other.cmd_flush;
+        self.cmd_touch += other.cmd_touch;
+        self.get_hits += other.get_hits;
+        self.get_misses += other.get_misses;
+        self.delete_misses += other.delete_misses;
+        self.delete_hits += other.delete_hits;
+        self.incr_misses += other.incr_misses;
+        self.incr_hits += other.incr_hits;
+        self.decr_misses += other.decr_misses;
+        self.decr_hits += other.decr_hits;
+        self.cas_misses += other.cas_misses;
+        self.cas_hits += other.cas_hits;
+        self.cas_badval += other.cas_badval;
+        self.touch_hits += other.touch_hits;
+        self.touch_misses += other.touch_misses;
+        self.total_items += other.total_items;
+        self.slabs_moved += other.slabs_moved;
+        self.slab_reassign_evictions += other.slab_reassign_evictions;
+    }
+
+    pub fn report(&self, stats: &mut Vec<Stat>) {
+        push(stats, "cmd_get", self.cmd_get);
+        push(stats, "cmd_set", self.cmd_set);
+        push(stats, "cmd_flush", self.cmd_flush);
+        push(stats, "cmd_touch", self.cmd_touch);
+        push(stats, "get_hits", self.get_hits);
+        push(stats, "get_misses", self.get_misses);
+        push(stats, "delete_misses", self.delete_misses);
+        push(stats, "delete_hits", self.delete_hits);
+        push(stats, "incr_misses", self.incr_misses);
+        push(stats, "incr_hits", self.incr_hits);
+        push(stats, "decr_misses", self.decr_misses);
+        push(stats, "decr_hits", self.decr_hits);
+        push(stats, "cas_misses", self.cas_misses);
+        push(stats, "cas_hits", self.cas_hits);
+        push(stats, "cas_badval", self.cas_badval);
+        push(stats, "touch_hits", self.touch_hits);
+        push(stats, "touch_misses", self.touch_misses);
+    }
+}
+
+pub fn sizes_report<I>(stats: &mut Vec<Stat>, weights: I)
+    where I: Iterator<Item = Weight>
+{
+    let mut histogram: BTreeMap<Weight, u64> = BTreeMap::new();
+    for weight in weights {
+        *histogram.entry(lru::size_bucket(weight)).or_insert(0) += 1;
+    }
+    for (bucket, count) in histogram {
+        push(stats, &bucket.to_string(), count);
+    }
+}
+
+pub fn push<T: ToString>(stats: &mut Vec<Stat>, name: &str, value: T) {
+    stats.push((name.to_string(), value.to_string()));
+}
+
+fn format_timeval(tv: libc::timeval) -> String {
+    format!("{}.{:06}", tv.tv_sec, tv.tv_usec)
+}
+
+pub fn process_report(stats: &mut Vec<Stat>,
+                      version: &[u8],
+                      started: u32,
+                      now: u32) {
+    let pid = unsafe { libc::getpid() };
+
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    unsafe {
+        libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+    }
+
+    push(stats, "pid", pid);
+    push(stats, "uptime", now.saturating_sub(started));
+    push(stats, "time", now);
+    push(stats, "version", String::from_utf8_lossy(version));
+    push(stats, "pointer_size", 8 * mem::size_of::<usize>());
+    push(stats, "rusage_user", format_timeval(usage.ru_utime));
+    push(stats, "rusage_system", format_timeval(usage.ru_stime));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn connections() {
+        let connections = ConnectionStats::default();
+        assert!(connections.admit(2));
+        assert!(connections.admit(2));
+        assert!(!connections.admit(2));
+        connections.disconnected();
+        connections.booted(true);
+        connections.rejected();
+
+        let mut stats = Vec::new();
+        connections.report(&mut stats);
+        assert_eq!(stats,
+                   vec![("curr_connections".to_string(), "1".to_string()),
+                        ("total_connections".to_string(), "2".to_string()),
+                        ("booted_connections".to_string(), "1".to_string()),
+                        ("idle_kicks".to_string(), "1".to_string()),
+                        ("rejected_connections".to_string(), "1".to_string()),
+                        ("listen_disabled_num".to_string(), "0".to_string())]);
+    }
+
+    #[test]
+    pub fn sizes() {
+        let mut stats = Vec::new();
+        sizes_report(&mut stats, vec![10, 20, 40, 100].into_iter());
+        assert_eq!(stats,
+                   vec![("32".to_string(), "2".to_string()),
+                        ("64".to_string(), "1".to_string()),
+                        ("128".to_string(), "1".to_string())]);
+    }
+
+    #[test]
+    pub fn process() {
+        let mut stats = Vec::new();
+        process_report(&mut stats, b"1.2.3", 100, 150);
+        let names: Vec<&str> = stats.iter().map(|s| &s.0[..]).collect();
+        assert_eq!(names,
+                   vec!["pid", "uptime", "time", "version", "pointer_size",
+                        "rusage_user", "rusage_system"]);
+        assert_eq!(stats[1].1, "50");
+        assert_eq!(stats[3].1, "1.2.3");
+    }
+}
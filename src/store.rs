@@ -4,10 +4,28 @@
 #[allow(unused_imports)]
 use time;
 
+use std::io;
+use std::io::Write;
+use std::cmp;
 use std::str;
 use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 
 use lru;
+use slabs;
+use slabs::{Slabs, SizeClasses, Chunks, ClassUsage, Reassigned, Automover};
+use meta;
+use meta::{MetaFlags, MetaCode, MetaReturn, MetaResponse};
+use stats;
+use stats::Stats;
+use stats::ConnectionStats;
+use settings::Settings;
+use server::VERSION;
+use snapshot;
+use snapshot::Record;
+use writelog::WriteLog;
 
 // Keys as we get them from the client
 pub type Key<'a> = &'a [u8];
@@ -20,8 +38,8 @@ pub type ReturnedKey<'a> = &'a [u8];
 
 // Data as we get it from the client
 pub type Data<'a> = &'a [u8];
-// Data as we store it (copied from the client connection's memory)
-pub type StoredData = Vec<u8>;
+// Data as we store it (copied from the client connection's memory into a slab)
+pub type StoredData = Chunks;
 // Data as we return it to a client (copied for now)
 pub type ReturnedData = Vec<u8>;
 
@@ -52,7 +70,7 @@ pub enum SetterType {
     Cas(CasUnique),
 }
 
-#[derive(Debug,PartialEq,Eq)]
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
 pub enum GetterType {
     Get,
     Gets,
@@ -64,6 +82,26 @@ pub enum IncrementerType {
     Decr,
 }
 
+#[derive(Debug,PartialEq,Eq)]
+pub enum StatsType {
+    General,
+    Items,
+    Slabs,
+    Sizes,
+    Settings,
+}
+
+// lru_crawler's subcommands
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum CrawlerCommand {
+    Enable,
+    Disable,
+    // microseconds to wait between each stretch of crawling
+    Sleep(u32),
+    // how many items to reclaim from each class per crawl. 0 is all of them
+    ToCrawl(u32),
+}
+
 #[derive(Debug,PartialEq,Eq)]
 pub enum ServerCommand<'a> {
     Setter {
@@ -77,6 +115,11 @@ pub enum ServerCommand<'a> {
         getter: GetterType,
         keys: Vec<Key<'a>>,
     },
+    GetAndTouch {
+        getter: GetterType,
+        ttl: Ttl,
+        keys: Vec<Key<'a>>,
+    },
     Delete {
         key: Key<'a>,
     },
@@ -89,11 +132,48 @@ pub enum ServerCommand<'a> {
         key: Key<'a>,
         value: IncrValue,
     },
-    FlushAll,
+    FlushAll {
+        delay: Ttl,
+    },
+    SlabsReassign {
+        src: usize,
+        dst: usize,
+    },
+    SlabsAutomove(usize),
+    LruCrawler(CrawlerCommand),
+    // None for every class
+    MetaDump {
+        class: Option<usize>,
+    },
+    // write everything to the snapshot file, for a warm restart
+    Snapshot,
     Bad(&'a [u8]),
     Quit,
     Version,
     Verbosity,
+    Noop,
+    Stats(StatsType),
+    MetaGet {
+        key: Key<'a>,
+        flags: MetaFlags<'a>,
+    },
+    MetaSet {
+        key: Key<'a>,
+        data: Data<'a>,
+        flags: MetaFlags<'a>,
+    },
+    MetaDelete {
+        key: Key<'a>,
+        flags: MetaFlags<'a>,
+    },
+    MetaArithmetic {
+        key: Key<'a>,
+        flags: MetaFlags<'a>,
+    },
+    MetaDebug {
+        key: Key<'a>,
+        flags: MetaFlags<'a>,
+    },
 }
 
 #[derive(Debug,PartialEq,Eq)]
@@ -134,9 +214,19 @@ pub enum Response<'a> {
     },
     Version,
     TooBig,
+    Noop,
+    Stats {
+        stats: Vec<stats::Stat>,
+    },
+    Meta(MetaResponse<'a>),
+    Reassign(Reassigned),
+    MetaDebug {
+        key: ReturnedKey<'a>,
+        stats: Vec<stats::Stat>,
+    },
 }
 
-fn forgetful_parse_int(current_data: &StoredData) -> Option<IncrValue> {
+fn forgetful_parse_int(current_data: &[u8]) -> Option<IncrValue> {
     // try to interpret it as an int
     let as_string = str::from_utf8(current_data);
     if as_string.is_err() {
@@ -157,15 +247,51 @@ enum _IncrSubResult {
     NewValue(IncrValue, Option<lru::Timestamp>, Flags),
 }
 
+// why allocate couldn't find room for a value
+enum Refused {
+    TooBig,
+    // the eviction policy decided it wasn't worth throwing anything away for
+    Rejected,
+    NoMemory,
+}
+
+impl Refused {
+    fn response<'a>(self) -> Response<'a> {
+        match self {
+            Refused::TooBig => Response::TooBig,
+            // as far as the client's concerned it was stored and then
+            // evicted straight away
+            Refused::Rejected => Response::Stored,
+            Refused::NoMemory => Response::ServerError { message: NO_MEMORY },
+        }
+    }
+}
+
+// what mg copies out of an entry before it lets go of the LRU
+struct MetaItem {
+    access: lru::Access,
+    expires: Option<lru::Timestamp>,
+    flags: Flags,
+    unique: CasUnique,
+    size: usize,
+    data: Option<ReturnedData>,
+    stale: bool,
+    won: bool,
+    already_won: bool,
+}
+
 // the number of seconds in a TTL after which we start recognising it as a
 // timestamp instead. This is a magic number used by memcached so we're cloning
 // its behaviour here. used by wrap_ttl
 const MAGIC_DATE: Ttl = 60 * 60 * 24 * 30;
 
-// right now these are enforced here but it would be nice if the parser could do
-// some of it too
-const MAX_KEY: usize = 255;
-const MAX_DATA: usize = 1024 * 1024; // 1MB
+// these are enforced here, and the parser also refuses storage commands that
+// break them before it waits for their payloads
+pub const MAX_KEY: usize = 255;
+pub const MAX_DATA: usize = 1024 * 1024; // 1MB
+// how far -I can take that
+pub const MIN_DATA_LIMIT: usize = 1024;
+pub const MAX_DATA_LIMIT: usize = 128 * 1024 * 1024;
 
 pub fn wrap_ttl(ttl: Ttl, now: Ttl) -> Option<Ttl> {
     if ttl == 0 {
@@ -177,8 +303,129 @@ pub fn wrap_ttl(ttl: Ttl, now: Ttl) -> Option<Ttl> {
     }
 }
 
+fn remaining_ttl(expires: Option<lru::Timestamp>, now: Ttl) -> i64 {
+    // how meta commands report TTLs. -1 means it never expires
+    match expires {
+        None => -1,
+        Some(ts) => ts as i64 - now as i64,
+    }
+}
+
+fn url_encode(key: &[u8]) -> String {
+    // keys can have anything but whitespace and control characters in them,
+    // so metadump escapes everything but the characters URLs leave alone
+    let mut encoded = String::with_capacity(key.len());
+    for &byte in key {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' |
+            b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// what a command changes, for the write log
+enum Logged {
+    Nothing,
+    Keys(Vec<StoredKey>),
+    // whatever a get-and-touch found
+    Touched,
+    // flush_all, with when it takes effect
+    Flush(Ttl),
+}
+
+fn logged_change(command: &ServerCommand, now: Ttl) -> Logged {
+    let meta = |key, flags: &MetaFlags| match meta_key(key, flags.base64) {
+        Some(skey) => Logged::Keys(vec![skey]),
+        None => Logged::Nothing,
+    };
+    match *command {
+        ServerCommand::Setter { key, .. } |
+        ServerCommand::Delete { key } |
+        ServerCommand::Touch { key, .. } |
+        ServerCommand::Incrementer { key, .. } => {
+            Logged::Keys(vec![key.to_vec()])
+        }
+        ServerCommand::GetAndTouch { .. } => Logged::Touched,
+        ServerCommand::MetaSet { key, ref flags, .. } |
+        ServerCommand::MetaDelete { key, ref flags } |
+        ServerCommand::MetaArithmetic { key, ref flags } => meta(key, flags),
+        // only a get that updates the ttl or creates the item changes it
+        ServerCommand::MetaGet { key, ref flags }
+            if flags.ttl.is_some() || flags.vivify.is_some() => {
+            meta(key, flags)
+        }
+        ServerCommand::FlushAll { delay } => {
+            Logged::Flush(wrap_ttl(delay, now).unwrap_or(0))
+        }
+        _ => Logged::Nothing,
+    }
+}
+
+fn changed(response: &Response) -> bool {
+    // whether a mutation did anything worth writing down. A meta command
+    // only did if it says HD or VA, or if mg created the item on a miss,
+    // which is how it comes to hand out a win token with EN
+    match *response {
+        Response::Stored |
+        Response::Deleted |
+        Response::Touched |
+        Response::Incr { .. } => true,
+        Response::Meta(ref meta) => {
+            match meta.code {
+                MetaCode::Stored | MetaCode::Value => true,
+                _ => meta.returned.contains(&MetaReturn::Win),
+            }
+        }
+        _ => false,
+    }
+}
+
+fn meta_key(ckey: Key, base64: bool) -> Option<StoredKey> {
+    meta::decode_key(ckey, base64)
+        .and_then(|skey| if skey.is_empty() || skey.len() > MAX_KEY {
+            None
+        } else {
+            Some(skey)
+        })
+}
+
+fn meta_returns<'a>(ckey: Key<'a>,
+                    flags: &MetaFlags<'a>)
+                    -> Vec<MetaReturn<'a>> {
+    // the parts of a meta response that don't depend on the item
+    let mut returned = Vec::new();
+    if flags.return_key {
+        returned.push(MetaReturn::Key(ckey));
+        if flags.base64 {
+            returned.push(MetaReturn::Base64);
+        }
+    }
+    if let Some(opaque) = flags.opaque {
+        returned.push(MetaReturn::Opaque(opaque));
+    }
+    returned
+}
+
+fn meta_response<'a>(code: MetaCode,
+                     returned: Vec<MetaReturn<'a>>,
+                     data: Option<ReturnedData>,
+                     silent: bool)
+                     -> Response<'a> {
+    Response::Meta(MetaResponse {
+        code,
+        returned,
+        data,
+        silent,
+    })
+}
+
+const BAD_META_KEY: &[u8] = b"bad command line format";
+const NO_MEMORY: &[u8] = b"out of memory storing object";
+
 #[cfg(not(test))]
-fn epoch_time() -> Ttl {
+pub fn epoch_time() -> Ttl {
     time::get_time().sec as Ttl
 }
 #[cfg(test)]
@@ -190,29 +437,153 @@ pub fn epoch_time() -> Ttl {
 #[derive(Debug)]
 pub struct Store {
     store: lru::LruCache<StoredKey, DataContainer>,
+    // where CAS uniques come from. Shards share this so that they're unique
+    // across the whole server, but each remembers the last one it handed out
+    cas_ids: Arc<AtomicU64>,
     last_cas_id: CasUnique,
+    stats: Stats,
+    connections: Arc<ConnectionStats>,
+    started: Ttl,
+    settings: Settings,
+    // where the values live, and so what decides how much we can hold. The
+    // LRU has a pool for each of its classes, and we make room in a class by
+    // evicting from its pool
+    slabs: Slabs,
+    mover: Automover,
+    // where we write down every change, if we're doing that. Shards share it
+    log: Option<Arc<WriteLog>>,
 }
 
 impl Store {
+    // a store on its own, for tests. The server's are shards of a
+    // ShardedStore
+    #[cfg(test)]
     pub fn new(capacity: Capacity) -> Store {
+        Store::with_settings(Settings {
+            maxbytes: capacity,
+            ..Settings::default()
+        })
+    }
+
+    #[cfg(test)]
+    pub fn with_settings(settings: Settings) -> Store {
+        let capacity = settings.maxbytes;
+        Store::shard(settings,
+                     capacity,
+                     Arc::new(ConnectionStats::default()),
+                     Arc::new(AtomicU64::new(0)))
+    }
+
+    pub fn shard(settings: Settings,
+                 capacity: Capacity,
+                 connections: Arc<ConnectionStats>,
+                 cas_ids: Arc<AtomicU64>)
+                 -> Store {
+        // one of several stores that each hold a part of the keyspace, and
+        // `capacity` of the memory budget
+        let classes = SizeClasses::new(settings.chunk_size,
+                                       settings.growth_factor,
+                                       cmp::min(settings.item_size_max,
+                                                slabs::SLAB_CHUNK_MAX));
         Store {
-            store: lru::LruCache::new(capacity),
+            store: lru::LruCache::with_pools(usize::MAX,
+                                             classes.len(),
+                                             settings.eviction),
+            cas_ids,
             last_cas_id: 0,
+            stats: Stats::default(),
+            connections,
+            started: epoch_time(),
+            settings,
+            slabs: Slabs::new(classes, capacity),
+            mover: Automover::default(),
+            log: None,
         }
     }
 
+    #[cfg(test)]
+    pub fn connection_stats(&self) -> Arc<ConnectionStats> {
+        // ShardedStore hands these to the server loops to keep up to date
+        self.connections.clone()
+    }
+
     fn make_cas_id(&mut self) -> CasUnique {
-        self.last_cas_id += 1;
+        self.last_cas_id = self.cas_ids.fetch_add(1, Ordering::Relaxed) + 1;
+        self.last_cas_id
+    }
+
+    pub fn last_cas_id(&self) -> CasUnique {
+        // the unique of the most recently mutated item
         self.last_cas_id
     }
 
+    pub fn set_log(&mut self, log: Arc<WriteLog>) {
+        self.log = Some(log);
+    }
+
     pub fn apply<'a>(&mut self, command: ServerCommand<'a>) -> Response<'a> {
+        // with a write log, whatever state a mutation leaves its keys in gets
+        // written down once it's done
+        let logged = match self.log {
+            Some(_) => logged_change(&command, epoch_time()),
+            None => Logged::Nothing,
+        };
+        let response = self.execute(command);
+        match logged {
+            Logged::Nothing => (),
+            Logged::Keys(ref keys) if changed(&response) => {
+                for key in keys {
+                    self.log_key(key);
+                }
+            }
+            Logged::Keys(_) => (),
+            Logged::Touched => {
+                match response {
+                    Response::Data { ref responses } |
+                    Response::Gets { ref responses } => {
+                        for found in responses {
+                            self.log_key(&found.key.to_vec());
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            Logged::Flush(deadline) => {
+                if let Some(ref log) = self.log {
+                    log.flush_all(deadline);
+                }
+            }
+        }
+        response
+    }
+
+    fn log_key(&self, key: &StoredKey) {
+        let log = match self.log {
+            Some(ref log) => log,
+            None => return,
+        };
+        match self.store.peek(key, epoch_time()) {
+            Some(entry) => {
+                log.item(key,
+                         &entry.data.data.bytes(),
+                         entry.data.flags,
+                         entry.data.unique,
+                         entry.expires,
+                         entry.used())
+            }
+            None => log.delete(key),
+        }
+    }
+
+    fn execute<'a>(&mut self, command: ServerCommand<'a>) -> Response<'a> {
         let now = epoch_time(); // TODO lazy?
 
         match command {
             ServerCommand::Setter { key: ckey, data: cdata, .. }
                 if ckey.len() > MAX_KEY ||
-                   cdata.len() > MAX_DATA => Response::TooBig,
+                   cdata.len() > self.settings.item_size_max => {
+                Response::TooBig
+            }
             ServerCommand::Setter { setter,
                                     key: ckey,
                                     data: cdata,
@@ -222,25 +593,17 @@ impl Store {
                 let ttl = wrap_ttl(cttl, now);
                 let skey = ckey.to_vec();
 
-                let container = |data: &[u8], flags| {
-                    DataContainer {
-                        data: data.to_vec(), // does a copy
-                        flags: flags,
-                        unique: new_cas,
-                    }
-                };
+                self.stats.cmd_set += 1;
 
-                match setter {
+                let response = match setter {
                     SetterType::Add if self.store.contains(&skey, now) => {
                         Response::NotStored
                     }
                     SetterType::Add | SetterType::Set => {
-                        self.store.set(skey, container(cdata, flags), ttl, now);
-                        Response::Stored
+                        self.store_value(skey, cdata, flags, new_cas, ttl, now)
                     }
                     SetterType::Replace if self.store.contains(&skey, now) => {
-                        self.store.set(skey, container(cdata, flags), ttl, now);
-                        Response::Stored
+                        self.store_value(skey, cdata, flags, new_cas, ttl, now)
                     }
                     SetterType::Replace => Response::NotStored,
                     SetterType::Append if self.store.contains(&skey, now) => {
@@ -256,17 +619,18 @@ impl Store {
                             let new_size = cdata.len() +
                                            current_container.data.len();
                             let mut new_vec = Vec::with_capacity(new_size);
-                            new_vec.extend_from_slice(&current_container.data);
+                            current_container.data.copy_into(&mut new_vec);
                             new_vec.extend_from_slice(cdata);
                             (new_vec,
                              current_entry.expires,
                              current_container.flags)
                         };
-                        self.store.set(skey,
-                                       container(&new_vec, old_flags),
-                                       old_ttl,
-                                       now);
-                        Response::Stored
+                        self.store_value(skey,
+                                         &new_vec,
+                                         old_flags,
+                                         new_cas,
+                                         old_ttl,
+                                         now)
                     }
                     SetterType::Append => Response::NotStored,
                     SetterType::Prepend if self.store.contains(&skey, now) => {
@@ -278,46 +642,57 @@ impl Store {
                                            current_container.data.len();
                             let mut new_vec = Vec::with_capacity(new_size);
                             new_vec.extend_from_slice(cdata);
-                            new_vec.extend_from_slice(&current_container.data);
+                            current_container.data.copy_into(&mut new_vec);
                             (new_vec,
                              current_entry.expires,
                              current_container.flags)
                         };
-                        self.store.set(skey,
-                                       container(&new_vec, old_flags),
-                                       old_ttl,
-                                       now);
-                        Response::Stored
+                        self.store_value(skey,
+                                         &new_vec,
+                                         old_flags,
+                                         new_cas,
+                                         old_ttl,
+                                         now)
                     }
                     SetterType::Prepend => Response::NotStored,
                     SetterType::Cas(_) if !self.store.contains(&skey, now) => {
+                        self.stats.cas_misses += 1;
                         Response::NotFound
                     }
                     SetterType::Cas(unique) if (self.store
                         .fast_get(&skey, now)
                         .map(|cont| cont.unique) ==
                                                 Some(unique)) => {
-                        self.store.set(skey, container(cdata, flags), ttl, now);
-                        Response::Stored
+                        self.stats.cas_hits += 1;
+                        self.store_value(skey, cdata, flags, new_cas, ttl, now)
                     }
                     SetterType::Cas(_) => {
                         // n.b. failed cas updates don't update the lru
+                        self.stats.cas_badval += 1;
                         Response::Exists
                     }
+                };
+                if response == Response::Stored {
+                    self.stats.total_items += 1;
                 }
+                response
             }
 
             ServerCommand::Getter { getter, keys } => {
                 let mut found = Vec::with_capacity(keys.len());
                 for ckey in keys {
                     let skey = ckey.to_vec();
+                    self.stats.cmd_get += 1;
                     if let Some(item) = self.store.get(&skey, now) {
                         found.push(SingleGetResponse {
                             key: ckey,
-                            data: item.data.clone(), // does a copy
+                            data: item.data.to_vec(), // does a copy
                             flags: item.flags,
                             unique: item.unique,
                         });
+                        self.stats.get_hits += 1;
+                    } else {
+                        self.stats.get_misses += 1;
                     }
                 }
                 // and turn that into the right result format for the request
@@ -327,12 +702,47 @@ impl Store {
                     GetterType::Gets => Response::Gets { responses: found },
                 }
             }
+            ServerCommand::GetAndTouch { getter, ttl: cttl, keys } => {
+                let ttl = wrap_ttl(cttl, now);
+                let mut found = Vec::with_capacity(keys.len());
+                for ckey in keys {
+                    let skey = ckey.to_vec();
+                    // it's a get as well as a touch, so it counts as both
+                    self.stats.cmd_get += 1;
+                    self.stats.cmd_touch += 1;
+                    // we hold the store for both halves, so nobody can see
+                    // (or expire) the item in between
+                    let touched = self.store.touch(&skey, ttl, now);
+                    match self.store.fast_get(&skey, now) {
+                        Some(item) if touched => {
+                            found.push(SingleGetResponse {
+                                key: ckey,
+                                data: item.data.to_vec(), // does a copy
+                                flags: item.flags,
+                                unique: item.unique,
+                            });
+                            self.stats.get_hits += 1;
+                            self.stats.touch_hits += 1;
+                        }
+                        _ => {
+                            self.stats.get_misses += 1;
+                            self.stats.touch_misses += 1;
+                        }
+                    }
+                }
+                match getter {
+                    GetterType::Get => Response::Data { responses: found },
+                    GetterType::Gets => Response::Gets { responses: found },
+                }
+            }
             ServerCommand::Delete { key: ckey } => {
                 let skey = ckey.to_vec();
 
                 if self.store.delete(&skey) {
+                    self.stats.delete_hits += 1;
                     Response::Deleted
                 } else {
+                    self.stats.delete_misses += 1;
                     Response::NotFound
                 }
             }
@@ -340,10 +750,14 @@ impl Store {
                 let skey = ckey.to_vec();
                 let ttl = wrap_ttl(cttl, now);
 
+                self.stats.cmd_touch += 1;
+
                 if self.store.contains(&skey, now) {
                     self.store.touch(&skey, ttl, now);
+                    self.stats.touch_hits += 1;
                     Response::Touched
                 } else {
+                    self.stats.touch_misses += 1;
                     Response::NotFound
                 }
             }
@@ -356,7 +770,8 @@ impl Store {
                     Some(full_entry) => {
                         let item = &(*full_entry).data;
                         let current_data = &(*item).data;
-                        let as_int = forgetful_parse_int(current_data);
+                        let as_int =
+                            forgetful_parse_int(&current_data.bytes());
                         match as_int {
                             None => _IncrSubResult::BadInt,
                             Some(current_int) => {
@@ -378,6 +793,21 @@ impl Store {
                         }
                     }
                 };
+                match (&isr, &incrementer) {
+                    (&_IncrSubResult::NotFound, &IncrementerType::Incr) => {
+                        self.stats.incr_misses += 1
+                    }
+                    (&_IncrSubResult::NotFound, &IncrementerType::Decr) => {
+                        self.stats.decr_misses += 1
+                    }
+                    (&_IncrSubResult::NewValue(..), &IncrementerType::Incr) => {
+                        self.stats.incr_hits += 1
+                    }
+                    (&_IncrSubResult::NewValue(..), &IncrementerType::Decr) => {
+                        self.stats.decr_hits += 1
+                    }
+                    (&_IncrSubResult::BadInt, _) => {}
+                }
                 match isr {
                     _IncrSubResult::NotFound => Response::NotFound,
                     _IncrSubResult::BadInt => {
@@ -388,32 +818,740 @@ impl Store {
                     _IncrSubResult::NewValue(new_int, sttl, flags) => {
                         let re_str = new_int.to_string();
                         let re_bytes = re_str.as_bytes();
-                        let new_data = re_bytes.to_vec();
-                        let new_container = DataContainer {
-                            data: new_data.to_vec(),
-                            flags: flags,
-                            unique: new_cas,
-                        };
-                        self.store.set(skey, new_container, sttl, now);
-                        Response::Incr { value: new_int }
+                        match self.store_value(skey,
+                                               re_bytes,
+                                               flags,
+                                               new_cas,
+                                               sttl,
+                                               now) {
+                            Response::Stored => {
+                                Response::Incr { value: new_int }
+                            }
+                            error => error,
+                        }
                     }
                 }
             }
-            ServerCommand::FlushAll => {
-                self.store.clear(); // weeeeee
+            ServerCommand::FlushAll { delay } => {
+                self.stats.cmd_flush += 1;
+                self.flush(delay, now);
+                Response::Ok
+            }
+            ServerCommand::Stats(stats_type) => {
+                Response::Stats {
+                    stats: stats_report(&[self], stats_type, now),
+                }
+            }
+            ServerCommand::MetaGet { key, flags } => {
+                self.meta_get(key, flags, now)
+            }
+            ServerCommand::MetaSet { key, data, flags } => {
+                self.meta_set(key, data, flags, now)
+            }
+            ServerCommand::MetaDelete { key, flags } => {
+                self.meta_delete(key, flags, now)
+            }
+            ServerCommand::MetaArithmetic { key, flags } => {
+                self.meta_arithmetic(key, flags, now)
+            }
+            ServerCommand::MetaDebug { key, flags } => {
+                self.meta_debug(key, flags, now)
+            }
+            ServerCommand::SlabsReassign { src, dst } => {
+                Response::Reassign(self.reassign(src, dst))
+            }
+            ServerCommand::SlabsAutomove(mode) => {
+                self.settings.slab_automove = mode;
+                Response::Ok
+            }
+            ServerCommand::LruCrawler(crawler) => {
+                match crawler {
+                    CrawlerCommand::Enable => self.settings.lru_crawler = true,
+                    CrawlerCommand::Disable => {
+                        self.settings.lru_crawler = false
+                    }
+                    CrawlerCommand::Sleep(sleep) => {
+                        self.settings.lru_crawler_sleep = sleep
+                    }
+                    CrawlerCommand::ToCrawl(tocrawl) => {
+                        self.settings.lru_crawler_tocrawl = tocrawl
+                    }
+                }
                 Response::Ok
             }
             ServerCommand::Bad(_) => Response::Error,
             ServerCommand::Version => Response::Version,
             // we ignore this, we just support it to make memcapable happy
             ServerCommand::Verbosity => Response::Ok,
-            ServerCommand::Quit => {
+            ServerCommand::Noop => Response::Noop,
+            ServerCommand::Quit |
+            ServerCommand::MetaDump { .. } => {
                 unreachable!("this should have been handled by the server dispatch loop")
             }
+            ServerCommand::Snapshot => {
+                unreachable!("snapshots are of every shard at once")
+            }
 
         }
     }
 
+    fn meta_cas_mismatch(&self,
+                         skey: &StoredKey,
+                         unique: Option<CasUnique>,
+                         now: Ttl)
+                         -> Option<MetaCode> {
+        // for the meta commands that take a C flag but don't have a cas
+        // flavour of their own to hand it to
+        match unique {
+            None => None,
+            Some(unique) => {
+                match self.store.fast_get(skey, now) {
+                    None => Some(MetaCode::NotFound),
+                    Some(item) if item.unique != unique => {
+                        Some(MetaCode::Exists)
+                    }
+                    Some(_) => None,
+                }
+            }
+        }
+    }
+
+    fn meta_get<'a>(&mut self,
+                    ckey: Key<'a>,
+                    flags: MetaFlags<'a>,
+                    now: Ttl)
+                    -> Response<'a> {
+        let skey = match meta_key(ckey, flags.base64) {
+            Some(skey) => skey,
+            None => return Response::ClientError { message: BAD_META_KEY },
+        };
+        let mut returned = meta_returns(ckey, &flags);
+
+        self.stats.cmd_get += 1;
+
+        // with N, a miss creates an empty placeholder and the client that
+        // caused that is the one that gets to fill it in
+        let mut created = false;
+        if let Some(cttl) = flags.vivify {
+            if !self.store.contains(&skey, now) {
+                let unique = self.make_cas_id();
+                match self.allocate(&skey, b"", now) {
+                    Ok(chunk) => {
+                        let placeholder = DataContainer {
+                            data: chunk,
+                            flags: 0,
+                            unique,
+                        };
+                        created = self.store.set(skey.clone(),
+                                                 placeholder,
+                                                 wrap_ttl(cttl, now),
+                                                 now);
+                    }
+                    // the eviction policy wouldn't let it in, so this is
+                    // just a miss
+                    Err(Refused::Rejected) => (),
+                    Err(refused) => return refused.response(),
+                }
+            }
+        }
+
+        let found = self.store
+            .lookup(&skey, now, !flags.no_bump)
+            .map(|(access, entry)| {
+                // exactly one client gets a win token to go and recache the
+                // item: whoever created it, or the first to see it after it
+                // went stale or (if they asked) got close to expiring.
+                // Everybody else keeps getting the old value until then
+                let already_won = entry.win_token_sent;
+                let recache = match (flags.recache, entry.expires) {
+                    (Some(recache), Some(expires)) => expires < now + recache,
+                    _ => false,
+                };
+                let won = !already_won && (created || entry.stale || recache);
+                if won {
+                    entry.win_token_sent = true;
+                }
+
+                MetaItem {
+                    access,
+                    expires: entry.expires,
+                    flags: entry.data.flags,
+                    unique: entry.data.unique,
+                    size: entry.data.data.len(),
+                    data: if flags.return_value {
+                        Some(entry.data.data.to_vec()) // does a copy
+                    } else {
+                        None
+                    },
+                    stale: entry.stale,
+                    won,
+                    already_won,
+                }
+            });
+        let mut item = match found {
+            None => {
+                self.stats.get_misses += 1;
+                return meta_response(MetaCode::Miss,
+                                     returned,
+                                     None,
+                                     flags.quiet);
+            }
+            Some(item) => item,
+        };
+        if created {
+            self.stats.get_misses += 1;
+        } else {
+            self.stats.get_hits += 1;
+        }
+
+        if let Some(cttl) = flags.ttl {
+            item.expires = wrap_ttl(cttl, now);
+            self.store.touch(&skey, item.expires, now);
+            self.stats.cmd_touch += 1;
+            self.stats.touch_hits += 1;
+        }
+
+        if flags.return_cas {
+            returned.push(MetaReturn::Cas(item.unique));
+        }
+        if flags.return_flags {
+            returned.push(MetaReturn::Flags(item.flags));
+        }
+        if flags.return_hit {
+            returned.push(MetaReturn::Hit(item.access.fetched));
+        }
+        if flags.return_last_access {
+            let idle = now.saturating_sub(item.access.used);
+            returned.push(MetaReturn::LastAccess(idle));
+        }
+        if flags.return_size {
+            returned.push(MetaReturn::Size(item.size));
+        }
+        if flags.return_ttl {
+            returned.push(MetaReturn::Ttl(remaining_ttl(item.expires, now)));
+        }
+        if item.already_won {
+            returned.push(MetaReturn::AlreadyWon);
+        }
+        if item.stale {
+            returned.push(MetaReturn::Stale);
+        }
+        if item.won {
+            returned.push(MetaReturn::Win);
+        }
+
+        let code = if item.data.is_some() {
+            MetaCode::Value
+        } else {
+            MetaCode::Stored
+        };
+        meta_response(code, returned, item.data, false)
+    }
+
+    fn meta_set<'a>(&mut self,
+                    ckey: Key<'a>,
+                    data: Data<'a>,
+                    flags: MetaFlags<'a>,
+                    now: Ttl)
+                    -> Response<'a> {
+        let skey = match meta_key(ckey, flags.base64) {
+            Some(skey) => skey,
+            None => return Response::ClientError { message: BAD_META_KEY },
+        };
+        let mut returned = meta_returns(ckey, &flags);
+
+        let setter = match flags.mode.unwrap_or(b'S') {
+            b'S' | b's' => SetterType::Set,
+            b'E' | b'e' => SetterType::Add,
+            b'R' | b'r' => SetterType::Replace,
+            b'A' | b'a' => SetterType::Append,
+            b'P' | b'p' => SetterType::Prepend,
+            _ => {
+                return Response::ClientError {
+                    message: b"invalid mode for ms",
+                }
+            }
+        };
+        let setter = match (setter, flags.compare_cas) {
+            (SetterType::Set, Some(unique)) |
+            (SetterType::Replace, Some(unique)) => SetterType::Cas(unique),
+            (setter, unique) => {
+                if let Some(code) = self.meta_cas_mismatch(&skey, unique, now) {
+                    return meta_response(code, returned, None, false);
+                }
+                setter
+            }
+        };
+
+        let code = match self.execute(ServerCommand::Setter {
+            setter,
+            key: &skey,
+            data,
+            ttl: flags.ttl.unwrap_or(0),
+            flags: flags.client_flags.unwrap_or(0),
+        }) {
+            Response::Stored => MetaCode::Stored,
+            Response::NotStored => MetaCode::NotStored,
+            Response::Exists => MetaCode::Exists,
+            Response::NotFound => MetaCode::NotFound,
+            Response::TooBig => return Response::TooBig,
+            // the only thing that can go wrong with storing it
+            Response::ServerError { .. } => {
+                return Response::ServerError { message: NO_MEMORY }
+            }
+            other => unreachable!("unexpected setter response {:?}", other),
+        };
+
+        if code == MetaCode::Stored && flags.return_cas {
+            returned.push(MetaReturn::Cas(self.last_cas_id));
+        }
+        let silent = flags.quiet && code == MetaCode::Stored;
+        meta_response(code, returned, None, silent)
+    }
+
+    fn meta_delete<'a>(&mut self,
+                       ckey: Key<'a>,
+                       flags: MetaFlags<'a>,
+                       now: Ttl)
+                       -> Response<'a> {
+        let skey = match meta_key(ckey, flags.base64) {
+            Some(skey) => skey,
+            None => return Response::ClientError { message: BAD_META_KEY },
+        };
+        let returned = meta_returns(ckey, &flags);
+
+        if let Some(code) = self.meta_cas_mismatch(&skey,
+                                                   flags.compare_cas,
+                                                   now) {
+            return meta_response(code, returned, None, false);
+        }
+
+        let code = if flags.invalidate {
+            // rather than deleting it, keep serving it as stale until somebody
+            // recaches it. It gets a new cas so that anybody holding the old
+            // one can't write over it
+            let new_cas = self.make_cas_id();
+            let found = match self.store.lookup(&skey, now, false) {
+                Some((_, entry)) => {
+                    entry.stale = true;
+                    entry.win_token_sent = false;
+                    entry.data.unique = new_cas;
+                    true
+                }
+                None => false,
+            };
+            if found {
+                if let Some(cttl) = flags.ttl {
+                    self.store.touch(&skey, wrap_ttl(cttl, now), now);
+                }
+                self.stats.delete_hits += 1;
+                MetaCode::Stored
+            } else {
+                self.stats.delete_misses += 1;
+                MetaCode::NotFound
+            }
+        } else {
+            match self.execute(ServerCommand::Delete { key: &skey }) {
+                Response::Deleted => MetaCode::Stored,
+                Response::NotFound => MetaCode::NotFound,
+                other => unreachable!("unexpected delete response {:?}", other),
+            }
+        };
+        let silent = flags.quiet &&
+                     (code == MetaCode::Stored || code == MetaCode::NotFound);
+        meta_response(code, returned, None, silent)
+    }
+
+    fn meta_arithmetic<'a>(&mut self,
+                           ckey: Key<'a>,
+                           flags: MetaFlags<'a>,
+                           now: Ttl)
+                           -> Response<'a> {
+        let skey = match meta_key(ckey, flags.base64) {
+            Some(skey) => skey,
+            None => return Response::ClientError { message: BAD_META_KEY },
+        };
+        let mut returned = meta_returns(ckey, &flags);
+
+        let incrementer = match flags.mode.unwrap_or(b'I') {
+            b'I' | b'i' | b'+' => IncrementerType::Incr,
+            b'D' | b'd' | b'-' => IncrementerType::Decr,
+            _ => {
+                return Response::ClientError {
+                    message: b"invalid mode for ma",
+                }
+            }
+        };
+
+        if let Some(code) = self.meta_cas_mismatch(&skey,
+                                                   flags.compare_cas,
+                                                   now) {
+            return meta_response(code, returned, None, false);
+        }
+
+        let value = match self.execute(ServerCommand::Incrementer {
+            incrementer,
+            key: &skey,
+            value: flags.delta.unwrap_or(1),
+        }) {
+            Response::Incr { value } => Some(value),
+            Response::NotFound if flags.vivify.is_some() => {
+                // they asked us to create it if it's missing, so it starts
+                // life at the initial value instead
+                let initial = flags.initial.unwrap_or(0);
+                let initial_data = initial.to_string();
+                match self.execute(ServerCommand::Setter {
+                    setter: SetterType::Add,
+                    key: &skey,
+                    data: initial_data.as_bytes(),
+                    ttl: flags.vivify.unwrap(),
+                    flags: 0,
+                }) {
+                    Response::Stored => Some(initial),
+                    _ => None,
+                }
+            }
+            Response::NotFound => None,
+            Response::ClientError { .. } => {
+                return Response::ClientError {
+                    message: b"cannot increment or decrement non-numeric value",
+                }
+            }
+            // the only thing that can go wrong with storing it
+            Response::ServerError { .. } => {
+                return Response::ServerError { message: NO_MEMORY }
+            }
+            other => unreachable!("unexpected incr response {:?}", other),
+        };
+        let value = match value {
+            None => {
+                return meta_response(MetaCode::NotFound,
+                                     returned,
+                                     None,
+                                     flags.quiet)
+            }
+            Some(value) => value,
+        };
+
+        if let Some(cttl) = flags.ttl {
+            self.store.touch(&skey, wrap_ttl(cttl, now), now);
+        }
+
+        if let Some((_, entry)) = self.store.lookup(&skey, now, false) {
+            if flags.return_cas {
+                returned.push(MetaReturn::Cas(entry.data.unique));
+            }
+            if flags.return_ttl {
+                returned.push(MetaReturn::Ttl(remaining_ttl(entry.expires,
+                                                            now)));
+            }
+        }
+
+        if flags.return_value {
+            let data = value.to_string().into_bytes();
+            meta_response(MetaCode::Value, returned, Some(data), false)
+        } else {
+            meta_response(MetaCode::Stored, returned, None, flags.quiet)
+        }
+    }
+
+    fn meta_debug<'a>(&mut self,
+                      ckey: Key<'a>,
+                      flags: MetaFlags<'a>,
+                      now: Ttl)
+                      -> Response<'a> {
+        let skey = match meta_key(ckey, flags.base64) {
+            Some(skey) => skey,
+            None => return Response::ClientError { message: BAD_META_KEY },
+        };
+
+        let found = self.store.lookup(&skey, now, false).map(|(access, entry)| {
+            let mut stats = Vec::new();
+            stats::push(&mut stats, "exp", remaining_ttl(entry.expires, now));
+            stats::push(&mut stats, "la", now.saturating_sub(access.used));
+            stats::push(&mut stats, "cas", entry.data.unique);
+            stats::push(&mut stats,
+                        "fetch",
+                        if access.fetched { "yes" } else { "no" });
+            stats::push(&mut stats, "cls", entry.data.data.class());
+            stats::push(&mut stats, "size", entry.weight());
+            stats
+        });
+
+        match found {
+            Some(stats) => {
+                Response::MetaDebug {
+                    key: ckey,
+                    stats,
+                }
+            }
+            None => meta_response(MetaCode::Miss, Vec::new(), None, false),
+        }
+    }
+
+    fn allocate(&mut self,
+                key: &StoredKey,
+                data: &[u8],
+                now: Ttl)
+                -> Result<Chunks, Refused> {
+        // copy a value into the slabs, throwing away whatever we have to in
+        // order to find room for it. Everything that stores a value comes
+        // through here, so this is where the item size limit is enforced
+        if data.len() > self.settings.item_size_max {
+            return Err(Refused::TooBig);
+        }
+        // anything too big for one chunk is split over several of the
+        // biggest ones
+        let parts: Vec<&[u8]> = match self.slabs.class_for(data.len()) {
+            Some(_) => vec![data],
+            None => {
+                let classes = self.slabs.classes();
+                data.chunks(classes.chunk_size(classes.len())).collect()
+            }
+        };
+        let class = self.slabs.classes().class_for(parts[0].len());
+
+        let mut chunks = Vec::with_capacity(parts.len());
+        let mut admitted = false;
+        for part in parts {
+            loop {
+                if let Some(chunk) = self.slabs.alloc_in(class, part) {
+                    chunks.push(chunk);
+                    break;
+                }
+                if !admitted {
+                    // the eviction policy may decide that it isn't worth
+                    // throwing anything away for
+                    if !self.store.admits(key, class - 1, now) {
+                        return Err(Refused::Rejected);
+                    }
+                    admitted = true;
+                }
+                // its class is full and there are no pages left to give it,
+                // so the only room is in the chunks that its other values are
+                // using. The other classes keep what they have
+                if !self.store.evict_from(class - 1, now) {
+                    return Err(Refused::NoMemory);
+                }
+            }
+        }
+        Ok(Chunks::new(chunks))
+    }
+
+    fn store_value<'a>(&mut self,
+                       key: StoredKey,
+                       data: &[u8],
+                       flags: Flags,
+                       unique: CasUnique,
+                       expires: Option<Ttl>,
+                       now: Ttl)
+                       -> Response<'a> {
+        // an append or an incr can grow a value past the item size limit,
+        // which allocate tells us about
+        match self.allocate(&key, data, now) {
+            Ok(chunk) => {
+                let container = DataContainer {
+                    data: chunk,
+                    flags,
+                    unique,
+                };
+                self.store.set(key, container, expires, now);
+                Response::Stored
+            }
+            Err(refused) => refused.response(),
+        }
+    }
+
+    pub fn class_pages(&self, class: usize) -> usize {
+        match self.slabs.usage().get(class.wrapping_sub(1)) {
+            Some(usage) => usage.pages,
+            None => 0,
+        }
+    }
+
+    fn reassign(&mut self, src: usize, dst: usize) -> Reassigned {
+        // move a page of memory from one class to another, throwing away
+        // whatever was stored in it
+        let classes = self.slabs.classes().len();
+        if src == dst {
+            return Reassigned::Same;
+        }
+        if src < 1 || src > classes || dst < 1 || dst > classes {
+            return Reassigned::BadClass;
+        }
+        let page = match self.slabs.spare_page(src) {
+            Some(page) => page,
+            None => return Reassigned::NoSpare,
+        };
+
+        let evicted = self.store
+            .remove_matching(src - 1, |item| item.data.uses_page(page));
+        self.slabs.move_page(page, dst);
+        self.stats.slabs_moved += 1;
+        self.stats.slab_reassign_evictions += evicted as u64;
+        Reassigned::Ok
+    }
+
+    pub fn rebalance(&mut self) {
+        // called every so often to let the automover move memory to where
+        // it's needed
+        let usages = self.slabs.usage();
+        let evicted_by_pool = &self.store.stats().evicted_by_pool;
+        let evictions: Vec<u64> = (0..usages.len())
+            .map(|pool| evicted_by_pool.get(&pool).cloned().unwrap_or(0))
+            .collect();
+        let mode = self.settings.slab_automove;
+        if let Some((src, dst)) = self.mover.check(mode, &usages, &evictions) {
+            self.reassign(src, dst);
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn pools(&self) -> usize {
+        // one for each slab class
+        self.slabs.classes().len()
+    }
+
+    pub fn crawl(&mut self,
+                 pool: usize,
+                 limit: usize,
+                 deadline: Instant)
+                 -> (usize, bool) {
+        // called by the expiry crawler to reclaim up to `limit` of a pool's
+        // expired items, as many as it can before `deadline`. Returns how
+        // many that was, and whether it had to stop before it ran out
+        self.store.crawl(pool, epoch_time(), limit, deadline)
+    }
+
+    pub fn dump_keys(&self, class: Option<usize>) -> Vec<Arc<StoredKey>> {
+        // everything in a class (or all of them) for lru_crawler metadump to
+        // go through a few at a time. Copying the keys is as much as we do
+        // with the lock held for the whole class
+        self.store
+            .entries()
+            .filter(|entry| {
+                class.is_none_or(|class| entry.data.data.class() == class)
+            })
+            .map(|entry| entry.key.clone())
+            .collect()
+    }
+
+    pub fn dump(&mut self, keys: &[Arc<StoredKey>], out: &mut Vec<u8>) {
+        // a line of metadata for each of `keys` that's still here, in the
+        // format memcached's metadump uses
+        let now = epoch_time();
+        for key in keys {
+            if let Some((access, entry)) = self.store.lookup(key, now, false) {
+                let expires =
+                    entry.expires.map_or(-1, |expires| expires as i64);
+                out.extend_from_slice(format!("key={} exp={} la={} cas={} \
+                                               fetch={} cls={} size={}\n",
+                                              url_encode(key),
+                                              expires,
+                                              access.used,
+                                              entry.data.unique,
+                                              if access.fetched {
+                                                  "yes"
+                                              } else {
+                                                  "no"
+                                              },
+                                              entry.data.data.class(),
+                                              entry.weight())
+                    .as_bytes());
+            }
+        }
+    }
+
+    pub fn snapshot(&self, out: &mut dyn Write) -> io::Result<usize> {
+        // write out everything we have for a warm restart, least recently used
+        // first. Returns how many items that was
+        let entries = self.store.by_recency(epoch_time());
+        for entry in &entries {
+            snapshot::write_item(out,
+                                 &entry.key,
+                                 &entry.data.data.bytes(),
+                                 entry.data.flags,
+                                 entry.data.unique,
+                                 entry.expires,
+                                 entry.used())?;
+        }
+        Ok(entries.len())
+    }
+
+    pub fn pending_flush(&self) -> Option<lru::Timestamp> {
+        self.store.pending_flush(epoch_time())
+    }
+
+    pub fn restore(&mut self, item: snapshot::Item) -> bool {
+        // put back something from a snapshot, keeping everything about it
+        // but where it lives. Anything that's expired since stays gone, as
+        // does anything that doesn't fit any more
+        let now = epoch_time();
+        if item.expires.is_some_and(|expires| expires < now) {
+            return false;
+        }
+        let chunk = match self.allocate(&item.key, &item.data, now) {
+            Ok(chunk) => chunk,
+            Err(_) => return false,
+        };
+        let container = DataContainer {
+            data: chunk,
+            flags: item.flags,
+            unique: item.unique,
+        };
+        if item.unique > self.last_cas_id {
+            self.last_cas_id = item.unique;
+        }
+        self.store.restore(item.key, container, item.expires, item.used, now)
+    }
+
+    pub fn replay(&mut self, record: Record) {
+        // redo something from the write log. Items replace whatever was
+        // there, even if they've expired or been flushed since
+        let now = epoch_time();
+        match record {
+            Record::Item(item) => {
+                self.store.delete(&item.key);
+                if !self.store.flushed(item.used, now) {
+                    self.restore(item);
+                }
+            }
+            Record::Delete(key) => {
+                self.store.delete(&key);
+            }
+            Record::Flush(deadline) => {
+                // everything logged before a flush was stored before its
+                // deadline. What comes after it may have been too, so the
+                // deadline stays to judge that by even once it's passed
+                if deadline <= now {
+                    self.store.clear();
+                }
+                self.store.flush_at(deadline, now);
+            }
+        }
+    }
+
+    pub fn maintain(&mut self) {
+        // called every so often to let the eviction policies move things
+        // between their segments
+        self.store.maintain(epoch_time());
+    }
+
+    pub fn flush(&mut self, delay: Ttl, now: Ttl) {
+        match wrap_ttl(delay, now) {
+            Some(deadline) if deadline > now => {
+                // everything stored until then goes away when it passes
+                self.store.flush_at(deadline, now);
+            }
+            _ => {
+                self.store.clear(); // weeeeee
+            }
+        }
+    }
+
     #[cfg(test)]
     pub fn simple_get(&mut self, key: &str) -> Option<String> {
         let as_bytes = key.as_bytes();
@@ -421,7 +1559,7 @@ impl Store {
         match self.store.fast_get(&as_vec, epoch_time()) {
             None => None,
             Some(container) => {
-                let ref container_data = container.data;
+                let container_data = container.data.bytes();
                 let container_as_string =
                     String::from_utf8_lossy(&container_data);
                 let mut new_string = String::new();
@@ -460,12 +1598,11 @@ impl Store {
                           unique: CasUnique) {
         let mut key_vec: Vec<u8> = Vec::new();
         key_vec.extend_from_slice(key.as_bytes());
-        let mut data_vec: Vec<u8> = Vec::new();
-        data_vec.extend_from_slice(data.as_bytes());
+        let chunk = self.slabs.alloc(data.as_bytes()).unwrap();
 
         self.store.set(key_vec,
                        DataContainer {
-                           data: data_vec,
+                           data: Chunks::Single(chunk),
                            flags: 0,
                            unique: unique,
                        },
@@ -474,6 +1611,12 @@ impl Store {
     }
 }
 
+impl lru::Pooled for DataContainer {
+    fn pool(&self) -> usize {
+        self.data.class() - 1
+    }
+}
+
 impl lru::HasWeight for DataContainer {
     fn weight(&self) -> lru::Weight {
         (self.data.capacity() + mem::size_of::<CasUnique>() +
@@ -481,9 +1624,100 @@ impl lru::HasWeight for DataContainer {
     }
 }
 
+pub fn stats_report(shards: &[&Store],
+                    stats_type: StatsType,
+                    now: Ttl)
+                    -> Vec<stats::Stat> {
+    // the stats for the whole server, which may be split across several
+    // shards. They all share the same settings and connections
+    let mut stats = Vec::new();
+    let first = shards[0];
+
+    let mut totals = Stats::default();
+    let mut lru_stats = lru::LruStats::default();
+    let mut capacity = 0;
+    let mut weight = 0;
+    let mut items = 0;
+    let mut usages = vec![ClassUsage::default(); first.slabs.classes().len()];
+    for shard in shards {
+        totals.add(&shard.stats);
+        lru_stats.add(shard.store.stats());
+        capacity += shard.slabs.limit();
+        weight += shard.store.weight();
+        items += shard.store.len();
+        for (total, usage) in usages.iter_mut().zip(shard.slabs.usage()) {
+            total.add(&usage);
+        }
+    }
+    let entries = || shards.iter().flat_map(|shard| shard.store.entries());
+
+    match stats_type {
+        StatsType::General => {
+            stats::process_report(&mut stats, VERSION, first.started, now);
+            first.connections.report(&mut stats);
+            stats::push(&mut stats, "threads", first.settings.threads);
+            totals.report(&mut stats);
+
+            stats::push(&mut stats, "limit_maxbytes", capacity);
+            stats::push(&mut stats, "bytes", weight);
+            stats::push(&mut stats, "curr_items", items);
+            stats::push(&mut stats, "total_items", totals.total_items);
+            stats::push(&mut stats,
+                        "expired_unfetched",
+                        lru_stats.expired_unfetched);
+            stats::push(&mut stats,
+                        "evicted_unfetched",
+                        lru_stats.evicted_unfetched);
+            stats::push(&mut stats, "evictions", lru_stats.evictions);
+            stats::push(&mut stats, "reclaimed", lru_stats.reclaimed);
+            stats::push(&mut stats,
+                        "crawler_reclaimed",
+                        lru_stats.crawler_reclaimed);
+            stats::push(&mut stats,
+                        "admission_rejections",
+                        lru_stats.rejections);
+            stats::push(&mut stats, "slabs_moved", totals.slabs_moved);
+            stats::push(&mut stats,
+                        "slab_reassign_evictions",
+                        totals.slab_reassign_evictions);
+            if let Some(ref log) = first.log {
+                log.report(&mut stats);
+            }
+        }
+        StatsType::Settings => {
+            first.settings.report(&mut stats);
+        }
+        StatsType::Sizes => {
+            stats::sizes_report(&mut stats, entries().map(|e| e.weight()));
+        }
+        StatsType::Items | StatsType::Slabs => {
+            let classes = first.slabs.classes();
+            if stats_type == StatsType::Items {
+                let entries = entries()
+                    .map(|entry| (entry.data.data.class(), entry.used()));
+                let mut summaries =
+                    classes.summarise(entries, &lru_stats.evicted_by_pool);
+                for shard in shards {
+                    for (pool, summary) in summaries.iter_mut().enumerate() {
+                        summary.add_segments(&shard.store.segments(pool));
+                    }
+                }
+                classes.items_report(&mut stats, &summaries, now);
+            } else {
+                classes.slabs_report(&mut stats,
+                                     &usages,
+                                     first.slabs.page_size());
+            }
+        }
+    }
+
+    stats
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use policy::{PolicyType, SegmentLimits};
 
     #[test]
     pub fn set() {
@@ -702,7 +1936,7 @@ mod tests {
 
     #[test]
     pub fn get_multi() {
-        let mut store = Store::new(200);
+        let mut store = Store::new(1000);
         store.simple_set("foo1", "bar1");
         store.simple_set("foo2", "bar2");
         let res = store.apply(ServerCommand::Getter {
@@ -747,7 +1981,7 @@ mod tests {
 
     #[test]
     pub fn gets_multi() {
-        let mut store = Store::new(200);
+        let mut store = Store::new(1000);
         store.simple_set_cas("foo1", "bar1", 100);
         store.simple_set_cas("foo2", "bar2", 100);
         let res = store.apply(ServerCommand::Getter {
@@ -771,6 +2005,40 @@ mod tests {
                    });
     }
 
+    #[test]
+    pub fn gat() {
+        let mut store = Store::new(1000);
+        let now = epoch_time();
+        store.simple_set_cas("foo", "bar", 100);
+
+        let res = store.apply(ServerCommand::GetAndTouch {
+            getter: GetterType::Gets,
+            ttl: 100,
+            keys: vec![b"foo", b"nope"],
+        });
+        assert_eq!(res,
+                   Response::Gets {
+                       responses: vec![SingleGetResponse {
+                                           key: b"foo",
+                                           data: b("bar"),
+                                           flags: 0,
+                                           unique: 100,
+                                       }],
+                   });
+        assert_eq!(store.simple_get_ttl("foo"), Some(now + 100));
+
+        let res = store.apply(ServerCommand::GetAndTouch {
+            getter: GetterType::Get,
+            ttl: 0,
+            keys: vec![b"foo"],
+        });
+        match res {
+            Response::Data { ref responses } => assert_eq!(responses.len(), 1),
+            other => panic!("unexpected response {:?}", other),
+        }
+        assert_eq!(store.simple_get_ttl("foo"), None);
+    }
+
     #[test]
     pub fn incr_present_and_good() {
         let mut store = Store::new(200);
@@ -982,12 +2250,788 @@ mod tests {
         store.simple_set("foo", "bar");
         assert_eq!(Some("bar".to_string()), store.simple_get("foo"));
 
-        let res = store.apply(ServerCommand::FlushAll);
+        let res = store.apply(ServerCommand::FlushAll { delay: 0 });
+        assert_eq!(res, Response::Ok);
+
+        assert_eq!(None, store.simple_get("foo"));
+    }
+
+    #[test]
+    pub fn delayed_flushall() {
+        let mut store = Store::new(200);
+        store.simple_set("foo", "bar");
+
+        // it doesn't go anywhere until the deadline passes
+        let res = store.apply(ServerCommand::FlushAll { delay: 100 });
         assert_eq!(res, Response::Ok);
+        assert_eq!(Some("bar".to_string()), store.simple_get("foo"));
+        assert_eq!(store.stats.cmd_flush, 1);
 
+        // and a deadline that's already passed is just a normal flush
+        let now = epoch_time();
+        store.apply(ServerCommand::FlushAll { delay: now - 10 });
         assert_eq!(None, store.simple_get("foo"));
     }
 
+    #[test]
+    pub fn stats() {
+        let mut store = Store::new(1000);
+        store.simple_set("foo", "1");
+        store.apply(ServerCommand::Getter {
+            getter: GetterType::Get,
+            keys: vec![b"foo", b"bar"],
+        });
+        store.apply(ServerCommand::Incrementer {
+            incrementer: IncrementerType::Incr,
+            key: b"foo",
+            value: 5,
+        });
+        store.apply(ServerCommand::Setter {
+            setter: SetterType::Cas(12345),
+            key: b"foo",
+            data: b"baz",
+            flags: 0,
+            ttl: 0,
+        });
+        store.apply(ServerCommand::GetAndTouch {
+            getter: GetterType::Get,
+            ttl: 100,
+            keys: vec![b"foo", b"bar"],
+        });
+        store.apply(ServerCommand::Delete { key: b"bar" });
+        store.connection_stats().admit(1);
+
+        let stats = match store.apply(ServerCommand::Stats(StatsType::General)) {
+            Response::Stats { stats } => stats,
+            other => panic!("unexpected response {:?}", other),
+        };
+        let stat = |name: &str| -> String {
+            stats.iter()
+                .find(|stat| stat.0 == name)
+                .map(|stat| stat.1.clone())
+                .unwrap()
+        };
+        assert_eq!(stat("cmd_get"), "4");
+        assert_eq!(stat("get_hits"), "2");
+        assert_eq!(stat("get_misses"), "2");
+        assert_eq!(stat("cmd_touch"), "2");
+        assert_eq!(stat("touch_hits"), "1");
+        assert_eq!(stat("touch_misses"), "1");
+        assert_eq!(stat("incr_hits"), "1");
+        assert_eq!(stat("cmd_set"), "1");
+        assert_eq!(stat("cas_badval"), "1");
+        assert_eq!(stat("delete_misses"), "1");
+        assert_eq!(stat("curr_items"), "1");
+        assert_eq!(stat("curr_connections"), "1");
+        assert_eq!(stat("limit_maxbytes"), "1000");
+    }
+
+    #[test]
+    pub fn stats_subcommands() {
+        let mut store = Store::new(1000);
+        store.simple_set("foo", "bar");
+        store.simple_set("foo2", "bar");
+
+        let mut stats_for = |stats_type| {
+            match store.apply(ServerCommand::Stats(stats_type)) {
+                Response::Stats { stats } => stats,
+                other => panic!("unexpected response {:?}", other),
+            }
+        };
+
+        let items = stats_for(StatsType::Items);
+        assert_eq!(items[0], ("items:1:number".to_string(), "2".to_string()));
+        assert_eq!(items[1], ("items:1:age".to_string(), "0".to_string()));
+
+        let slabs = stats_for(StatsType::Slabs);
+        assert_eq!(slabs[0], ("1:chunk_size".to_string(), "96".to_string()));
+        // with this little memory the pages only have room for one chunk
+        assert_eq!(slabs[2], ("1:total_pages".to_string(), "2".to_string()));
+        assert_eq!(slabs[3], ("1:total_chunks".to_string(), "2".to_string()));
+        assert_eq!(slabs[4], ("1:used_chunks".to_string(), "2".to_string()));
+        assert_eq!(slabs[6], ("1:mem_requested".to_string(), "6".to_string()));
+
+        // the values weigh what their chunks do, not what they asked for
+        let sizes = stats_for(StatsType::Sizes);
+        assert_eq!(sizes, vec![("192".to_string(), "2".to_string())]);
+
+        let settings = stats_for(StatsType::Settings);
+        assert_eq!(settings[0], ("maxbytes".to_string(), "1000".to_string()));
+    }
+
+    #[test]
+    pub fn segment_stats() {
+        let mut store = Store::with_settings(Settings {
+            maxbytes: 1000,
+            eviction: PolicyType::Segmented(SegmentLimits::default()),
+            ..Settings::default()
+        });
+        store.simple_set("foo", "bar");
+        store.simple_set("foo2", "bar");
+
+        let mut stats_for = |stats_type| {
+            match store.apply(ServerCommand::Stats(stats_type)) {
+                Response::Stats { stats } => stats,
+                other => panic!("unexpected response {:?}", other),
+            }
+        };
+        let stat = |stats: &Vec<stats::Stat>, name: &str| -> String {
+            stats.iter()
+                .find(|stat| stat.0 == name)
+                .map(|stat| stat.1.clone())
+                .unwrap()
+        };
+
+        // the second one pushed the first out of HOT
+        let items = stats_for(StatsType::Items);
+        assert_eq!(stat(&items, "items:1:number"), "2");
+        assert_eq!(stat(&items, "items:1:number_hot"), "1");
+        assert_eq!(stat(&items, "items:1:number_warm"), "0");
+        assert_eq!(stat(&items, "items:1:number_cold"), "1");
+        assert_eq!(stat(&items, "items:1:number_temp"), "0");
+
+        let settings = stats_for(StatsType::Settings);
+        assert_eq!(stat(&settings, "lru_segmented"), "yes");
+        assert_eq!(stat(&settings, "hot_lru_pct"), "20");
+    }
+
+    #[test]
+    pub fn slab_memory() {
+        let mut store = Store::new(1000);
+        let set = |store: &mut Store, key: &'static str, size| {
+            store.apply(ServerCommand::Setter {
+                setter: SetterType::Set,
+                key: key.as_bytes(),
+                data: &[b'x'; 400][..size],
+                ttl: 0,
+                flags: 0,
+            })
+        };
+
+        // the small values take most of the memory...
+        for key in &["a", "b", "c", "d", "e", "f"] {
+            assert_eq!(set(&mut store, key, 1), Response::Stored);
+        }
+        // ...so there isn't enough left to store something much bigger
+        assert_eq!(set(&mut store, "big", 400),
+                   Response::ServerError { message: NO_MEMORY });
+
+        // medium sized ones can still fit a few pages, and then make room by
+        // throwing each other away. The small ones are left alone
+        for key in &["m1", "m2", "m3", "m4", "m5"] {
+            assert_eq!(set(&mut store, key, 100), Response::Stored);
+        }
+        assert_eq!(store.simple_get("m5"), Some("x".repeat(100)));
+        assert_eq!(store.simple_get("m1"), None);
+        assert_eq!(store.simple_get("a"), Some("x".to_string()));
+        assert_eq!(store.slabs.usage()[1].pages, 3);
+
+        // until we give the medium ones some of their memory
+        assert_eq!(store.apply(ServerCommand::SlabsReassign { src: 1, dst: 1 }),
+                   Response::Reassign(Reassigned::Same));
+        assert_eq!(store.apply(ServerCommand::SlabsReassign { src: 1, dst: 99 }),
+                   Response::Reassign(Reassigned::BadClass));
+        assert_eq!(store.apply(ServerCommand::SlabsReassign { src: 1, dst: 2 }),
+                   Response::Reassign(Reassigned::Ok));
+        assert_eq!(store.slabs.usage()[0].pages, 5);
+        assert_eq!(store.slabs.usage()[1].pages, 4);
+        assert_eq!(set(&mut store, "m6", 100), Response::Stored);
+        assert_eq!(store.simple_get("m3"), Some("x".repeat(100)));
+        assert_eq!(store.stats.slabs_moved, 1);
+        assert_eq!(store.stats.slab_reassign_evictions, 1);
+    }
+
+    #[test]
+    pub fn slab_pools() {
+        let mut store = Store::new(20000);
+        let big = [b'x'; 300];
+        let set = |store: &mut Store, key: String, data: &[u8]| {
+            store.apply(ServerCommand::Setter {
+                setter: SetterType::Set,
+                key: key.as_bytes(),
+                data,
+                ttl: 0,
+                flags: 0,
+            }) == Response::Stored
+        };
+
+        for idx in 0..50 {
+            assert!(set(&mut store, format!("small{}", idx), b"hot"));
+        }
+        // a burst of big values only pushes out other big values
+        for idx in 0..200 {
+            assert!(set(&mut store, format!("big{}", idx), &big));
+        }
+        for idx in 0..50 {
+            let key = format!("small{}", idx);
+            assert_eq!(store.simple_get(&key), Some("hot".to_string()));
+        }
+        assert_eq!(store.simple_get("big0"), None);
+        assert_eq!(store.simple_get("big199"), Some("x".repeat(300)));
+        assert!(store.store.stats().evicted_by_pool[&5] > 100);
+    }
+
+    #[test]
+    pub fn admission() {
+        let mut store = Store::with_settings(Settings {
+            maxbytes: 1000,
+            eviction: PolicyType::TinyLfu,
+            ..Settings::default()
+        });
+        let set = |store: &mut Store, key: &'static str| {
+            store.apply(ServerCommand::Setter {
+                setter: SetterType::Set,
+                key: key.as_bytes(),
+                data: &[b'x'; 100],
+                ttl: 0,
+                flags: 0,
+            })
+        };
+
+        // more than fits, so the class is full, and use everything in it
+        let keys = ["m1", "m2", "m3", "m4", "m5", "m6", "m7", "m8", "m9"];
+        for key in &keys {
+            assert_eq!(set(&mut store, key), Response::Stored);
+        }
+        store.apply(ServerCommand::Getter {
+            getter: GetterType::Get,
+            keys: keys.iter().map(|key| key.as_bytes()).collect(),
+        });
+        let kept = store.store.len();
+
+        // something new isn't worth throwing any of that away for. The client
+        // can't tell that from it being evicted straight away
+        assert_eq!(set(&mut store, "new"), Response::Stored);
+        assert_eq!(store.simple_get("new"), None);
+        assert_eq!(store.store.len(), kept);
+        assert_eq!(store.store.stats().rejections, 1);
+
+        // unless it keeps coming back
+        assert_eq!(set(&mut store, "new"), Response::Stored);
+        assert_eq!(store.simple_get("new"), Some("x".repeat(100)));
+        assert_eq!(store.store.stats().rejections, 1);
+    }
+
+    #[test]
+    pub fn large_items() {
+        let mut store = Store::with_settings(Settings {
+            maxbytes: 16 * 1024 * 1024,
+            item_size_max: 3 * 1024 * 1024,
+            ..Settings::default()
+        });
+        fn store_with<'a>(store: &mut Store,
+                          setter: SetterType,
+                          data: &'a [u8])
+                          -> Response<'a> {
+            store.apply(ServerCommand::Setter {
+                setter,
+                key: b"big",
+                data,
+                ttl: 0,
+                flags: 0,
+            })
+        }
+
+        // bigger than any chunk, so it's kept in several
+        let big: Vec<u8> = (0..2_500_000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(store_with(&mut store, SetterType::Set, &big),
+                   Response::Stored);
+        assert_eq!(store_with(&mut store, SetterType::Append, b"tail"),
+                   Response::Stored);
+        assert_eq!(store_with(&mut store, SetterType::Cas(2), &big[1..]),
+                   Response::Stored);
+        assert_eq!(store_with(&mut store, SetterType::Prepend, b"x"),
+                   Response::Stored);
+
+        // and the limit's the same however they try to get past it
+        let huge = vec![b'x'; 3 * 1024 * 1024 + 1];
+        assert_eq!(store_with(&mut store, SetterType::Set, &huge),
+                   Response::TooBig);
+        assert_eq!(store_with(&mut store, SetterType::Cas(4), &huge),
+                   Response::TooBig);
+        let most = vec![b'x'; 700_000];
+        assert_eq!(store_with(&mut store, SetterType::Append, &most),
+                   Response::TooBig);
+        assert_eq!(store_with(&mut store, SetterType::Prepend, &most),
+                   Response::TooBig);
+
+        let mut expected = b"x".to_vec();
+        expected.extend_from_slice(&big[1..]);
+        match store.apply(ServerCommand::Getter {
+            getter: GetterType::Gets,
+            keys: vec![b"big"],
+        }) {
+            Response::Gets { responses } => {
+                assert!(responses[0].data == expected);
+                assert_eq!(responses[0].unique, 4);
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+        let top = store.slabs.classes().len();
+        let usage = &store.slabs.usage()[top - 1];
+        assert_eq!(usage.chunks - usage.free_chunks, 3);
+    }
+
+    fn meta(response: Response) -> MetaResponse {
+        match response {
+            Response::Meta(meta) => meta,
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn meta_get() {
+        let mut store = Store::new(1000);
+        let now = epoch_time();
+
+        let res = meta(store.apply(ServerCommand::MetaGet {
+            key: b"foo",
+            flags: MetaFlags { quiet: true, ..MetaFlags::default() },
+        }));
+        assert_eq!(res.code, MetaCode::Miss);
+        assert!(res.silent);
+
+        store.simple_set_cas("foo", "bar", 100);
+        let res = meta(store.apply(ServerCommand::MetaGet {
+            key: b"foo",
+            flags: MetaFlags {
+                return_value: true,
+                return_cas: true,
+                return_hit: true,
+                return_ttl: true,
+                return_size: true,
+                return_key: true,
+                opaque: Some(b"123"),
+                ttl: Some(60),
+                ..MetaFlags::default()
+            },
+        }));
+        assert_eq!(res,
+                   MetaResponse {
+                       code: MetaCode::Value,
+                       returned: vec![MetaReturn::Key(b"foo"),
+                                      MetaReturn::Opaque(b"123"),
+                                      MetaReturn::Cas(100),
+                                      MetaReturn::Hit(false),
+                                      MetaReturn::Size(3),
+                                      MetaReturn::Ttl(60)],
+                       data: Some(b("bar")),
+                       silent: false,
+                   });
+        assert_eq!(store.simple_get_ttl("foo"), Some(now + 60));
+
+        // now it's been fetched, and without v we just get a HD
+        let res = meta(store.apply(ServerCommand::MetaGet {
+            key: b"Zm9v",
+            flags: MetaFlags {
+                base64: true,
+                return_hit: true,
+                return_last_access: true,
+                ..MetaFlags::default()
+            },
+        }));
+        assert_eq!(res.code, MetaCode::Stored);
+        assert_eq!(res.returned,
+                   vec![MetaReturn::Hit(true), MetaReturn::LastAccess(0)]);
+
+        let res = store.apply(ServerCommand::MetaGet {
+            key: b"!!!!",
+            flags: MetaFlags { base64: true, ..MetaFlags::default() },
+        });
+        assert_eq!(res, Response::ClientError { message: BAD_META_KEY });
+    }
+
+    #[test]
+    pub fn meta_set() {
+        let mut store = Store::new(1000);
+
+        let set = |store: &mut Store, data, flags| {
+            meta(store.apply(ServerCommand::MetaSet {
+                key: b"foo",
+                data,
+                flags,
+            }))
+            .code
+        };
+
+        let add = MetaFlags { mode: Some(b'E'), ..MetaFlags::default() };
+        assert_eq!(set(&mut store, b"bar", add), MetaCode::Stored);
+        let add = MetaFlags { mode: Some(b'E'), ..MetaFlags::default() };
+        assert_eq!(set(&mut store, b"baz", add), MetaCode::NotStored);
+
+        let append = MetaFlags { mode: Some(b'A'), ..MetaFlags::default() };
+        assert_eq!(set(&mut store, b"baz", append), MetaCode::Stored);
+        let prepend = MetaFlags { mode: Some(b'P'), ..MetaFlags::default() };
+        assert_eq!(set(&mut store, b">", prepend), MetaCode::Stored);
+        assert_eq!(store.simple_get("foo"), Some(">barbaz".to_string()));
+
+        let cas = store.last_cas_id();
+        let stale = MetaFlags {
+            compare_cas: Some(cas - 1),
+            ..MetaFlags::default()
+        };
+        assert_eq!(set(&mut store, b"nope", stale), MetaCode::Exists);
+        let fresh = MetaFlags {
+            compare_cas: Some(cas),
+            client_flags: Some(12),
+            ..MetaFlags::default()
+        };
+        assert_eq!(set(&mut store, b"yes", fresh), MetaCode::Stored);
+        assert_eq!(store.simple_get("foo"), Some("yes".to_string()));
+        assert_eq!(store.simple_get_flags("foo"), Some(12));
+
+        let res = meta(store.apply(ServerCommand::MetaSet {
+            key: b"foo",
+            data: b"quiet",
+            flags: MetaFlags {
+                quiet: true,
+                return_cas: true,
+                ..MetaFlags::default()
+            },
+        }));
+        assert!(res.silent);
+        assert_eq!(res.returned, vec![MetaReturn::Cas(store.last_cas_id())]);
+
+        let replace = MetaFlags { mode: Some(b'R'), ..MetaFlags::default() };
+        store.apply(ServerCommand::FlushAll { delay: 0 });
+        assert_eq!(set(&mut store, b"bar", replace), MetaCode::NotStored);
+
+        let res = store.apply(ServerCommand::MetaSet {
+            key: b"foo",
+            data: b"bar",
+            flags: MetaFlags { mode: Some(b'X'), ..MetaFlags::default() },
+        });
+        assert_eq!(res,
+                   Response::ClientError { message: b"invalid mode for ms" });
+    }
+
+    #[test]
+    pub fn meta_delete() {
+        let mut store = Store::new(1000);
+        store.simple_set_cas("foo", "bar", 100);
+
+        let mut delete = |flags| {
+            meta(store.apply(ServerCommand::MetaDelete {
+                key: b"foo",
+                flags,
+            }))
+        };
+
+        let res = delete(MetaFlags {
+            compare_cas: Some(99),
+            ..MetaFlags::default()
+        });
+        assert_eq!(res.code, MetaCode::Exists);
+        let res = delete(MetaFlags {
+            compare_cas: Some(100),
+            quiet: true,
+            ..MetaFlags::default()
+        });
+        assert_eq!(res.code, MetaCode::Stored);
+        assert!(res.silent);
+        let res = delete(MetaFlags::default());
+        assert_eq!(res.code, MetaCode::NotFound);
+        assert!(!res.silent);
+    }
+
+    #[test]
+    pub fn meta_arithmetic() {
+        let mut store = Store::new(1000);
+
+        let mut arithmetic = |flags| {
+            meta(store.apply(ServerCommand::MetaArithmetic {
+                key: b"foo",
+                flags,
+            }))
+        };
+
+        let res = arithmetic(MetaFlags::default());
+        assert_eq!(res.code, MetaCode::NotFound);
+
+        // auto-vivify creates it with the initial value
+        let res = arithmetic(MetaFlags {
+            vivify: Some(100),
+            initial: Some(10),
+            return_value: true,
+            return_ttl: true,
+            ..MetaFlags::default()
+        });
+        assert_eq!(res.code, MetaCode::Value);
+        assert_eq!(res.data, Some(b("10")));
+        assert_eq!(res.returned, vec![MetaReturn::Ttl(100)]);
+
+        let res = arithmetic(MetaFlags {
+            delta: Some(5),
+            return_value: true,
+            ..MetaFlags::default()
+        });
+        assert_eq!(res.data, Some(b("15")));
+
+        let res = arithmetic(MetaFlags {
+            mode: Some(b'D'),
+            delta: Some(20),
+            ttl: Some(0),
+            ..MetaFlags::default()
+        });
+        assert_eq!(res.code, MetaCode::Stored);
+        assert_eq!(res.data, None);
+
+        let res = arithmetic(MetaFlags {
+            return_value: true,
+            vivify: Some(100),
+            initial: Some(10),
+            ..MetaFlags::default()
+        });
+        assert_eq!(res.data, Some(b("1")));
+
+        assert_eq!(store.simple_get("foo"), Some("1".to_string()));
+        assert_eq!(store.simple_get_ttl("foo"), None);
+    }
+
+    #[test]
+    pub fn meta_stale_while_revalidate() {
+        let mut store = Store::new(1000);
+        let now = epoch_time();
+        store.simple_set("foo", "old");
+
+        let get = |store: &mut Store| {
+            meta(store.apply(ServerCommand::MetaGet {
+                key: b"foo",
+                flags: MetaFlags {
+                    return_value: true,
+                    return_cas: true,
+                    ..MetaFlags::default()
+                },
+            }))
+        };
+
+        let res = meta(store.apply(ServerCommand::MetaDelete {
+            key: b"foo",
+            flags: MetaFlags {
+                invalidate: true,
+                ttl: Some(30),
+                ..MetaFlags::default()
+            },
+        }));
+        assert_eq!(res.code, MetaCode::Stored);
+        assert_eq!(store.simple_get_ttl("foo"), Some(now + 30));
+        let cas = store.last_cas_id();
+
+        // the first client to see it gets to recache it...
+        let res = get(&mut store);
+        assert_eq!(res.data, Some(b("old")));
+        assert_eq!(res.returned,
+                   vec![MetaReturn::Cas(cas),
+                        MetaReturn::Stale,
+                        MetaReturn::Win]);
+
+        // ...and everybody else gets the stale value in the meantime
+        let res = get(&mut store);
+        assert_eq!(res.data, Some(b("old")));
+        assert_eq!(res.returned,
+                   vec![MetaReturn::Cas(cas),
+                        MetaReturn::AlreadyWon,
+                        MetaReturn::Stale]);
+
+        // the old cas doesn't work any more, but the winner's does
+        let res = meta(store.apply(ServerCommand::MetaSet {
+            key: b"foo",
+            data: b"new",
+            flags: MetaFlags {
+                compare_cas: Some(cas - 1),
+                ..MetaFlags::default()
+            },
+        }));
+        assert_eq!(res.code, MetaCode::Exists);
+        let res = meta(store.apply(ServerCommand::MetaSet {
+            key: b"foo",
+            data: b"new",
+            flags: MetaFlags {
+                compare_cas: Some(cas),
+                ..MetaFlags::default()
+            },
+        }));
+        assert_eq!(res.code, MetaCode::Stored);
+
+        let res = get(&mut store);
+        assert_eq!(res.data, Some(b("new")));
+        assert_eq!(res.returned, vec![MetaReturn::Cas(store.last_cas_id())]);
+
+        let res = meta(store.apply(ServerCommand::MetaDelete {
+            key: b"bar",
+            flags: MetaFlags { invalidate: true, ..MetaFlags::default() },
+        }));
+        assert_eq!(res.code, MetaCode::NotFound);
+    }
+
+    #[test]
+    pub fn meta_win_tokens() {
+        let mut store = Store::new(1000);
+
+        let mut get = |key, flags| {
+            meta(store.apply(ServerCommand::MetaGet {
+                    key,
+                    flags,
+                }))
+                .returned
+        };
+
+        // vivifying a miss hands out the token with an empty placeholder
+        let vivify = || MetaFlags { vivify: Some(30), ..MetaFlags::default() };
+        assert_eq!(get(b"foo", vivify()), vec![MetaReturn::Win]);
+        assert_eq!(get(b"foo", vivify()), vec![MetaReturn::AlreadyWon]);
+
+        // and so does getting close to expiring, if they ask
+        store.apply(ServerCommand::Setter {
+            setter: SetterType::Set,
+            key: b"bar",
+            data: b"data",
+            ttl: 5,
+            flags: 0,
+        });
+        let mut get = |flags| {
+            meta(store.apply(ServerCommand::MetaGet {
+                    key: b"bar",
+                    flags,
+                }))
+                .returned
+        };
+        let recache = |secs| {
+            MetaFlags {
+                recache: Some(secs),
+                ..MetaFlags::default()
+            }
+        };
+        assert_eq!(get(recache(2)), vec![]);
+        assert_eq!(get(recache(10)), vec![MetaReturn::Win]);
+        assert_eq!(get(recache(10)), vec![MetaReturn::AlreadyWon]);
+    }
+
+    #[test]
+    pub fn meta_debug() {
+        let mut store = Store::new(1000);
+        store.simple_set_cas("foo", "bar", 100);
+
+        let res = store.apply(ServerCommand::MetaDebug {
+            key: b"foo",
+            flags: MetaFlags::default(),
+        });
+        let stats = match res {
+            Response::MetaDebug { key, stats } => {
+                assert_eq!(key, b"foo");
+                stats
+            }
+            other => panic!("unexpected response {:?}", other),
+        };
+        let names: Vec<&str> = stats.iter().map(|s| &s.0[..]).collect();
+        assert_eq!(names, vec!["exp", "la", "cas", "fetch", "cls", "size"]);
+        assert_eq!(stats[0].1, "-1");
+        assert_eq!(stats[2].1, "100");
+        assert_eq!(stats[3].1, "no");
+
+        let res = meta(store.apply(ServerCommand::MetaDebug {
+            key: b"bar",
+            flags: MetaFlags::default(),
+        }));
+        assert_eq!(res.code, MetaCode::Miss);
+    }
+
+    #[test]
+    pub fn metadump() {
+        let mut store = Store::new(1000);
+        store.simple_set_cas("a key%", "bar", 100);
+        store.simple_set("foo", "baz");
+        store.simple_get("foo");
+
+        let mut keys = store.dump_keys(None);
+        keys.sort();
+        assert_eq!(keys.len(), 2);
+        // nothing comes out for keys that have gone since we listed them
+        store.apply(ServerCommand::Delete { key: b"foo" });
+        let mut out = Vec::new();
+        store.dump(&keys, &mut out);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("key=a%20key%25 exp=-1 la="));
+        assert!(out.ends_with(" cas=100 fetch=no cls=1 size=174\n"));
+        assert_eq!(out.lines().count(), 1);
+
+        assert_eq!(store.dump_keys(Some(1)).len(), 1);
+        assert!(store.dump_keys(Some(2)).is_empty());
+    }
+
+    #[test]
+    pub fn snapshot() {
+        let now = epoch_time();
+        let item = |key: &str, unique, expires, used| {
+            snapshot::Item {
+                key: key.as_bytes().to_vec(),
+                data: b"bar".to_vec(),
+                flags: 5,
+                unique,
+                expires,
+                used,
+            }
+        };
+
+        let mut store = Store::new(1000);
+        assert!(store.restore(item("old", 3, None, now - 100)));
+        assert!(store.restore(item("new", 7, Some(now + 60), now)));
+        assert!(store.restore(item("middle", 4, None, now - 50)));
+        // anything that's expired since doesn't come back
+        assert!(!store.restore(item("expired", 9, Some(now - 1), now - 10)));
+        assert_eq!(store.last_cas_id(), 7);
+        assert_eq!(Some(5), store.simple_get_flags("middle"));
+        assert_eq!(Some(now + 60), store.simple_get_ttl("new"));
+
+        // and it all goes out again least recently used first. Those gets
+        // made middle and new the most recent, in that order
+        let mut file = Vec::new();
+        snapshot::write_header(&mut file, 7).unwrap();
+        assert_eq!(store.snapshot(&mut file).unwrap(), 3);
+        snapshot::write_end(&mut file).unwrap();
+        let (_, items) = snapshot::read(&mut &file[..]).unwrap();
+        let keys: Vec<&[u8]> = items.iter().map(|i| &i.key[..]).collect();
+        let expected: Vec<&[u8]> = vec![b"old", b"middle", b"new"];
+        assert_eq!(keys, expected);
+        assert_eq!(items[0], item("old", 3, None, now - 100));
+        assert_eq!(items[2].expires, Some(now + 60));
+    }
+
+    #[test]
+    pub fn replay_flush() {
+        let now = epoch_time();
+        let item = |key: &str, used| {
+            Record::Item(snapshot::Item {
+                key: key.as_bytes().to_vec(),
+                data: b"bar".to_vec(),
+                flags: 0,
+                unique: 1,
+                expires: None,
+                used,
+            })
+        };
+
+        // a flush_all whose deadline passed while we were down takes what
+        // was set before it, and after it but before the deadline
+        let mut store = Store::new(1000);
+        store.replay(item("before", now - 100));
+        store.replay(Record::Flush(now - 50));
+        store.replay(item("during", now - 60));
+        store.replay(item("after", now - 10));
+        assert_eq!(store.simple_get("before"), None);
+        assert_eq!(store.simple_get("during"), None);
+        assert_eq!(store.simple_get("after"), Some("bar".to_string()));
+
+        // and one that's still to come only takes them once it's here
+        let mut store = Store::new(1000);
+        store.replay(item("before", now - 100));
+        store.replay(Record::Flush(now + 1));
+        store.replay(item("during", now));
+        assert_eq!(store.simple_get("before"), Some("bar".to_string()));
+        assert_eq!(store.simple_get("during"), Some("bar".to_string()));
+        assert!(store.store.flushed(now, now + 1));
+    }
+
     fn b(inp: &'static str) -> Vec<u8> {
         // syntactic sugar for tests
         let mut s = String::new();
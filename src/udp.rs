@@ -0,0 +1,212 @@
+//! The UDP listener. Every datagram starts with an 8-byte frame header ahead
+//! of ordinary ASCII protocol commands, and responses that don't fit in one
+//! datagram get split across several that the client puts back together
+
+use std::cmp;
+use std::sync::Arc;
+use std::net::UdpSocket;
+
+use nom::be_u16;
+
+use shards::ShardedStore;
+use server;
+use server::Processed;
+
+pub use nom::IResult;
+
+pub const HEADER_SIZE: usize = 8;
+
+// like memcached, keep datagrams small enough that they won't be fragmented
+const MAX_DATAGRAM: usize = 1400;
+const MAX_PAYLOAD: usize = MAX_DATAGRAM - HEADER_SIZE;
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub struct FrameHeader {
+    pub request_id: u16,
+    pub sequence: u16,
+    pub total: u16,
+}
+
+named!(frame_header<&[u8], FrameHeader>,
+    chain!(
+        request_id: be_u16 ~
+        sequence: be_u16 ~
+        total: be_u16 ~
+        be_u16, // reserved
+        || {
+            FrameHeader {
+                request_id,
+                sequence,
+                total,
+            }
+        }
+    )
+);
+
+pub fn parse_datagram(datagram: &[u8]) -> Option<(FrameHeader, &[u8])> {
+    match frame_header(datagram) {
+        // like memcached, we only take requests that fit in one datagram
+        IResult::Done(payload, header) if header.total == 1 &&
+                                          header.sequence == 0 => {
+            Some((header, payload))
+        }
+        _ => None,
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+}
+
+pub fn split_response(request_id: u16, response: &[u8]) -> Vec<Vec<u8>> {
+    // sequence numbers are only 16 bits, so that's as big as a response can
+    // get
+    let response: &[u8] = if response.len() > MAX_PAYLOAD * 0xffff {
+        b"SERVER_ERROR response too large for UDP\r\n"
+    } else {
+        response
+    };
+
+    let chunks: Vec<&[u8]> = response.chunks(MAX_PAYLOAD).collect();
+    let total = chunks.len() as u16;
+    chunks.iter()
+        .enumerate()
+        .map(|(sequence, chunk)| {
+            let mut datagram = Vec::with_capacity(HEADER_SIZE + chunk.len());
+            put_u16(&mut datagram, request_id);
+            put_u16(&mut datagram, sequence as u16);
+            put_u16(&mut datagram, total);
+            put_u16(&mut datagram, 0);
+            datagram.extend_from_slice(chunk);
+            datagram
+        })
+        .collect()
+}
+
+pub fn respond(store: &ShardedStore,
+               payload: &[u8],
+               verbose: bool)
+               -> Vec<u8> {
+    // run everything in the datagram and collect up what it says back. There's
+    // no connection to hold on to, so incomplete commands are just dropped
+    let mut response = Vec::new();
+    let mut remaining = payload;
+    while !remaining.is_empty() {
+        match server::ascii_command(store,
+                                    remaining,
+                                    &mut response,
+                                    verbose) {
+            Processed::Consumed(consumed) => {
+                // a refused payload can run past the end of the datagram
+                remaining = &remaining[cmp::min(consumed, remaining.len())..];
+            }
+            Processed::Dump(consumed, mut dump) => {
+                // there's nobody to wait for, so it all goes in at once
+                while !store.dump_batch(&mut dump, &mut response) {}
+                remaining = &remaining[consumed..];
+            }
+            Processed::NeedMore | Processed::Disconnect => break,
+        }
+    }
+    response
+}
+
+pub fn serve(store: Arc<ShardedStore>, port: u16, verbose: bool) {
+    let uri = format!("0.0.0.0:{}", port);
+    let uri: &str = &uri;
+    let socket = UdpSocket::bind(uri).unwrap();
+
+    // the biggest datagram anybody can send us
+    let mut buff = vec![0; 65536];
+
+    loop {
+        let (size, peer) = match socket.recv_from(&mut buff) {
+            Ok(received) => received,
+            Err(err) => {
+                if verbose {
+                    println!("udp receive error: {:?}", err);
+                }
+                continue;
+            }
+        };
+
+        let (header, payload) = match parse_datagram(&buff[..size]) {
+            Some(parsed) => parsed,
+            None => {
+                if verbose {
+                    println!("bad udp frame from {}", peer);
+                }
+                continue;
+            }
+        };
+
+        let response = respond(&store, payload, verbose);
+        for datagram in split_response(header.request_id, &response) {
+            if let Err(err) = socket.send_to(&datagram, peer) {
+                if verbose {
+                    println!("udp send error: {:?}", err);
+                }
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shards::ShardedStore;
+    use settings::Settings;
+
+    #[test]
+    pub fn frames() {
+        let good = b"\x00\x07\x00\x00\x00\x01\x00\x00get foo\r\n";
+        assert_eq!(parse_datagram(good),
+                   Some((FrameHeader {
+                       request_id: 7,
+                       sequence: 0,
+                       total: 1,
+                   },
+                         &b"get foo\r\n"[..])));
+
+        // multi-datagram requests, and frames that are too short
+        assert_eq!(parse_datagram(b"\x00\x07\x00\x00\x00\x02\x00\x00get"),
+                   None);
+        assert_eq!(parse_datagram(b"\x00\x07\x00"), None);
+    }
+
+    #[test]
+    pub fn splitting() {
+        assert!(split_response(1, b"").is_empty());
+
+        let response = vec![b'x'; MAX_PAYLOAD * 2 + 10];
+        let datagrams = split_response(0x0102, &response);
+        assert_eq!(datagrams.len(), 3);
+        for (sequence, datagram) in datagrams.iter().enumerate() {
+            let (_, header) = frame_header(datagram).unwrap();
+            assert_eq!(header,
+                       FrameHeader {
+                           request_id: 0x0102,
+                           sequence: sequence as u16,
+                           total: 3,
+                       });
+        }
+        assert_eq!(datagrams[0].len(), MAX_DATAGRAM);
+        assert_eq!(datagrams[2].len(), HEADER_SIZE + 10);
+    }
+
+    #[test]
+    pub fn responding() {
+        let store = ShardedStore::new(Settings {
+            maxbytes: 10000,
+            shards: 2,
+            ..Settings::default()
+        });
+        let response = respond(&store,
+                               b"set foo 0 0 3\r\nbar\r\nget foo\r\nget",
+                               false);
+        assert_eq!(response,
+                   b"STORED\r\nVALUE foo 0 3\r\nbar\r\nEND\r\n".to_vec());
+    }
+}
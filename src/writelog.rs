@@ -0,0 +1,301 @@
+//! An append-only log of every change to the cache, for the times when
+//! losing everything in a crash is expensive. `Store::apply` writes down the
+//! state each mutation leaves its key in, we replay it at startup, and it's
+//! compacted in the background by rewriting it from what's live in the cache.
+//!
+//! The records are the ones from snapshot.rs, so a freshly compacted log is a
+//! snapshot without an end marker
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use lru::Timestamp;
+use snapshot;
+use snapshot::Record;
+use stats;
+use stats::Stat;
+use store::{Flags, CasUnique};
+
+// we compact once the log is twice the size it was after the last
+// compaction, but never while it's smaller than this
+const COMPACT_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+// when what's been written to the log has to be on disk by
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum FsyncPolicy {
+    // before we answer the client
+    Always,
+    // within a second or so
+    EverySec,
+    // whenever the OS gets around to it
+    No,
+}
+
+impl FsyncPolicy {
+    pub fn from_name(name: &str) -> Option<FsyncPolicy> {
+        match name {
+            "always" => Some(FsyncPolicy::Always),
+            "everysec" => Some(FsyncPolicy::EverySec),
+            "no" => Some(FsyncPolicy::No),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FsyncPolicy::Always => "always",
+            FsyncPolicy::EverySec => "everysec",
+            FsyncPolicy::No => "no",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WriteLog {
+    path: String,
+    fsync: FsyncPolicy,
+    state: Mutex<LogState>,
+}
+
+#[derive(Debug)]
+struct LogState {
+    file: File,
+    // how big the log is, and how big it was after the last compaction
+    size: u64,
+    compacted_size: u64,
+    // whether there's anything written since the last fsync
+    dirty: bool,
+    // while we're compacting, a copy of everything logged since we started,
+    // which goes on the end of the compacted log
+    pending: Option<Vec<u8>>,
+    compactions: u64,
+    errors: u64,
+}
+
+pub fn replay(path: &str) -> io::Result<(CasUnique, Vec<Record>)> {
+    // everything in the log at `path`. There's nothing in one that doesn't
+    // exist yet
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok((0, Vec::new()))
+        }
+        Err(err) => return Err(err),
+    };
+    snapshot::read_log(&mut BufReader::new(file))
+}
+
+impl WriteLog {
+    pub fn new(path: &str, fsync: FsyncPolicy) -> io::Result<WriteLog> {
+        // carry on appending to the log at `path`. It should be compacted
+        // before anything else is written to it, so that a record that was
+        // half written when we went away doesn't get in the way
+        let file =
+            OpenOptions::new().append(true).create(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(WriteLog {
+            path: path.to_string(),
+            fsync,
+            state: Mutex::new(LogState {
+                file,
+                size,
+                compacted_size: size,
+                dirty: false,
+                pending: None,
+                compactions: 0,
+                errors: 0,
+            }),
+        })
+    }
+
+    pub fn fsync(&self) -> FsyncPolicy {
+        self.fsync
+    }
+
+    pub fn item(&self,
+                key: &[u8],
+                data: &[u8],
+                flags: Flags,
+                unique: CasUnique,
+                expires: Option<Timestamp>,
+                used: Timestamp) {
+        let mut record = Vec::with_capacity(key.len() + data.len() + 32);
+        // writing to a Vec can't fail
+        snapshot::write_item(&mut record,
+                             key,
+                             data,
+                             flags,
+                             unique,
+                             expires,
+                             used)
+            .unwrap();
+        self.append(&record);
+    }
+
+    pub fn delete(&self, key: &[u8]) {
+        let mut record = Vec::with_capacity(key.len() + 5);
+        snapshot::write_delete(&mut record, key).unwrap();
+        self.append(&record);
+    }
+
+    pub fn flush_all(&self, deadline: Timestamp) {
+        let mut record = Vec::with_capacity(5);
+        snapshot::write_flush(&mut record, deadline).unwrap();
+        self.append(&record);
+    }
+
+    fn append(&self, record: &[u8]) {
+        // the client is going to be told it worked whatever happens here, so
+        // if the disk lets us down all we can do is count it
+        let mut state = self.state.lock().unwrap();
+        let mut written = state.file.write_all(record);
+        if written.is_ok() && self.fsync == FsyncPolicy::Always {
+            written = state.file.sync_data();
+        }
+        match written {
+            Ok(()) => {
+                state.size += record.len() as u64;
+                state.dirty = self.fsync == FsyncPolicy::EverySec;
+            }
+            Err(_) => state.errors += 1,
+        }
+        if let Some(ref mut pending) = state.pending {
+            pending.extend_from_slice(record);
+        }
+    }
+
+    pub fn sync(&self) {
+        // for FsyncPolicy::EverySec, once a second
+        let mut state = self.state.lock().unwrap();
+        if state.dirty {
+            if state.file.sync_data().is_err() {
+                state.errors += 1;
+            }
+            state.dirty = false;
+        }
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.size >= COMPACT_MIN_SIZE &&
+        state.size >= 2 * state.compacted_size
+    }
+
+    fn compact_path(&self) -> String {
+        format!("{}.compact", self.path)
+    }
+
+    pub fn start_compaction(&self,
+                            last_cas_id: CasUnique)
+                            -> io::Result<BufWriter<File>> {
+        // somewhere for the caller to write everything that's live, after
+        // which finish_compaction puts it in place of the log. Anything
+        // logged in the meantime is kept to go on the end of it
+        let file = File::create(self.compact_path())?;
+        let mut out = BufWriter::new(file);
+        snapshot::write_header(&mut out, last_cas_id)?;
+        self.state.lock().unwrap().pending = Some(Vec::new());
+        Ok(out)
+    }
+
+    pub fn finish_compaction(&self, out: BufWriter<File>) -> io::Result<()> {
+        let mut file = out.into_inner().map_err(|err| err.into_error())?;
+        let mut state = self.state.lock().unwrap();
+        let pending = state.pending.take().unwrap_or_default();
+        file.write_all(&pending)?;
+        file.sync_all()?;
+        let size = file.metadata()?.len();
+        fs::rename(self.compact_path(), &self.path)?;
+        // it's the log now whatever happens, so that's where we write
+        state.file = file;
+        state.size = size;
+        state.compacted_size = size;
+        state.dirty = false;
+        state.compactions += 1;
+        if sync_dir(&self.path).is_err() {
+            state.errors += 1;
+        }
+        Ok(())
+    }
+
+    pub fn abandon_compaction(&self) {
+        // if writing the compacted log didn't work out we keep the old one
+        let mut state = self.state.lock().unwrap();
+        state.pending = None;
+        state.errors += 1;
+        let _ = fs::remove_file(self.compact_path());
+    }
+
+    pub fn report(&self, stats: &mut Vec<Stat>) {
+        let state = self.state.lock().unwrap();
+        stats::push(stats, "write_log_bytes", state.size);
+        stats::push(stats, "write_log_compactions", state.compactions);
+        stats::push(stats, "write_log_errors", state.errors);
+    }
+}
+
+fn sync_dir(path: &str) -> io::Result<()> {
+    // so that a rename into it survives a crash
+    let dir = match Path::new(path).parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    use snapshot::Item;
+
+    #[test]
+    pub fn compaction() {
+        let path = env::temp_dir()
+            .join(format!("rustcached-test-{}.log", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        assert_eq!(replay(path).unwrap(), (0, Vec::new()));
+
+        let log = WriteLog::new(path, FsyncPolicy::Always).unwrap();
+        let mut out = log.start_compaction(10).unwrap();
+        // this happens while we're compacting, so it has to survive it
+        log.delete(b"gone");
+        snapshot::write_item(&mut out, b"foo", b"bar", 0, 9, None, 100)
+            .unwrap();
+        log.finish_compaction(out).unwrap();
+        log.flush_all(0);
+
+        let (last_cas_id, records) = replay(path).unwrap();
+        assert_eq!(last_cas_id, 10);
+        assert_eq!(records,
+                   vec![Record::Item(Item {
+                            key: b"foo".to_vec(),
+                            data: b"bar".to_vec(),
+                            flags: 0,
+                            unique: 9,
+                            expires: None,
+                            used: 100,
+                        }),
+                        Record::Delete(b"gone".to_vec()),
+                        Record::Flush(0)]);
+
+        // and a record we were in the middle of writing when we went away
+        // never happened
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&[1, 0, 0]).unwrap();
+        assert_eq!(replay(path).unwrap().1.len(), 3);
+
+        let mut stats = Vec::new();
+        log.report(&mut stats);
+        assert_eq!(stats[1], ("write_log_compactions".to_string(),
+                              "1".to_string()));
+        fs::remove_file(path).unwrap();
+    }
+}